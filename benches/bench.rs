@@ -2,11 +2,15 @@
 #![deny(unused_extern_crates)]
 
 extern crate criterion;
+#[cfg(feature = "rayon")]
+extern crate rayon;
 extern crate top_map;
 
 use criterion::{criterion_group, criterion_main, Bencher, Criterion, Fun};
+#[cfg(feature = "rayon")]
+use rayon::iter::ParallelIterator;
 use std::collections::BTreeMap;
-use top_map::TopMap;
+use top_map::{FarBelowPolicy, ReanchorPolicy, TopMap};
 
 fn bench(c: &mut Criterion) {
     fn insert_remove_empty_top_map(b: &mut Bencher, &index: &isize) {
@@ -29,10 +33,10 @@ fn bench(c: &mut Criterion) {
 
     fn insert_remove_existing_top_map(b: &mut Bencher, &index: &isize) {
         let mut m = (0..1000)
-            .map(|n| (n as isize, n))
+            .map(|n| (n, n))
             .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
 
-        m.shrink_to_fit();
+        m.shrink_to_min();
 
         b.iter(|| {
             m.insert(index, index);
@@ -41,7 +45,7 @@ fn bench(c: &mut Criterion) {
     }
 
     fn insert_remove_existing_btree_map(b: &mut Bencher, &index: &isize) {
-        let mut m = (0..1000).map(|n| (n as isize, n)).collect::<BTreeMap<isize, isize>>();
+        let mut m = (0..1000).map(|n| (n, n)).collect::<BTreeMap<isize, isize>>();
 
         b.iter(|| {
             m.insert(index, index);
@@ -51,10 +55,10 @@ fn bench(c: &mut Criterion) {
 
     fn lookup_top_map(b: &mut Bencher, &index: &isize) {
         let mut m = (0..1000)
-            .map(|n| (n as isize, n))
+            .map(|n| (n, n))
             .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
 
-        m.shrink_to_fit();
+        m.shrink_to_min();
 
         b.iter(|| {
             assert_eq!(index, m[index]);
@@ -62,19 +66,73 @@ fn bench(c: &mut Criterion) {
     }
 
     fn lookup_btree_map(b: &mut Bencher, &index: &isize) {
-        let m = (0..1000).map(|n| (n as isize, n)).collect::<BTreeMap<isize, isize>>();
+        let m = (0..1000).map(|n| (n, n)).collect::<BTreeMap<isize, isize>>();
 
         b.iter(|| {
             assert_eq!(index, m[&index]);
         });
     }
 
+    fn lookup_many(b: &mut Bencher, &batched: &bool) {
+        let m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        let keys = (0..64).map(|n| (n * 7) % 64).collect::<Vec<isize>>();
+
+        b.iter(|| {
+            if batched {
+                m.get_many(&keys)
+            } else {
+                keys.iter().map(|&key| m.get(key)).collect::<Vec<_>>()
+            }
+        });
+    }
+
+    fn lookup_many_rest(b: &mut Bencher, &(batched, spacing): &(bool, isize)) {
+        let m = (0..100_000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        let keys = (0..1000).map(|n| 200 + (n * 7919) % 1000 * spacing).collect::<Vec<isize>>();
+
+        b.iter(|| {
+            if batched {
+                m.get_many(&keys)
+            } else {
+                keys.iter().map(|&key| m.get(key)).collect::<Vec<_>>()
+            }
+        });
+    }
+
+    fn lookup_frozen(b: &mut Bencher, &frozen: &bool) {
+        let m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        if frozen {
+            let m = m.freeze();
+
+            b.iter(|| {
+                for n in (0..1000).step_by(7) {
+                    assert_eq!(n, m[n]);
+                }
+            });
+        } else {
+            b.iter(|| {
+                for n in (0..1000).step_by(7) {
+                    assert_eq!(n, m[n]);
+                }
+            });
+        }
+    }
+
     fn increment_top_map(b: &mut Bencher, &index: &isize) {
         let mut m = (0..1000)
-            .map(|n| (n as isize, n))
+            .map(|n| (n, n))
             .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
 
-        m.shrink_to_fit();
+        m.shrink_to_min();
 
         b.iter(|| {
             m[index] += 1;
@@ -82,7 +140,7 @@ fn bench(c: &mut Criterion) {
     }
 
     fn increment_btree_map(b: &mut Bencher, &index: &isize) {
-        let mut m = (0..1000).map(|n| (n as isize, n)).collect::<BTreeMap<isize, isize>>();
+        let mut m = (0..1000).map(|n| (n, n)).collect::<BTreeMap<isize, isize>>();
 
         b.iter(|| {
             *m.get_mut(&index).unwrap() += 1;
@@ -92,13 +150,150 @@ fn bench(c: &mut Criterion) {
     fn extend_in_direction(b: &mut Bencher, &direction: &i8) {
         b.iter(|| {
             let _: TopMap<[Option<(isize, isize)>; 128]> = if direction > 0 {
-                (0..1000).map(|n| (n as isize, n)).collect()
+                (0..1000).map(|n| (n, n)).collect()
             } else {
-                (0..1000).rev().map(|n| (n as isize, n)).collect()
+                (0..1000).rev().map(|n| (n, n)).collect()
             };
         });
     }
 
+    fn extend_descending_with_first_key(b: &mut Bencher, &first_key: &isize) {
+        b.iter(|| {
+            let mut m = TopMap::<[Option<(isize, isize)>; 128]>::with_first_key(first_key);
+            m.extend((0..1000).rev().map(|n| (n, n)));
+        });
+    }
+
+    fn lookup_after_drift(b: &mut Bencher, &reanchor: &bool) {
+        let mut m = TopMap::<[Option<(isize, isize)>; 128]>::new();
+
+        if reanchor {
+            m.set_reanchor_policy(Some(ReanchorPolicy {
+                max_occupied: 16,
+                min_hot_rest: 64,
+            }));
+        }
+
+        m.insert(0, 0);
+
+        for n in 1..10000 {
+            m.insert(n, n);
+
+            if n > 1000 {
+                m.remove(n - 1000);
+            }
+        }
+
+        b.iter(|| {
+            for n in 9000..9128 {
+                assert_eq!(n, m[n]);
+            }
+        });
+    }
+
+    fn insert_far_below_full_window(b: &mut Bencher, &distance: &isize) {
+        b.iter_with_setup(
+            || {
+                let mut m = TopMap::<[Option<(isize, isize)>; 128]>::new();
+                m.extend((10000..10128).map(|n| (n, n)));
+                m
+            },
+            |mut m| {
+                m.insert(10000 - distance, 0);
+                m
+            },
+        );
+    }
+
+    fn merge_interleaved(b: &mut Bencher, &bulk: &bool) {
+        b.iter_with_setup(
+            || {
+                let evens = (0..10000).map(|n| (2 * n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+                let odds = (0..10000).map(|n| (2 * n + 1, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+                (evens, odds)
+            },
+            |(mut evens, mut odds)| {
+                if bulk {
+                    evens.append(&mut odds);
+                } else {
+                    for (key, &value) in odds.iter() {
+                        evens.insert(key, value);
+                    }
+                }
+
+                (evens, odds)
+            },
+        );
+    }
+
+    fn insert_outlier(b: &mut Bencher, &policy: &FarBelowPolicy) {
+        let mut m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        m.set_far_below_policy(policy);
+
+        b.iter(|| {
+            m.insert(-1000000, 0);
+            m.remove(-1000000);
+
+            for n in 0..64 {
+                assert_eq!(n, m[n]);
+            }
+        });
+    }
+
+    fn refill_batch(b: &mut Bencher, &batch: &usize) {
+        let m = (0..10000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        b.iter_with_setup(
+            || {
+                let mut m = m.clone();
+                m.shrink_to(128 - batch);
+                m
+            },
+            |mut m| {
+                m.reserve_top(128);
+                m
+            },
+        );
+    }
+
+    fn load_dense(b: &mut Bencher, &dense: &bool) {
+        let values = (0..100000).collect::<Vec<isize>>();
+
+        b.iter(|| {
+            let mut m = TopMap::<[Option<(isize, isize)>; 128]>::new();
+
+            if dense {
+                m.extend_from_dense_slice(0, &values);
+            } else {
+                m.extend(values.iter().enumerate().map(|(key, &value)| (key as isize, value)));
+            }
+
+            m
+        });
+    }
+
+    #[cfg(feature = "rayon")]
+    fn sum_heavy_values(b: &mut Bencher, &parallel: &bool) {
+        fn heavy(value: isize) -> isize {
+            (0..1000).fold(value, |acc, n| acc.wrapping_mul(31).wrapping_add(n))
+        }
+
+        let m = (0..100000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        b.iter(|| {
+            if parallel {
+                m.par_iter().map(|(_, &value)| heavy(value)).reduce(|| 0, isize::wrapping_add)
+            } else {
+                m.iter().map(|(_, &value)| heavy(value)).fold(0, isize::wrapping_add)
+            }
+        });
+    }
+
     let indices = vec![0, 50, 63, 64, 65, 127, 128, 129, 999];
 
     for &n in indices.iter() {
@@ -143,6 +338,34 @@ fn bench(c: &mut Criterion) {
     c.bench_function_over_inputs("lookup_btree_map", lookup_btree_map, indices.clone());
     c.bench_function_over_inputs("increment_btree_map", increment_btree_map, indices.clone());
     c.bench_function_over_inputs("extend_in_direction", extend_in_direction, vec![-1, 1]);
+    c.bench_function_over_inputs(
+        "insert_far_below_full_window",
+        insert_far_below_full_window,
+        vec![64, 1000],
+    );
+    c.bench_function_over_inputs("merge_interleaved", merge_interleaved, vec![false, true]);
+    c.bench_function_over_inputs(
+        "insert_outlier",
+        insert_outlier,
+        vec![FarBelowPolicy::Reanchor, FarBelowPolicy::KeepWindow],
+    );
+    c.bench_function_over_inputs("refill_batch", refill_batch, vec![4, 8, 16, 32, 64, 127]);
+    c.bench_function_over_inputs("load_dense", load_dense, vec![false, true]);
+    c.bench_function_over_inputs("lookup_many", lookup_many, vec![false, true]);
+    c.bench_function_over_inputs(
+        "lookup_many_rest",
+        lookup_many_rest,
+        vec![(false, 2), (true, 2), (false, 90), (true, 90)],
+    );
+    c.bench_function_over_inputs("lookup_frozen", lookup_frozen, vec![false, true]);
+    c.bench_function_over_inputs("lookup_after_drift", lookup_after_drift, vec![false, true]);
+    #[cfg(feature = "rayon")]
+    c.bench_function_over_inputs("sum_heavy_values", sum_heavy_values, vec![false, true]);
+    c.bench_function_over_inputs(
+        "extend_descending_with_first_key",
+        extend_descending_with_first_key,
+        vec![0],
+    );
 }
 
 criterion_group!(benches, bench);
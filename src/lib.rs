@@ -1,28 +1,119 @@
 #![deny(warnings)]
 #![deny(unused_extern_crates)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api, btreemap_alloc))]
 
 extern crate fixed_vec_deque;
 
-#[cfg(test)]
+#[cfg(feature = "arbitrary")]
+extern crate arbitrary;
+
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
+#[cfg(feature = "rkyv")]
+extern crate rkyv;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
+
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
+#[cfg(feature = "proptest")]
+extern crate proptest as proptest_crate;
+
+#[cfg(any(test, feature = "quickcheck"))]
 extern crate quickcheck;
 
+#[cfg(not(feature = "allocator_api"))]
+use alloc_stub::{Allocator, Global};
+#[cfg(feature = "allocator_api")]
+use std::alloc::{Allocator, Global};
+use std::cmp;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::collections::btree_map;
+use std::convert::TryFrom;
+use std::error::Error;
 use std::fmt;
-use std::iter::FromIterator;
+use std::hash::Hash;
+use std::iter::{self, FromIterator, Peekable};
 use std::marker::PhantomData;
 use std::mem;
-use std::ops;
+use std::ops::{self, Bound};
+use std::slice;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
 use fixed_vec_deque::{Array as FvdArray, FixedVecDeque};
 
+#[cfg(feature = "arbitrary")]
+use arbitrary::Unstructured;
+
+#[cfg(feature = "quickcheck")]
+use quickcheck::{Arbitrary, Gen};
+
+#[cfg(feature = "rayon")]
+use rayon::iter::{
+    FromParallelIterator, IntoParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelBridge,
+    ParallelExtend, ParallelIterator,
+};
+
+#[cfg(feature = "rayon")]
+use rayon::slice::ParallelSliceMut;
+
+#[cfg(feature = "serde")]
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+
+#[cfg(feature = "serde")]
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+// Reports a structural change as a `tracing` event. Without the `tracing` feature nothing is formatted or emitted.
+#[cfg(feature = "tracing")]
+macro_rules! trace_event {
+    ($message:tt, key = $key:expr, moved = $moved:expr) => {
+        ::tracing::debug!(key = isize::from($key) as i64, moved = $moved as u64, $message)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace_event {
+    ($message:tt, key = $key:expr, moved = $moved:expr) => {
+        let _ = (&$key, &$moved);
+    };
+}
+
+/// Spells the array type behind a `TopMap`, optionally as a type alias:
+/// `top_array!(pub type Orders, Key = isize, Value = Order, N = 128)`.
+#[macro_export]
+macro_rules! top_array {
+    (Key = $key:ty, Value = $value:ty, N = $n:expr) => {
+        [Option<($key, $value)>; $n]
+    };
+    ($(#[$attr:meta])* $vis:vis type $name:ident, Key = $key:ty, Value = $value:ty, N = $n:expr) => {
+        $(#[$attr])* $vis type $name = top_array!(Key = $key, Value = $value, N = $n);
+    };
+}
+
 pub trait Array {
     type Key;
     type Value;
     type Array: FvdArray<Item = Option<(Self::Key, Self::Value)>>;
 
-    fn min_size() -> usize;
-    fn max_size() -> usize;
+    /// Size the top tier is refilled to once it drains; defaults to half of `max_size`.
+    fn min_size() -> usize {
+        Self::max_size() / 2
+    }
+
+    fn max_size() -> usize {
+        <Self::Array as FvdArray>::size()
+    }
 }
 
 impl<Key, Value, A> Array for A
@@ -32,647 +123,9467 @@ where
     type Key = Key;
     type Value = Value;
     type Array = Self;
+}
 
-    fn min_size() -> usize {
-        A::size() / 2
+/// Ring buffer behind the top tier. `pop_*` return the vacated slot; `push_*` return the new slot for the caller
+/// to fill.
+pub trait Storage: ops::IndexMut<usize> {
+    fn max_size(&self) -> usize;
+
+    fn min_size(&self) -> usize {
+        self.max_size() / 2
     }
 
-    fn max_size() -> usize {
-        A::size()
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
     }
-}
 
-pub struct TopMap<A>
-where
-    A: Array,
-{
-    top: FixedVecDeque<A::Array>,
-    rest: BTreeMap<A::Key, A::Value>,
-}
+    fn front(&self) -> Option<&Self::Output> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(&self[0])
+        }
+    }
 
-impl<A> TopMap<A>
-where
-    A: Array,
-    A::Key: Ord,
-{
-    pub fn new() -> Self {
-        Self {
-            top: FixedVecDeque::new(),
-            rest: BTreeMap::new(),
+    fn back(&self) -> Option<&Self::Output> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(&self[self.len() - 1])
         }
     }
-}
 
-fn positive(i: isize) -> Option<usize> {
-    if i >= 0 {
-        Some(i as usize)
-    } else {
-        None
+    fn push_front(&mut self) -> &mut Self::Output;
+    fn push_back(&mut self) -> &mut Self::Output;
+    fn pop_front(&mut self) -> Option<&mut Self::Output>;
+    fn pop_back(&mut self) -> Option<&mut Self::Output>;
+    fn clear(&mut self);
+
+    /// Iterates over the slots in order; a concrete type so walking the window doesn't allocate.
+    type IterMut<'a>: Iterator<Item = &'a mut Self::Output>
+    where
+        Self: 'a;
+
+    fn iter_mut(&mut self) -> Self::IterMut<'_>;
+
+    /// The slots in order, split where the ring wraps.
+    fn as_slices(&self) -> (&[Self::Output], &[Self::Output])
+    where
+        Self::Output: Sized;
+
+    fn as_mut_slices(&mut self) -> (&mut [Self::Output], &mut [Self::Output])
+    where
+        Self::Output: Sized;
+
+    fn heap_size(&self) -> usize {
+        0
     }
-}
 
-enum Index<'a> {
-    AboveTop { distance: usize },
-    InsideTop { index: usize, _pd: PhantomData<&'a ()> },
-    OutsideTop { index: usize, _pd: PhantomData<&'a ()> },
-    Rest,
+    /// Slot `index`, skipping the bounds check where the storage can.
+    ///
+    /// # Safety
+    ///
+    /// `index` must be less than `len()`.
+    unsafe fn get_unchecked(&self, index: usize) -> &Self::Output {
+        &self[index]
+    }
+
+    /// Mutable slot `index`, skipping the bounds check where the storage can.
+    ///
+    /// # Safety
+    ///
+    /// As for `get_unchecked`.
+    unsafe fn get_unchecked_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self[index]
+    }
 }
 
-pub enum Entry<'a, A: 'a>
-where
-    A: Array,
-{
-    AboveTop {
-        key: A::Key,
-        map: &'a mut TopMap<A>,
-        distance: usize,
-    },
+/// Anything a `TopMap` can be built on: fixed-size `Array` layouts, or a runtime-sized `DynArray`.
+pub trait Layout {
+    type Key;
+    type Value;
+    type Storage: Storage + ops::IndexMut<usize, Output = Option<(Self::Key, Self::Value)>>;
+
+    /// Where the nodes of the ordered maps come from.
+    #[cfg(feature = "allocator_api")]
+    type Alloc: Allocator + Clone;
+
+    fn into_storage(self) -> Self::Storage;
 
-    Vec(A::Key, &'a mut Option<(A::Key, A::Value)>),
-    BTreeMap(btree_map::Entry<'a, A::Key, A::Value>),
+    #[cfg(feature = "allocator_api")]
+    fn allocator(storage: &Self::Storage) -> Self::Alloc;
 }
 
-impl<'a, A> Entry<'a, A>
+impl<A> Layout for A
 where
     A: Array,
-    A::Key: Ord,
 {
-    fn insert(self, value: A::Value) -> Option<A::Value> {
-        match self {
-            Entry::AboveTop { key, map, distance } => {
-                *map.insert_above_top(distance) = Some((key, value));
-                None
-            }
-
-            Entry::Vec(key, entry) => Some(mem::replace(entry, Some((key, value)))?.1),
-            Entry::BTreeMap(btree_map::Entry::Occupied(mut entry)) => Some(entry.insert(value)),
-
-            Entry::BTreeMap(btree_map::Entry::Vacant(entry)) => {
-                entry.insert(value);
-                None
-            }
-        }
-    }
+    type Key = A::Key;
+    type Value = A::Value;
+    type Storage = FixedStorage<A>;
 
-    pub fn or_insert(self, default: A::Value) -> &'a mut A::Value {
-        match self {
-            Entry::AboveTop { key, map, distance } => {
-                &mut map.insert_above_top(distance).get_or_insert((key, default)).1
-            }
+    #[cfg(feature = "allocator_api")]
+    type Alloc = Global;
 
-            Entry::Vec(key, entry) => &mut entry.get_or_insert((key, default)).1,
-            Entry::BTreeMap(entry) => entry.or_insert(default),
-        }
+    fn into_storage(self) -> FixedStorage<A> {
+        FixedStorage::default()
     }
 
-    pub fn or_insert_with<F: FnOnce() -> A::Value>(self, default: F) -> &'a mut A::Value {
-        match self {
-            Entry::AboveTop { key, map, distance } => {
-                &mut map.insert_above_top(distance).get_or_insert_with(|| (key, default())).1
-            }
-
-            Entry::Vec(key, entry) => &mut entry.get_or_insert_with(|| (key, default())).1,
-            Entry::BTreeMap(entry) => entry.or_insert_with(default),
-        }
+    #[cfg(feature = "allocator_api")]
+    fn allocator(_storage: &FixedStorage<A>) -> Global {
+        Global
     }
 }
 
-impl<A> TopMap<A>
+pub struct FixedStorage<A>(FixedVecDeque<A::Array>)
+where
+    A: Array;
+
+impl<A> Default for FixedStorage<A>
 where
     A: Array,
 {
-    pub fn len(&self) -> usize {
-        self.top.iter().filter(|&entry| entry.is_some()).count() + self.rest.len()
+    fn default() -> Self {
+        FixedStorage(FixedVecDeque::new())
     }
 }
 
-fn ensure_index<T, A>(v: &mut FixedVecDeque<A>, index: usize) -> &mut Option<T>
+impl<A> Clone for FixedStorage<A>
 where
-    A: FvdArray<Item = Option<T>>,
+    A: Array,
+    A::Key: Clone,
+    A::Value: Clone,
 {
-    if let Some(count) = (index + 1).checked_sub(v.len()) {
-        for _ in 0..count {
-            *v.push_back() = None;
+    fn clone(&self) -> Self {
+        let mut storage = Self::default();
+
+        for index in 0..self.0.len() {
+            *storage.0.push_back() = self.0[index].clone();
         }
-    }
 
-    &mut v[index]
+        storage
+    }
 }
 
-impl<A> TopMap<A>
+impl<A> ops::Index<usize> for FixedStorage<A>
 where
     A: Array,
-    A::Key: Ord,
 {
-    fn insert_above_top(&mut self, distance: usize) -> &mut Option<(A::Key, A::Value)> {
-        if let Some(new_count) = A::max_size().checked_sub(distance) {
-            if let Some(drain_count) = self.top.len().checked_sub(new_count) {
-                for _ in 0..drain_count {
-                    if let Some((key, value)) = mem::replace(self.top.pop_back().unwrap(), None) {
-                        self.rest.insert(key, value);
-                    }
-                }
-            }
+    type Output = Option<(A::Key, A::Value)>;
 
-            for _ in 0..distance - 1 {
-                *self.top.push_front() = None;
-            }
-        } else {
-            while let Some(entry) = self.top.pop_back() {
-                if let Some((key, value)) = mem::replace(entry, None) {
-                    self.rest.insert(key, value);
-                }
-            }
-        }
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.0[index]
+    }
+}
 
-        let front = self.top.push_front();
-        *front = None;
-        front
+impl<A> ops::IndexMut<usize> for FixedStorage<A>
+where
+    A: Array,
+{
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.0[index]
     }
 }
 
-impl<A> TopMap<A>
+impl<A> Storage for FixedStorage<A>
 where
     A: Array,
-    A::Key: Copy + Ord,
-    isize: From<A::Key>,
 {
-    pub fn iter(&self) -> impl Iterator<Item = (A::Key, &A::Value)> {
-        self.top
-            .iter()
-            .filter_map(|entry| entry.as_ref().map(|(key, value)| (*key, value)))
-            .chain(self.rest.iter().map(|(key, value)| (*key, value)))
+    type IterMut<'a>
+        = fixed_vec_deque::IterMut<'a, A::Array>
+    where
+        A: 'a;
+
+    fn max_size(&self) -> usize {
+        A::max_size()
     }
 
-    pub fn iter_mut(&mut self) -> impl Iterator<Item = (A::Key, &mut A::Value)> {
-        self.top
-            .iter_mut()
-            .filter_map(|entry| entry.as_mut().map(|(key, value)| (*key, value)))
-            .chain(self.rest.iter_mut().map(|(key, value)| (*key, value)))
+    fn min_size(&self) -> usize {
+        A::min_size()
     }
 
-    pub fn clear(&mut self) {
-        self.top.clear();
-        self.rest.clear();
+    fn len(&self) -> usize {
+        self.0.len()
     }
 
-    pub fn shrink_to_fit(&mut self) {
-        while self.top.len() > A::min_size() {
-            if let Some((key, value)) = mem::replace(self.top.pop_back().unwrap(), None) {
-                self.rest.insert(key, value);
-            }
-        }
+    fn push_front(&mut self) -> &mut Self::Output {
+        self.0.push_front()
     }
 
-    fn index(&self, key: A::Key) -> Index {
-        let index = if let Some(ref min_entry) = self.top.front() {
-            let &(min_key, _) = min_entry.as_ref().expect("top entry should be filled");
-            isize::from(key) - isize::from(min_key)
-        } else {
-            return Index::OutsideTop {
-                index: 0,
-                _pd: PhantomData,
-            };
-        };
+    fn push_back(&mut self) -> &mut Self::Output {
+        self.0.push_back()
+    }
 
-        if let Some(index) = positive(index) {
-            if index >= A::max_size() {
-                Index::Rest
-            } else if index >= self.top.len() {
-                Index::OutsideTop {
-                    index,
-                    _pd: PhantomData,
-                }
-            } else {
-                Index::InsideTop {
-                    index,
-                    _pd: PhantomData,
-                }
-            }
-        } else {
-            Index::AboveTop {
-                distance: -index as usize,
-            }
-        }
+    fn pop_front(&mut self) -> Option<&mut Self::Output> {
+        self.0.pop_front()
     }
 
-    pub fn entry(&mut self, key: A::Key) -> Entry<A> {
-        match self.index(key) {
-            Index::AboveTop { distance } => Entry::AboveTop {
-                key,
-                map: self,
-                distance,
-            },
+    fn pop_back(&mut self) -> Option<&mut Self::Output> {
+        self.0.pop_back()
+    }
 
-            Index::InsideTop { index, .. } => Entry::Vec(key, &mut self.top[index]),
+    fn clear(&mut self) {
+        self.0.clear()
+    }
 
-            Index::OutsideTop { index, .. } => {
-                if let Some((&rest_key, _)) = self.rest.iter().next() {
-                    if key >= rest_key {
-                        return Entry::BTreeMap(self.rest.entry(key));
-                    }
-                }
+    fn iter_mut(&mut self) -> fixed_vec_deque::IterMut<'_, A::Array> {
+        self.0.iter_mut()
+    }
 
-                Entry::Vec(key, ensure_index(&mut self.top, index))
-            },
+    fn as_slices(&self) -> (&[Self::Output], &[Self::Output]) {
+        self.0.as_slices()
+    }
 
-            Index::Rest => Entry::BTreeMap(self.rest.entry(key)),
-        }
+    fn as_mut_slices(&mut self) -> (&mut [Self::Output], &mut [Self::Output]) {
+        self.0.as_mut_slices()
     }
 
-    pub fn get(&self, key: A::Key) -> Option<&A::Value> {
-        match self.index(key) {
-            Index::AboveTop { distance: _ } => None,
-            Index::InsideTop { index, .. } => Some(&self.top[index].as_ref()?.1),
-            Index::OutsideTop { .. } | Index::Rest => self.rest.get(&key),
+    unsafe fn get_unchecked(&self, index: usize) -> &Self::Output {
+        debug_assert!(index < self.0.len(), "index {} out of range for length {}", index, self.0.len());
+        let (front, back) = self.0.as_slices();
+
+        if index < front.len() {
+            front.get_unchecked(index)
+        } else {
+            back.get_unchecked(index - front.len())
         }
     }
 
-    pub fn get_mut(&mut self, key: A::Key) -> Option<&mut A::Value> {
-        match self.index(key) {
-            Index::AboveTop { distance: _ } => None,
-            Index::InsideTop { index, .. } => Some(&mut self.top[index].as_mut()?.1),
-            Index::OutsideTop { .. } | Index::Rest => self.rest.get_mut(&key),
+    unsafe fn get_unchecked_mut(&mut self, index: usize) -> &mut Self::Output {
+        debug_assert!(index < self.0.len(), "index {} out of range for length {}", index, self.0.len());
+        let (front, back) = self.0.as_mut_slices();
+
+        if index < front.len() {
+            front.get_unchecked_mut(index)
+        } else {
+            back.get_unchecked_mut(index - front.len())
         }
     }
+}
 
-    pub fn insert(&mut self, key: A::Key, value: A::Value) -> Option<A::Value> {
-        self.entry(key).insert(value)
-    }
+// Without the `allocator_api` feature, `DynArray`'s allocator parameter is only ever this `Global`, which keeps the
+// type's parameters the same either way.
+#[cfg(not(feature = "allocator_api"))]
+mod alloc_stub {
+    pub trait Allocator {}
 
-    pub fn remove(&mut self, key: A::Key) -> Option<A::Value> {
-        match self.index(key) {
-            Index::AboveTop { distance: _ } => None,
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct Global;
 
-            Index::InsideTop { index: 0, .. } => {
-                let (_, value) = mem::replace(self.top.pop_front().unwrap(), None)?;
+    impl Allocator for Global {}
+}
 
-                while let Some(None) = self.top.front() {
-                    self.top.pop_front();
-                }
+/// Heap-allocated top tier whose size is chosen at runtime: `TopMap::with_array(DynArray::new(4096))`. With the
+/// `allocator_api` feature, `DynArray::new_in` takes its slots and the ordered maps' nodes from one allocator.
+#[derive(Clone)]
+pub struct DynArray<K, V, Al: Allocator = Global> {
+    #[cfg(feature = "allocator_api")]
+    slots: Box<[Option<(K, V)>], Al>,
+    #[cfg(not(feature = "allocator_api"))]
+    slots: Box<[Option<(K, V)>]>,
+    #[cfg(not(feature = "allocator_api"))]
+    alloc: PhantomData<Al>,
+    head: usize,
+    len: usize,
+    min_size: usize,
+}
 
-                if self.top.len() <= A::min_size() {
-                    let min_top_key = if let Some(&Some((min_top_key, _))) = self.top.front() {
-                        Some(min_top_key)
-                    } else if let Some((&rest_key, _)) = self.rest.iter().next() {
-                        let rest_value = self.rest.remove(&rest_key).unwrap();
-                        *self.top.push_back() = Some((rest_key, rest_value));
-                        Some(rest_key)
-                    } else {
-                        None
-                    };
+impl<K, V> DynArray<K, V> {
+    pub fn new(max_size: usize) -> Self {
+        Self::with_min_size(max_size, max_size / 2)
+    }
 
-                    if let Some(min_top_key) = min_top_key {
-                        while let Some((&key, _)) = self.rest.iter().next() {
-                            let index = positive(isize::from(key) - isize::from(min_top_key)).expect(
-                                "everything in the rest map should have an index higher than everything in the top vec",
-                            );
+    pub fn with_min_size(max_size: usize, min_size: usize) -> Self {
+        Self::with_slots(max_size, min_size, Global)
+    }
+}
 
-                            assert!(A::min_size() <= A::max_size());
+impl<K, V, Al: Allocator> DynArray<K, V, Al> {
+    #[cfg(feature = "allocator_api")]
+    pub fn new_in(max_size: usize, alloc: Al) -> Self {
+        Self::with_min_size_in(max_size, max_size / 2, alloc)
+    }
 
-                            if index >= A::min_size() {
-                                break;
-                            }
+    #[cfg(feature = "allocator_api")]
+    pub fn with_min_size_in(max_size: usize, min_size: usize, alloc: Al) -> Self {
+        Self::with_slots(max_size, min_size, alloc)
+    }
 
-                            let value = self.rest.remove(&key).unwrap();
-                            *ensure_index(&mut self.top, index) = Some((key, value));
-                        }
-                    }
-                }
+    #[cfg(feature = "allocator_api")]
+    fn with_slots(max_size: usize, min_size: usize, alloc: Al) -> Self {
+        assert!(min_size <= max_size, "min_size must not exceed max_size");
+        let mut slots = Vec::with_capacity_in(max_size, alloc);
+        slots.resize_with(max_size, || None);
 
-                Some(value)
-            }
+        Self {
+            slots: slots.into_boxed_slice(),
+            head: 0,
+            len: 0,
+            min_size,
+        }
+    }
 
-            Index::InsideTop { index, .. } => {
-                let (_, value) = mem::replace(&mut self.top[index], None)?;
-                Some(value)
-            }
+    #[cfg(not(feature = "allocator_api"))]
+    fn with_slots(max_size: usize, min_size: usize, _alloc: Al) -> Self {
+        assert!(min_size <= max_size, "min_size must not exceed max_size");
 
-            Index::OutsideTop { .. } | Index::Rest => self.rest.remove(&key),
+        Self {
+            slots: (0..max_size).map(|_| None).collect::<Vec<_>>().into_boxed_slice(),
+            alloc: PhantomData,
+            head: 0,
+            len: 0,
+            min_size,
         }
     }
+
+    fn slot(&self, index: usize) -> usize {
+        assert!(index < self.len, "index {} out of range for length {}", index, self.len);
+        (self.head + index) % self.slots.len()
+    }
 }
 
-impl<A> ops::Index<A::Key> for TopMap<A>
-where
-    A: Array,
-    A::Key: Copy + Ord + fmt::Debug,
-    isize: From<A::Key>,
-{
-    type Output = A::Value;
+impl<K, V, Al: Allocator + Clone> Layout for DynArray<K, V, Al> {
+    type Key = K;
+    type Value = V;
+    type Storage = Self;
 
-    fn index(&self, index: A::Key) -> &A::Value {
-        self.get(index)
-            .unwrap_or_else(|| panic!("no item with key {:?}", index))
+    #[cfg(feature = "allocator_api")]
+    type Alloc = Al;
+
+    fn into_storage(self) -> Self {
+        self
+    }
+
+    #[cfg(feature = "allocator_api")]
+    fn allocator(storage: &Self) -> Al {
+        Box::allocator(&storage.slots).clone()
     }
 }
 
-impl<A> ops::IndexMut<A::Key> for TopMap<A>
-where
-    A: Array,
-    A::Key: Copy + Ord + fmt::Debug,
-    isize: From<A::Key>,
-{
-    fn index_mut(&mut self, index: A::Key) -> &mut A::Value {
-        self.get_mut(index)
-            .unwrap_or_else(|| panic!("no item with key {:?}", index))
-    }
-}
+impl<K, V, Al: Allocator> ops::Index<usize> for DynArray<K, V, Al> {
+    type Output = Option<(K, V)>;
 
-impl<A> Extend<(A::Key, A::Value)> for TopMap<A>
-where
-    A: Array,
-    A::Key: Copy + Ord,
-    isize: From<A::Key>,
-{
-    fn extend<T: IntoIterator<Item = (A::Key, A::Value)>>(&mut self, iter: T) {
-        for (key, value) in iter {
-            self.insert(key, value);
-        }
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.slots[self.slot(index)]
     }
 }
 
-impl<A> FromIterator<(A::Key, A::Value)> for TopMap<A>
-where
-    A: Array,
-    A::Key: Copy + Ord,
-    isize: From<A::Key>,
-{
-    fn from_iter<T: IntoIterator<Item = (A::Key, A::Value)>>(iter: T) -> Self {
-        let mut m = TopMap::new();
-        m.extend(iter);
-        m
+impl<K, V, Al: Allocator> ops::IndexMut<usize> for DynArray<K, V, Al> {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        let slot = self.slot(index);
+        &mut self.slots[slot]
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::BTreeMap;
+impl<K, V, Al: Allocator> Storage for DynArray<K, V, Al> {
+    type IterMut<'a>
+        = iter::Take<iter::Chain<slice::IterMut<'a, Option<(K, V)>>, slice::IterMut<'a, Option<(K, V)>>>>
+    where
+        Self: 'a;
 
-    use quickcheck::{quickcheck, Arbitrary, Gen};
+    fn max_size(&self) -> usize {
+        self.slots.len()
+    }
 
-    use super::{Array, TopMap};
+    fn min_size(&self) -> usize {
+        self.min_size
+    }
 
-    static ITEMS: &[(isize, &'static str)] = &[
-        (100, "a1"),
-        (101, "a2"),
-        (200, "b1"),
-        (201, "b2"),
-        (300, "c1"),
-        (301, "c2"),
-    ];
+    fn len(&self) -> usize {
+        self.len
+    }
 
-    fn lens<A>(m: &TopMap<A>) -> [usize; 3]
-    where
-        A: Array,
-    {
-        [
-            m.len(),
-            m.top.iter().filter(|&entry| entry.is_some()).count(),
-            m.rest.len(),
-        ]
+    fn push_front(&mut self) -> &mut Self::Output {
+        let capacity = self.slots.len();
+        assert!(capacity > 0, "push into a zero-sized DynArray");
+        self.head = (self.head + capacity - 1) % capacity;
+        self.len = cmp::min(self.len + 1, capacity);
+        &mut self[0]
     }
 
-    #[test]
-    fn collect() {
-        let m = ITEMS.iter().cloned().collect::<TopMap<[Option<(isize, &str)>; 10]>>();
-        assert_eq!([6, 2, 4], lens(&m));
+    fn push_back(&mut self) -> &mut Self::Output {
+        let capacity = self.slots.len();
+        assert!(capacity > 0, "push into a zero-sized DynArray");
 
-        let items = m.iter()
-            .map(|(key, &value)| (key, value))
-            .collect::<Vec<(isize, &'static str)>>();
+        if self.len == capacity {
+            self.head = (self.head + 1) % capacity;
+        } else {
+            self.len += 1;
+        }
 
-        assert_eq!(ITEMS, &items[..]);
+        let index = self.len - 1;
+        &mut self[index]
     }
 
-    #[test]
-    fn insert() {
-        let mut m = TopMap::<[Option<(isize, &str)>; 10]>::new();
-        assert_eq!(None, m.get(100isize));
-        assert_eq!([0, 0, 0], lens(&m));
+    fn pop_front(&mut self) -> Option<&mut Self::Output> {
+        if self.len == 0 {
+            return None;
+        }
 
-        assert_eq!(None, m.insert(200, "b1"));
-        assert_eq!([1, 1, 0], lens(&m));
+        let slot = self.head;
+        self.head = (self.head + 1) % self.slots.len();
+        self.len -= 1;
+        Some(&mut self.slots[slot])
+    }
 
-        assert_eq!(None, m.insert(201, "b2"));
-        assert_eq!([2, 2, 0], lens(&m));
+    fn pop_back(&mut self) -> Option<&mut Self::Output> {
+        if self.len == 0 {
+            return None;
+        }
 
-        assert_eq!(None, m.insert(300, "c1"));
-        assert_eq!([3, 2, 1], lens(&m));
+        let slot = self.slot(self.len - 1);
+        self.len -= 1;
+        Some(&mut self.slots[slot])
+    }
 
-        assert_eq!(None, m.insert(301, "c2"));
-        assert_eq!([4, 2, 2], lens(&m));
+    fn clear(&mut self) {
+        for slot in self.slots.iter_mut() {
+            *slot = None;
+        }
 
-        assert_eq!(None, m.insert(100, "a1"));
-        assert_eq!([5, 1, 4], lens(&m));
+        self.head = 0;
+        self.len = 0;
+    }
 
-        assert_eq!(None, m.insert(101, "a2"));
-        assert_eq!([6, 2, 4], lens(&m));
+    fn iter_mut(&mut self) -> Self::IterMut<'_> {
+        let (back, front) = self.slots.split_at_mut(self.head);
+        let len = self.len;
+        front.iter_mut().chain(back.iter_mut()).take(len)
+    }
 
-        let items = m.iter()
-            .map(|(key, &value)| (key, value))
-            .collect::<Vec<(isize, &'static str)>>();
+    fn as_slices(&self) -> (&[Option<(K, V)>], &[Option<(K, V)>]) {
+        let (back, front) = self.slots.split_at(self.head);
+        let front_len = cmp::min(self.len, front.len());
+        (&front[..front_len], &back[..self.len - front_len])
+    }
 
-        assert_eq!(ITEMS, &items[..]);
+    fn as_mut_slices(&mut self) -> (&mut [Option<(K, V)>], &mut [Option<(K, V)>]) {
+        let (back, front) = self.slots.split_at_mut(self.head);
+        let front_len = cmp::min(self.len, front.len());
+        (&mut front[..front_len], &mut back[..self.len - front_len])
     }
 
-    #[test]
-    fn remove() {
-        let mut m = ITEMS.iter().cloned().collect::<TopMap<[Option<(isize, &str)>; 10]>>();
-        assert_eq!(None, m.remove(-1));
-        assert_eq!([6, 2, 4], lens(&m));
+    fn heap_size(&self) -> usize {
+        self.slots.len() * mem::size_of::<Option<(K, V)>>()
+    }
 
-        assert_eq!(Some("a1"), m.remove(100));
-        assert_eq!([5, 1, 4], lens(&m));
+    unsafe fn get_unchecked(&self, index: usize) -> &Option<(K, V)> {
+        debug_assert!(index < self.len, "index {} out of range for length {}", index, self.len);
+        self.slots.get_unchecked((self.head + index) % self.slots.len())
+    }
 
-        assert_eq!(Some("a2"), m.remove(101));
-        assert_eq!([4, 2, 2], lens(&m));
+    unsafe fn get_unchecked_mut(&mut self, index: usize) -> &mut Option<(K, V)> {
+        debug_assert!(index < self.len, "index {} out of range for length {}", index, self.len);
+        let slot = (self.head + index) % self.slots.len();
+        self.slots.get_unchecked_mut(slot)
+    }
+}
 
-        assert_eq!(Some("c1"), m.remove(300));
-        assert_eq!([3, 2, 1], lens(&m));
+/// Fixed-size layout `A` with the ordered maps allocating their nodes from `alloc`, for `TopMap::new_in`. The window
+/// itself is inline; a `DynArray` from `DynArray::new_in` puts a runtime-sized window in the allocator instead.
+#[cfg(feature = "allocator_api")]
+pub struct InAllocator<A, Al> {
+    pub array: A,
+    pub alloc: Al,
+}
 
-        assert_eq!(Some("c2"), m.remove(301));
-        assert_eq!([2, 2, 0], lens(&m));
+/// The window storage of an `InAllocator` layout, carrying the allocator alongside.
+#[cfg(feature = "allocator_api")]
+#[derive(Clone, Default)]
+pub struct AllocStorage<S, Al> {
+    storage: S,
+    alloc: Al,
+}
 
-        assert_eq!(Some("b1"), m.remove(200));
-        assert_eq!([1, 1, 0], lens(&m));
+#[cfg(feature = "allocator_api")]
+impl<A, Al> Layout for InAllocator<A, Al>
+where
+    A: Array,
+    Al: Allocator + Clone,
+{
+    type Key = A::Key;
+    type Value = A::Value;
+    type Storage = AllocStorage<FixedStorage<A>, Al>;
+    type Alloc = Al;
 
-        assert_eq!(Some("b2"), m.remove(201));
-        assert_eq!([0, 0, 0], lens(&m));
+    fn into_storage(self) -> Self::Storage {
+        AllocStorage {
+            storage: self.array.into_storage(),
+            alloc: self.alloc,
+        }
+    }
 
-        let items = m.iter()
-            .map(|(key, &value)| (key, value))
-            .collect::<Vec<(isize, &'static str)>>();
+    fn allocator(storage: &Self::Storage) -> Al {
+        storage.alloc.clone()
+    }
+}
 
-        assert!(items.is_empty());
+#[cfg(feature = "allocator_api")]
+impl<S: ops::Index<usize>, Al> ops::Index<usize> for AllocStorage<S, Al> {
+    type Output = S::Output;
+
+    fn index(&self, index: usize) -> &S::Output {
+        &self.storage[index]
     }
+}
 
-    #[test]
-    fn insert_remove_existing_m1() {
-        let mut m = (0..1000)
-            .map(|n| (n as isize, n))
-            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+#[cfg(feature = "allocator_api")]
+impl<S: ops::IndexMut<usize>, Al> ops::IndexMut<usize> for AllocStorage<S, Al> {
+    fn index_mut(&mut self, index: usize) -> &mut S::Output {
+        &mut self.storage[index]
+    }
+}
 
-        assert_eq!([1000, 128, 872], lens(&m));
-        assert_eq!(127, m[127]);
+#[cfg(feature = "allocator_api")]
+impl<S: Storage, Al> Storage for AllocStorage<S, Al> {
+    type IterMut<'a>
+        = S::IterMut<'a>
+    where
+        Self: 'a;
 
-        let index = -1;
-        assert_eq!(None, m.insert(index, index));
-        assert_eq!([1001, 128, 873], lens(&m));
-        assert_eq!(127, m[127]);
+    fn max_size(&self) -> usize {
+        self.storage.max_size()
+    }
 
-        assert_eq!(Some(index), m.remove(index));
-        assert_eq!([1000, 127, 873], lens(&m));
-        assert_eq!(127, m[127]);
+    fn min_size(&self) -> usize {
+        self.storage.min_size()
+    }
 
-        m.shrink_to_fit();
-        assert_eq!([1000, 64, 936], lens(&m));
-        assert_eq!(127, m[127]);
+    fn len(&self) -> usize {
+        self.storage.len()
+    }
 
-        assert_eq!(None, m.insert(index, index));
-        assert_eq!([1001, 65, 936], lens(&m));
-        assert_eq!(127, m[127]);
+    fn push_front(&mut self) -> &mut S::Output {
+        self.storage.push_front()
+    }
 
-        assert_eq!(Some(index), m.remove(index));
-        assert_eq!([1000, 64, 936], lens(&m));
-        assert_eq!(127, m[127]);
+    fn push_back(&mut self) -> &mut S::Output {
+        self.storage.push_back()
     }
 
-    #[test]
-    fn insert_remove_existing_m3() {
-        let mut m = (0..1000)
-            .map(|n| (n as isize, n))
-            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+    fn pop_front(&mut self) -> Option<&mut S::Output> {
+        self.storage.pop_front()
+    }
 
-        assert_eq!([1000, 128, 872], lens(&m));
-        assert_eq!(127, m[127]);
+    fn pop_back(&mut self) -> Option<&mut S::Output> {
+        self.storage.pop_back()
+    }
 
-        let index = -3;
-        assert_eq!(None, m.insert(index, index));
-        assert_eq!([1001, 126, 875], lens(&m));
-        assert_eq!(127, m[127]);
+    fn clear(&mut self) {
+        self.storage.clear()
+    }
 
-        assert_eq!(Some(index), m.remove(index));
-        assert_eq!([1000, 125, 875], lens(&m));
-        assert_eq!(127, m[127]);
+    fn iter_mut(&mut self) -> S::IterMut<'_> {
+        self.storage.iter_mut()
+    }
 
-        m.shrink_to_fit();
-        assert_eq!([1000, 64, 936], lens(&m));
-        assert_eq!(127, m[127]);
+    fn as_slices(&self) -> (&[S::Output], &[S::Output])
+    where
+        S::Output: Sized,
+    {
+        self.storage.as_slices()
+    }
 
-        assert_eq!(None, m.insert(index, index));
-        assert_eq!([1001, 65, 936], lens(&m));
-        assert_eq!(127, m[127]);
+    fn as_mut_slices(&mut self) -> (&mut [S::Output], &mut [S::Output])
+    where
+        S::Output: Sized,
+    {
+        self.storage.as_mut_slices()
+    }
 
-        assert_eq!(Some(index), m.remove(index));
-        assert_eq!([1000, 64, 936], lens(&m));
-        assert_eq!(127, m[127]);
+    fn heap_size(&self) -> usize {
+        self.storage.heap_size()
     }
 
-    #[test]
-    fn insert_remove_existing_m999() {
-        let mut m = (0..1000)
-            .map(|n| (n as isize, n))
-            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+    unsafe fn get_unchecked(&self, index: usize) -> &S::Output {
+        self.storage.get_unchecked(index)
+    }
 
-        assert_eq!([1000, 128, 872], lens(&m));
-        assert_eq!(127, m[127]);
+    unsafe fn get_unchecked_mut(&mut self, index: usize) -> &mut S::Output {
+        self.storage.get_unchecked_mut(index)
+    }
+}
 
-        let index = -999;
-        assert_eq!(None, m.insert(index, index));
-        assert_eq!([1001, 1, 1000], lens(&m));
-        assert_eq!(127, m[127]);
+// Clones of a map share one callback. A limit set with `set_capacity_limit` alone has no callback and drops what it
+// evicts. `overflow` holds a new key that was itself picked for eviction while an `Entry` still points at its value;
+// it reaches the callback at the map's next insert, or when the map is dropped.
+struct Eviction<K, V> {
+    limit: usize,
+    on_evict: Option<OnEvict<K, V>>,
+    overflow: Option<(K, V)>,
+}
 
-        assert_eq!(Some(index), m.remove(index));
-        assert_eq!([1000, 64, 936], lens(&m));
-        assert_eq!(127, m[127]);
+type OnEvict<K, V> = Arc<Mutex<dyn FnMut(K, V) + Send>>;
 
-        m.shrink_to_fit();
-        assert_eq!([1000, 64, 936], lens(&m));
-        assert_eq!(127, m[127]);
+type OnTierEvent<K> = Arc<Mutex<dyn FnMut(TierEvent<K>) + Send>>;
 
-        assert_eq!(None, m.insert(index, index));
-        assert_eq!([1001, 1, 1000], lens(&m));
-        assert_eq!(127, m[127]);
+// What each key touched since `checkpoint` held before its first change, `None` if it was absent. `clone` is captured
+// when the checkpoint is taken, so the mutators that record into the log don't need `Value: Clone` themselves.
+struct UndoLog<K, V> {
+    clone: fn(&V) -> V,
+    seen: BTreeSet<K>,
+    entries: Vec<(K, Option<V>)>,
+}
 
-        assert_eq!(Some(index), m.remove(index));
-        assert_eq!([1000, 64, 936], lens(&m));
-        assert_eq!(127, m[127]);
+// Counts promotions, demotions and re-anchors so a `TierGuard` can tell whether any key has changed tier since it was
+// taken. Zero-sized unless the `debug-guards` feature is on in a debug build.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Generation {
+    #[cfg(all(feature = "debug-guards", debug_assertions))]
+    count: u64,
+}
+
+#[cfg(all(feature = "debug-guards", debug_assertions))]
+impl Generation {
+    fn bump(&mut self) {
+        self.count += 1;
     }
 
-    #[test]
-    fn insert_outside_top() {
-        let mut map: TopMap<[Option<(isize, isize)>; 128]> = TopMap::new();
-        assert_eq!(None, map.insert(-63, 93));
-        assert_eq!(93, map[-63]);
+    fn changes_since(self, earlier: Self) -> u64 {
+        self.count.wrapping_sub(earlier.count)
+    }
+}
 
-        assert_eq!(None, map.insert(87, 14));
-        assert_eq!(93, map[-63]);
-        assert_eq!(14, map[87]);
+#[cfg(not(all(feature = "debug-guards", debug_assertions)))]
+impl Generation {
+    fn bump(&mut self) {}
 
-        assert_eq!(None, map.insert(0, 45));
-        assert_eq!(93, map[-63]);
-        assert_eq!(14, map[87]);
-        assert_eq!(45, map[0]);
+    fn changes_since(self, _earlier: Self) -> u64 {
+        0
+    }
+}
 
-        assert_eq!(Some(93), map.remove(-63));
-        assert_eq!(None, map.get(-63));
-        assert_eq!(14, map[87]);
-        assert_eq!(45, map[0]);
+// The ordered maps either side of the window. With the `allocator_api` feature their nodes come from the layout's
+// allocator, so every empty tree is made by `new_tree` and every collected one by `tree_from`.
+#[cfg(feature = "allocator_api")]
+type Tree<A> = BTreeMap<<A as Layout>::Key, <A as Layout>::Value, <A as Layout>::Alloc>;
 
-        assert_eq!(Some(14), map.insert(87, 14));
-        assert_eq!(None, map.get(-63));
-        assert_eq!(14, map[87]);
-        assert_eq!(45, map[0]);
-    }
+#[cfg(not(feature = "allocator_api"))]
+type Tree<A> = BTreeMap<<A as Layout>::Key, <A as Layout>::Value>;
 
-    #[derive(Clone, Debug)]
-    enum Action<Key, Value> {
-        Insert { key: Key, value: Value },
-        Remove { key: Key },
-        Get { key: Key },
-    }
+// The window entries and the rest tier, as returned by `into_parts`.
+type Parts<A> = (Vec<(<A as Layout>::Key, <A as Layout>::Value)>, Tree<A>);
 
-    impl<Key: Arbitrary, Value: Arbitrary> Arbitrary for Action<Key, Value> {
-        fn arbitrary<G: Gen>(g: &mut G) -> Self {
-            match u8::arbitrary(g) % 3 {
-                0 => Action::Insert {
-                    key: Key::arbitrary(g),
-                    value: Value::arbitrary(g),
-                },
-                1 => Action::Remove { key: Key::arbitrary(g) },
-                2 => Action::Get { key: Key::arbitrary(g) },
-                _ => unreachable!(),
-            }
-        }
-    }
+#[cfg(feature = "allocator_api")]
+type TreeEntry<'a, A> = btree_map::Entry<'a, <A as Layout>::Key, <A as Layout>::Value, <A as Layout>::Alloc>;
 
-    fn matches_btree_map(actions: Vec<Action<isize, isize>>) -> bool {
-        let mut map1 = BTreeMap::new();
-        let mut map2: TopMap<[Option<(isize, isize)>; 128]> = TopMap::new();
+#[cfg(not(feature = "allocator_api"))]
+type TreeEntry<'a, A> = btree_map::Entry<'a, <A as Layout>::Key, <A as Layout>::Value>;
 
-        for action in actions {
-            match action {
-                Action::Insert { key, value } => if map1.insert(key, value) != map2.insert(key, value) {
-                    return false;
-                },
+#[cfg(feature = "allocator_api")]
+fn new_tree<A: Layout>(top: &A::Storage) -> Tree<A> {
+    BTreeMap::new_in(A::allocator(top))
+}
 
-                Action::Remove { key } => if map1.remove(&key) != map2.remove(key) {
-                    return false;
-                },
+#[cfg(not(feature = "allocator_api"))]
+fn new_tree<A: Layout>(_top: &A::Storage) -> Tree<A> {
+    BTreeMap::new()
+}
+
+#[cfg(feature = "allocator_api")]
+fn tree_from<A, I>(top: &A::Storage, iter: I) -> Tree<A>
+where
+    A: Layout,
+    A::Key: Ord,
+    I: IntoIterator<Item = (A::Key, A::Value)>,
+{
+    let mut tree = new_tree::<A>(top);
+    tree.extend(iter);
+    tree
+}
+
+#[cfg(not(feature = "allocator_api"))]
+fn tree_from<A, I>(_top: &A::Storage, iter: I) -> Tree<A>
+where
+    A: Layout,
+    A::Key: Ord,
+    I: IntoIterator<Item = (A::Key, A::Value)>,
+{
+    iter.into_iter().collect()
+}
+
+// The rest tier. It owns its tree until `cow_clone`, after which several maps share one tree until one of them
+// changes it and takes its own copy. The shared tree sits behind a trait object so that only `cow_clone` needs the
+// tree to be `Send + Sync`, rather than every map that wants to be `Send`.
+enum RestTier<T> {
+    Owned(T),
+    Shared(Arc<dyn SharedTree<T>>),
+}
+
+trait SharedTree<T>: Send + Sync {
+    fn tree(&self) -> &T;
+    fn tree_mut(&mut self) -> &mut T;
+    fn copy(&self) -> T;
+    fn share(&self, tree: T) -> Arc<dyn SharedTree<T>>;
+}
+
+impl<T: Clone + Send + Sync + 'static> SharedTree<T> for T {
+    fn tree(&self) -> &T {
+        self
+    }
+
+    fn tree_mut(&mut self) -> &mut T {
+        self
+    }
+
+    fn copy(&self) -> T {
+        self.clone()
+    }
+
+    fn share(&self, tree: T) -> Arc<dyn SharedTree<T>> {
+        Arc::new(tree)
+    }
+}
+
+impl<T> RestTier<T> {
+    fn make_mut(&mut self) -> &mut T {
+        match *self {
+            RestTier::Owned(ref mut map) => map,
+            RestTier::Shared(ref mut shared) => {
+                if Arc::get_mut(shared).is_none() {
+                    *shared = (**shared).share((**shared).copy());
+                }
+
+                Arc::get_mut(shared).unwrap().tree_mut()
+            }
+        }
+    }
+
+    // Swaps in `empty`, which `new_tree` makes for the map's allocator.
+    fn take(&mut self, empty: T) -> T {
+        match *self {
+            RestTier::Owned(ref mut map) => mem::replace(map, empty),
+            RestTier::Shared(ref mut shared) => match Arc::get_mut(shared) {
+                Some(map) => mem::replace(map.tree_mut(), empty),
+                None => {
+                    let map = (**shared).copy();
+                    *shared = (**shared).share(empty);
+                    map
+                }
+            },
+        }
+    }
+
+    fn set(&mut self, map: T) {
+        match *self {
+            RestTier::Owned(ref mut rest) => *rest = map,
+            RestTier::Shared(ref mut shared) => match Arc::get_mut(shared) {
+                Some(rest) => *rest.tree_mut() = map,
+                None => *shared = (**shared).share(map),
+            },
+        }
+    }
+
+    fn is_shared(&self) -> bool {
+        match *self {
+            RestTier::Owned(_) => false,
+            RestTier::Shared(ref shared) => Arc::strong_count(shared) > 1,
+        }
+    }
+
+    // The `Arc` allocation holding a shared tree, after its two reference counts.
+    fn heap_size(&self) -> usize {
+        match *self {
+            RestTier::Owned(_) => 0,
+            RestTier::Shared(_) => 2 * mem::size_of::<usize>() + mem::size_of::<T>(),
+        }
+    }
+}
+
+// Maps in copy-on-write mode share the tree; the rest copy it up front.
+impl<T: Clone> Clone for RestTier<T> {
+    fn clone(&self) -> Self {
+        match *self {
+            RestTier::Owned(ref map) => RestTier::Owned(map.clone()),
+            RestTier::Shared(ref shared) => RestTier::Shared(shared.clone()),
+        }
+    }
+}
+
+impl<T> ops::Deref for RestTier<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match *self {
+            RestTier::Owned(ref map) => map,
+            RestTier::Shared(ref shared) => (**shared).tree(),
+        }
+    }
+}
+
+/// Marks the start of a batch of changes that `rollback` can undo; see `TopMap::checkpoint`.
+#[must_use = "pass the checkpoint to `rollback` or `commit`"]
+#[derive(Debug)]
+pub struct Checkpoint {
+    _private: (),
+}
+
+impl<K, V> Eviction<K, V> {
+    fn evict(&self, key: K, value: V) {
+        if let Some(ref on_evict) = self.on_evict {
+            let mut on_evict = on_evict.lock().unwrap_or_else(PoisonError::into_inner);
+            (*on_evict)(key, value);
+        }
+    }
+}
+
+impl<K, V> Drop for Eviction<K, V> {
+    fn drop(&mut self) {
+        if let Some((key, value)) = self.overflow.take() {
+            self.evict(key, value);
+        }
+    }
+}
+
+impl<K, V> Clone for Eviction<K, V> {
+    fn clone(&self) -> Self {
+        Eviction {
+            limit: self.limit,
+            on_evict: self.on_evict.clone(),
+            overflow: None,
+        }
+    }
+}
+
+/// Integer values that `TopMap::checked_add` and `saturating_add` can accumulate into.
+pub trait Counter: Copy + Default {
+    fn checked_add(self, delta: Self) -> Option<Self>;
+    fn saturating_add(self, delta: Self) -> Self;
+}
+
+macro_rules! counter {
+    ($($t:ty)*) => {
+        $(
+            impl Counter for $t {
+                fn checked_add(self, delta: $t) -> Option<$t> {
+                    <$t>::checked_add(self, delta)
+                }
+
+                fn saturating_add(self, delta: $t) -> $t {
+                    <$t>::saturating_add(self, delta)
+                }
+            }
+        )*
+    };
+}
+
+counter!(i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize);
+
+pub struct TopMap<A>
+where
+    A: Layout,
+{
+    anchor: Option<A::Key>,
+    below: Tree<A>,
+    top: A::Storage,
+    occupied: usize,
+    rest: RestTier<Tree<A>>,
+    rest_min: Option<A::Key>,
+    metrics: TierMetrics,
+    eviction: Option<Eviction<A::Key, A::Value>>,
+    reanchor: Option<ReanchorPolicy>,
+    far_below: FarBelowPolicy,
+    demotion: DemotionPolicy,
+    touched: BTreeSet<A::Key>,
+    refill_margin: usize,
+    undo: Option<UndoLog<A::Key, A::Value>>,
+    observer: Option<OnTierEvent<A::Key>>,
+    generation: Generation,
+}
+
+/// A map keyed by `isize` with a 64-slot window. Keys are `isize` rather than `i64` because slot offsets are computed
+/// through `isize: From<Key>`.
+pub type TopMap64<V> = TopMap<[Option<(isize, V)>; 64]>;
+
+/// A map keyed by `isize` with a 128-slot window.
+pub type TopMap128<V> = TopMap<[Option<(isize, V)>; 128]>;
+
+/// A map keyed by `isize` with a 256-slot window.
+pub type TopMap256<V> = TopMap<[Option<(isize, V)>; 256]>;
+
+/// The map types and the common aliases, for a glob import.
+pub mod prelude {
+    pub use super::{DefaultTopMap, Entry, OccupiedEntry, TopMap, TopMap128, TopMap256, TopMap64, TopSet};
+}
+
+/// Fallible forms of the queries that can otherwise panic, for callers that audit their code for panics.
+///
+/// `TopMap::try_get` and `try_get_mut` stand in for `Index` and `IndexMut`, and `FrozenTopMap::get` for the frozen
+/// map's `Index`. `iter_slots`, `gaps` and `gaps_in` name the keys of empty window slots, and panic if a slot has no
+/// key of the key type; `CheckedSlots` has versions that return `TopMapError::OutOfKeyRange` instead. The other
+/// queries clamp keys far from the window rather than overflowing, and don't panic. Mutating methods still panic
+/// where their docs say so.
+pub mod checked {
+    use std::convert::TryFrom;
+    use std::ops;
+
+    use super::{Layout, Storage, TopMap, TopMapError};
+
+    /// What `try_iter_slots` returns: each window slot's key with its value, if any, in key order.
+    pub type Slots<'a, K, V> = Result<Vec<(K, Option<&'a V>)>, TopMapError<K>>;
+
+    pub trait CheckedSlots {
+        type Key;
+        type Value;
+
+        /// Like `TopMap::iter_slots`, collected.
+        fn try_iter_slots(&self) -> Slots<Self::Key, Self::Value>;
+
+        /// Like `TopMap::gaps`, collected.
+        fn try_gaps(&self) -> Result<Vec<Self::Key>, TopMapError<Self::Key>>;
+
+        /// Like `TopMap::gaps_in`, collected.
+        fn try_gaps_in(&self, range: ops::Range<Self::Key>) -> Result<Vec<Self::Key>, TopMapError<Self::Key>>;
+    }
+
+    impl<A> CheckedSlots for TopMap<A>
+    where
+        A: Layout,
+        A::Key: Copy + Ord + TryFrom<isize>,
+        isize: From<A::Key>,
+    {
+        type Key = A::Key;
+        type Value = A::Value;
+
+        fn try_iter_slots(&self) -> Slots<A::Key, A::Value> {
+            (0..self.top.len())
+                .map(|index| match self.top[index] {
+                    Some((key, ref value)) => Ok((key, Some(value))),
+                    None => self.try_slot_key(index).map(|key| (key, None)),
+                })
+                .collect()
+        }
+
+        fn try_gaps(&self) -> Result<Vec<A::Key>, TopMapError<A::Key>> {
+            self.try_gaps_between(0, self.top.len())
+        }
+
+        fn try_gaps_in(&self, range: ops::Range<A::Key>) -> Result<Vec<A::Key>, TopMapError<A::Key>> {
+            self.try_gaps_between(self.clamped_slot(range.start, false), self.clamped_slot(range.end, false))
+        }
+    }
+
+    impl<A> TopMap<A>
+    where
+        A: Layout,
+        A::Key: Copy + Ord + TryFrom<isize>,
+        isize: From<A::Key>,
+    {
+        fn try_slot_key(&self, index: usize) -> Result<A::Key, TopMapError<A::Key>> {
+            self.slot_key(index).ok_or(TopMapError::OutOfKeyRange { offset: index })
+        }
+
+        fn try_gaps_between(&self, start: usize, end: usize) -> Result<Vec<A::Key>, TopMapError<A::Key>> {
+            (start..end)
+                .filter(|&index| self.top[index].is_none())
+                .map(|index| self.try_slot_key(index))
+                .collect()
+        }
+    }
+}
+
+impl<A> TopMap<A>
+where
+    A: Layout,
+    A::Key: Ord,
+{
+    pub fn with_array(array: A) -> Self {
+        TopMapBuilder::with_array(array).build()
+    }
+}
+
+impl<A> TopMap<A>
+where
+    A: Layout,
+    A::Key: Ord,
+    A::Storage: Default,
+{
+    /// Not a `const fn`: the window storage comes from `Default::default`, which a `const fn` can't call, so a map in
+    /// a `static` goes behind a `LazyLock`.
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn with_first_key(anchor: A::Key) -> Self {
+        Self::builder().anchor(anchor).build()
+    }
+
+    /// Creates a map holding at most `limit` entries, passing the largest entries to `on_evict` to make room.
+    pub fn with_eviction_limit<F>(limit: usize, on_evict: F) -> Self
+    where
+        F: FnMut(A::Key, A::Value) + Send + 'static,
+    {
+        Self::builder().eviction_limit(limit, on_evict).build()
+    }
+
+    pub fn builder() -> TopMapBuilder<A> {
+        TopMapBuilder::with_storage(A::Storage::default())
+    }
+}
+
+impl<A> Default for TopMap<A>
+where
+    A: Layout,
+    A::Key: Ord,
+    A::Storage: Default,
+{
+    fn default() -> Self {
+        TopMap::new()
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+impl<A, Al> TopMap<InAllocator<A, Al>>
+where
+    A: Array,
+    A::Key: Ord,
+    Al: Allocator + Clone,
+{
+    /// Creates an empty map whose ordered maps allocate their nodes from `alloc`.
+    pub fn new_in(alloc: Al) -> Self {
+        TopMapBuilder::with_storage(AllocStorage {
+            storage: FixedStorage::default(),
+            alloc,
+        })
+        .build()
+    }
+}
+
+/// Collects a map's settings before creating it: `TopMap::builder().anchor(1000).refill_margin(8).build()`. Settings
+/// left alone match `TopMap::new`.
+pub struct TopMapBuilder<A>
+where
+    A: Layout,
+{
+    top: A::Storage,
+    anchor: Option<A::Key>,
+    eviction: Option<Eviction<A::Key, A::Value>>,
+    reanchor: Option<ReanchorPolicy>,
+    far_below: FarBelowPolicy,
+    demotion: DemotionPolicy,
+    refill_margin: usize,
+}
+
+impl<A> TopMapBuilder<A>
+where
+    A: Layout,
+    A::Key: Ord,
+{
+    fn with_storage(top: A::Storage) -> Self {
+        Self {
+            top,
+            anchor: None,
+            eviction: None,
+            reanchor: None,
+            far_below: FarBelowPolicy::default(),
+            demotion: DemotionPolicy::default(),
+            refill_margin: 0,
+        }
+    }
+
+    /// Starts a builder around `array`, e.g. a `DynArray` sized at runtime.
+    pub fn with_array(array: A) -> Self {
+        Self::with_storage(array.into_storage())
+    }
+
+    /// Anchors the window at `key` before the first insert.
+    pub fn anchor(self, key: A::Key) -> Self {
+        Self {
+            anchor: Some(key),
+            ..self
+        }
+    }
+
+    pub fn eviction_limit<F>(self, limit: usize, on_evict: F) -> Self
+    where
+        F: FnMut(A::Key, A::Value) + Send + 'static,
+    {
+        assert!(limit > 0, "eviction limit must be positive");
+
+        Self {
+            eviction: Some(Eviction {
+                limit,
+                on_evict: Some(Arc::new(Mutex::new(on_evict))),
+                overflow: None,
+            }),
+            ..self
+        }
+    }
+
+    pub fn reanchor_policy(self, policy: ReanchorPolicy) -> Self {
+        Self {
+            reanchor: Some(policy),
+            ..self
+        }
+    }
+
+    pub fn far_below_policy(self, policy: FarBelowPolicy) -> Self {
+        Self {
+            far_below: policy,
+            ..self
+        }
+    }
+
+    pub fn demotion_policy(self, policy: DemotionPolicy) -> Self {
+        Self {
+            demotion: policy,
+            ..self
+        }
+    }
+
+    pub fn refill_margin(self, margin: usize) -> Self {
+        Self {
+            refill_margin: margin,
+            ..self
+        }
+    }
+
+    pub fn build(self) -> TopMap<A> {
+        let below = new_tree::<A>(&self.top);
+        let rest = new_tree::<A>(&self.top);
+
+        TopMap {
+            anchor: self.anchor,
+            below,
+            top: self.top,
+            occupied: 0,
+            rest: RestTier::Owned(rest),
+            rest_min: None,
+            metrics: TierMetrics::default(),
+            eviction: self.eviction,
+            reanchor: self.reanchor,
+            far_below: self.far_below,
+            demotion: self.demotion,
+            touched: BTreeSet::new(),
+            refill_margin: self.refill_margin,
+            undo: None,
+            observer: None,
+            generation: Generation::default(),
+        }
+    }
+}
+
+impl<A> TopMapBuilder<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+    isize: From<A::Key>,
+{
+    /// Builds the map and loads `iter`, whose keys should ascend, as `insert_sorted_iter` would.
+    pub fn build_from_sorted<I>(self, iter: I) -> TopMap<A>
+    where
+        I: IntoIterator<Item = (A::Key, A::Value)>,
+    {
+        let mut m = self.build();
+        m.insert_sorted_iter(iter);
+        m
+    }
+}
+
+/// Clones share the eviction callback, which then sees the entries evicted from either map.
+impl<A> Clone for TopMap<A>
+where
+    A: Layout,
+    A::Key: Clone,
+    A::Value: Clone,
+    A::Storage: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            anchor: self.anchor.clone(),
+            below: self.below.clone(),
+            top: self.top.clone(),
+            occupied: self.occupied,
+            rest: self.rest.clone(),
+            rest_min: self.rest_min.clone(),
+            metrics: self.metrics,
+            eviction: self.eviction.clone(),
+            reanchor: self.reanchor,
+            far_below: self.far_below,
+            demotion: self.demotion,
+            touched: self.touched.clone(),
+            refill_margin: self.refill_margin,
+            undo: None,
+            observer: self.observer.clone(),
+            generation: self.generation,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TierMetrics {
+    pub demotions: u64,
+    pub promotions: u64,
+    pub window_evictions: u64,
+    pub refills: u64,
+    pub reanchors: u64,
+}
+
+/// Moves the window up to the smallest rest key once fewer than `max_occupied` top slots are in use and at least
+/// `min_hot_rest` rest keys would fit in the window from there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReanchorPolicy {
+    pub max_occupied: usize,
+    pub min_hot_rest: usize,
+}
+
+/// What to do with a key inserted at least a whole window below the anchor.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FarBelowPolicy {
+    /// Move the window down to the new key, demoting everything in the top tier.
+    #[default]
+    Reanchor,
+    /// Leave the window where it is and keep the new key in an ordered map below it.
+    KeepWindow,
+}
+
+/// Which entry makes way when an insert into a map at its capacity limit needs room. A window slot is tied to its key,
+/// so moving the window always demotes the keys past its new end; the policy picks what leaves the map.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DemotionPolicy {
+    /// Evict the largest key, which may be the new key itself.
+    #[default]
+    LargestFirst,
+    /// Walk down from the largest key, sparing each key marked by `touch` once and clearing its mark, and evict the
+    /// first unmarked key. The new key counts as unmarked.
+    SecondChance,
+}
+
+/// A change to which keys the window holds, passed to the observer from `set_tier_observer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TierEvent<K> {
+    /// `key` moved out of the window into one of the ordered maps.
+    Demoted { key: K },
+    /// `key` moved from one of the ordered maps into the window.
+    Promoted { key: K },
+    /// The window moved from starting at `old` to starting at `new`.
+    WindowReanchored { old: K, new: K },
+    /// The empty window was anchored at `key`.
+    WindowAnchored { key: K },
+    /// The window anchored at `key` emptied and gave up its anchor.
+    WindowCleared { key: K },
+}
+
+/// Where `tier_of` found a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tier {
+    /// In the ordered map of keys below the window.
+    Below,
+    /// In the window, `slot` places after the anchor.
+    Top { slot: usize },
+    /// In the ordered map of keys past the window.
+    Rest,
+}
+
+/// Remembers a map's tier placement, from `tier_guard` or `iter_guarded`. With the `debug-guards` feature in a debug
+/// build, `check` panics once any key has been promoted or demoted or the window has moved; otherwise it does nothing.
+#[derive(Clone, Copy, Debug)]
+pub struct TierGuard {
+    generation: Generation,
+}
+
+impl TierGuard {
+    pub fn check<A: Layout>(&self, map: &TopMap<A>) {
+        assert!(
+            self.generation == map.generation,
+            "tier placement changed since the guard was taken ({} promotions, demotions or re-anchors)",
+            map.generation.changes_since(self.generation)
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+    pub top: usize,
+    pub rest: usize,
+    pub other: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.top + self.rest + self.other
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats<K> {
+    pub top_capacity: usize,
+    pub top_occupied: usize,
+    pub leading_holes: usize,
+    pub trailing_holes: usize,
+    pub rest_len: usize,
+    pub anchor: Option<K>,
+    pub span: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidParts {
+    UnsortedTop,
+    TopTooWide,
+    RestOverlapsTop,
+}
+
+impl fmt::Display for InvalidParts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            InvalidParts::UnsortedTop => "top entries are not in ascending key order",
+            InvalidParts::TopTooWide => "top entries span more keys than the top can hold",
+            InvalidParts::RestOverlapsTop => "rest keys must not fall between the first and last top key",
+        })
+    }
+}
+
+impl Error for InvalidParts {}
+
+/// Why a `try_get` or `try_get_mut` found no value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TopMapError<K> {
+    /// No entry has this key.
+    Missing(K),
+    /// The window slot for `key` holds `found` instead, which means the map's internal layout is broken.
+    MisplacedSlot { key: K, found: K },
+    /// Window slot `offset` has no key of the key type, which means the window has grown past the largest key or
+    /// lost its anchor.
+    OutOfKeyRange { offset: usize },
+}
+
+impl<K: fmt::Debug> fmt::Display for TopMapError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TopMapError::Missing(ref key) => write!(f, "no item with key {:?}", key),
+            TopMapError::MisplacedSlot { ref key, ref found } => {
+                write!(f, "window slot for key {:?} holds key {:?}", key, found)
+            }
+            TopMapError::OutOfKeyRange { offset } => {
+                write!(f, "window slot {} runs past the range of the key type", offset)
+            }
+        }
+    }
+}
+
+impl<K: fmt::Debug> Error for TopMapError<K> {}
+
+/// An internal invariant that `TopMap::check` found broken, with the key or counts involved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvariantViolation<K> {
+    /// The window has more slots than the storage can hold.
+    WindowTooLong { len: usize, max_size: usize },
+    /// The window has slots but no anchor to place them.
+    WindowWithoutAnchor,
+    /// `key` sits in window slot `index`, which belongs to a different key.
+    MisplacedSlot { key: K, index: usize },
+    /// The recorded count of occupied slots disagrees with the slots.
+    OccupiedCount { recorded: usize, actual: usize },
+    /// `key` is in the below map but not below the anchor.
+    BelowOverlapsWindow(K),
+    /// `key` is in the rest map but not past the end of the window.
+    RestOverlapsWindow(K),
+    /// The cached smallest rest key disagrees with the rest map.
+    StaleRestMin { recorded: Option<K>, actual: Option<K> },
+}
+
+impl<K> InvariantViolation<K> {
+    fn map_key<L, F: Fn(K) -> L>(self, f: F) -> InvariantViolation<L> {
+        use self::InvariantViolation::*;
+
+        match self {
+            WindowTooLong { len, max_size } => WindowTooLong { len, max_size },
+            WindowWithoutAnchor => WindowWithoutAnchor,
+            MisplacedSlot { key, index } => MisplacedSlot { key: f(key), index },
+            OccupiedCount { recorded, actual } => OccupiedCount { recorded, actual },
+            BelowOverlapsWindow(key) => BelowOverlapsWindow(f(key)),
+            RestOverlapsWindow(key) => RestOverlapsWindow(f(key)),
+            StaleRestMin { recorded, actual } => StaleRestMin {
+                recorded: recorded.map(&f),
+                actual: actual.map(&f),
+            },
+        }
+    }
+}
+
+impl<K: fmt::Debug> fmt::Display for InvariantViolation<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InvariantViolation::*;
+
+        match *self {
+            WindowTooLong { len, max_size } => write!(f, "window has {} slots but holds at most {}", len, max_size),
+            WindowWithoutAnchor => f.write_str("window has slots but no anchor"),
+            MisplacedSlot { ref key, index } => write!(f, "key {:?} is in window slot {}", key, index),
+            OccupiedCount { recorded, actual } => {
+                write!(f, "{} window slots recorded as occupied but {} are", recorded, actual)
+            }
+            BelowOverlapsWindow(ref key) => write!(f, "below key {:?} is not below the anchor", key),
+            RestOverlapsWindow(ref key) => write!(f, "rest key {:?} is not past the end of the window", key),
+            StaleRestMin { ref recorded, ref actual } => {
+                write!(f, "rest_min is {:?} but the smallest rest key is {:?}", recorded, actual)
+            }
+        }
+    }
+}
+
+impl<K: fmt::Debug> Error for InvariantViolation<K> {}
+
+// Node layout of std's BTreeMap, from `alloc/src/collections/btree/node.rs`: with `B = 6`, a node holds up to
+// `2 * B - 1` key/value pairs after its parent pointer and two `u16` indexes, and an internal node adds `2 * B` edges.
+// Inserting in key order splits a full node into `B` pairs, so such a tree settles at `B` pairs per leaf plus one
+// separator in the parent, and `B + 1` children per internal node. Random insertion fills nodes about a quarter more
+// and bulk loading (`collect`, `split_off`) fills them completely, so for those trees this overestimates.
+const BTREE_B: usize = 6;
+const BTREE_NODE_CAPACITY: usize = 2 * BTREE_B - 1;
+
+// The widest gap between consecutive keys that `get_many` walks across rather than looking the next key up afresh.
+// Integer keys bound the entries walked over by the gap. In the `lookup_many_rest` bench a walk of up to 16 entries
+// still beats a fresh descent of a 100,000-entry tree.
+const SORTED_WALK_GAP: isize = 16;
+
+// How many keys a refill promotes one at a time before splitting the rest of the batch off the rest map instead. In
+// the `refill_batch` bench, splitting after 11 keys refills 64 slots in about 2.1 us against 5.7 us one key at a time,
+// and 127 slots in 3.4 us against 8.8 us; splitting after 2 keys already makes a refill of 4 slots about twice as slow.
+const REFILL_SPLIT_AFTER: usize = 11;
+
+fn btree_heap_size<K, V>(len: usize) -> usize {
+    if len == 0 {
+        return 0;
+    }
+
+    let align = cmp::max(mem::align_of::<usize>(), cmp::max(mem::align_of::<K>(), mem::align_of::<V>()));
+    let round = |size: usize| size.div_ceil(align) * align;
+
+    let leaf_size = round(
+        mem::size_of::<usize>()
+            + BTREE_NODE_CAPACITY * (mem::size_of::<K>() + mem::size_of::<V>())
+            + 2 * mem::size_of::<u16>(),
+    );
+
+    // Each level of internal nodes has a `B + 1`th as many nodes as the one below, which sums to a `B`th of the leaves.
+    let internal_size = leaf_size + (BTREE_NODE_CAPACITY + 1) * mem::size_of::<usize>();
+    let leaves = len.div_ceil(BTREE_B + 1);
+    let internals = (leaves - 1) / BTREE_B;
+    leaves * leaf_size + internals * internal_size
+}
+
+// Splits off the keys at or past `bound` when it starts a range.
+fn split_from<A>(map: &mut Tree<A>, bound: Bound<A::Key>, top: &A::Storage) -> Tree<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+{
+    match bound {
+        Bound::Included(key) => map.split_off(&key),
+        Bound::Excluded(key) => {
+            let mut upper = map.split_off(&key);
+
+            if let Some(value) = upper.remove(&key) {
+                map.insert(key, value);
+            }
+
+            upper
+        }
+        Bound::Unbounded => mem::replace(map, new_tree::<A>(top)),
+    }
+}
+
+// Removes the keys in `range` with two splits and a join, returning how many went.
+fn remove_map_range<A>(map: &mut Tree<A>, range: (Bound<A::Key>, Bound<A::Key>), top: &A::Storage) -> usize
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+{
+    if map.is_empty() {
+        return 0;
+    }
+
+    let mut middle = split_from::<A>(map, range.0, top);
+
+    let mut upper = match range.1 {
+        Bound::Included(key) => split_from::<A>(&mut middle, Bound::Excluded(key), top),
+        Bound::Excluded(key) => split_from::<A>(&mut middle, Bound::Included(key), top),
+        Bound::Unbounded => new_tree::<A>(top),
+    };
+
+    if map.len() < upper.len() {
+        mem::swap(map, &mut upper);
+    }
+
+    map.append(&mut upper);
+    middle.len()
+}
+
+/// A value from one of two maps, as yielded by `symmetric_difference`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum JoinKind {
+    Full,
+    Left,
+    Inner,
+}
+
+// Walks two ascending sequences together, pairing up entries with equal keys. A full join yields every entry, a left
+// join the left side's entries and an inner join only the pairs; the walk stops once nothing more can be yielded.
+struct Join<L: Iterator, R: Iterator> {
+    left: Peekable<L>,
+    right: Peekable<R>,
+    kind: JoinKind,
+}
+
+impl<K, X, Y, L, R> Iterator for Join<L, R>
+where
+    K: Ord,
+    L: Iterator<Item = (K, X)>,
+    R: Iterator<Item = (K, Y)>,
+{
+    type Item = (K, Option<X>, Option<Y>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ordering = match (self.left.peek(), self.right.peek()) {
+                (Some(left), Some(right)) => left.0.cmp(&right.0),
+                (Some(_), None) if self.kind != JoinKind::Inner => cmp::Ordering::Less,
+                (None, Some(_)) if self.kind == JoinKind::Full => cmp::Ordering::Greater,
+                _ => return None,
+            };
+
+            match ordering {
+                cmp::Ordering::Less => {
+                    let (key, left) = self.left.next().unwrap();
+
+                    if self.kind != JoinKind::Inner {
+                        return Some((key, Some(left), None));
+                    }
+                }
+
+                cmp::Ordering::Greater => {
+                    let (key, right) = self.right.next().unwrap();
+
+                    if self.kind == JoinKind::Full {
+                        return Some((key, None, Some(right)));
+                    }
+                }
+
+                cmp::Ordering::Equal => {
+                    let (key, left) = self.left.next().unwrap();
+                    let (_, right) = self.right.next().unwrap();
+                    return Some((key, Some(left), Some(right)));
+                }
+            }
+        }
+    }
+}
+
+fn positive(i: isize) -> Option<usize> {
+    if i >= 0 {
+        Some(i as usize)
+    } else {
+        None
+    }
+}
+
+// How many slots `key` sits past `base`: `None` if it's below `base`, or too far above it for an `isize` offset.
+fn key_offset<K>(key: K, base: K) -> Option<usize>
+where
+    isize: From<K>,
+{
+    isize::from(key).checked_sub(isize::from(base)).and_then(positive)
+}
+
+enum Index<'a> {
+    AboveTop { distance: usize },
+    InsideTop { index: usize, _pd: PhantomData<&'a ()> },
+    OutsideTop { index: usize, _pd: PhantomData<&'a ()> },
+    Rest,
+}
+
+pub enum Entry<'a, A: 'a>
+where
+    A: Layout,
+{
+    AboveTop {
+        key: A::Key,
+        map: &'a mut TopMap<A>,
+        distance: usize,
+    },
+
+    RestMin {
+        key: A::Key,
+        map: &'a mut TopMap<A>,
+    },
+
+    OutsideTop {
+        key: A::Key,
+        map: &'a mut TopMap<A>,
+        index: usize,
+    },
+
+    AtLimit {
+        key: A::Key,
+        map: &'a mut TopMap<A>,
+    },
+
+    Vec(A::Key, &'a mut Option<(A::Key, A::Value)>, &'a mut usize),
+    BTreeMap(TreeEntry<'a, A>),
+}
+
+impl<'a, A> Entry<'a, A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+    isize: From<A::Key>,
+{
+    fn insert(self, value: A::Value) -> Option<A::Value> {
+        match self {
+            Entry::AboveTop { key, map, distance } => {
+                map.occupied += 1;
+                *map.insert_above_top(key, distance) = Some((key, value));
+                None
+            }
+
+            Entry::RestMin { key, map } => {
+                map.insert_rest(key, value);
+                None
+            }
+
+            Entry::OutsideTop { key, map, index } => {
+                map.occupied += 1;
+                *map.insert_outside_top(key, index) = Some((key, value));
+                None
+            }
+
+            Entry::AtLimit { key, map } => {
+                match map.take_victim(key) {
+                    Some((victim, victim_value)) => {
+                        map.evict_entry(victim, victim_value);
+                        map.tier_entry(key).insert(value);
+                    }
+
+                    None => map.evict_entry(key, value),
+                }
+
+                None
+            }
+
+            Entry::Vec(key, entry, occupied) => {
+                let old_entry = entry.replace((key, value));
+
+                if old_entry.is_none() {
+                    *occupied += 1;
+                }
+
+                Some(old_entry?.1)
+            }
+
+            Entry::BTreeMap(btree_map::Entry::Occupied(mut entry)) => Some(entry.insert(value)),
+
+            Entry::BTreeMap(btree_map::Entry::Vacant(entry)) => {
+                entry.insert(value);
+                None
+            }
+        }
+    }
+
+    pub fn or_insert(self, default: A::Value) -> &'a mut A::Value {
+        match self {
+            Entry::AboveTop { key, map, distance } => {
+                map.occupied += 1;
+                &mut map.insert_above_top(key, distance).get_or_insert((key, default)).1
+            }
+
+            Entry::RestMin { key, map } => {
+                map.rest_min = Some(key);
+                map.rest.make_mut().entry(key).or_insert(default)
+            }
+
+            Entry::OutsideTop { key, map, index } => {
+                map.occupied += 1;
+                &mut map.insert_outside_top(key, index).get_or_insert((key, default)).1
+            }
+
+            Entry::AtLimit { key, map } => map.insert_at_limit(key, default),
+
+            Entry::Vec(key, entry, occupied) => {
+                if entry.is_none() {
+                    *occupied += 1;
+                }
+
+                &mut entry.get_or_insert((key, default)).1
+            }
+
+            Entry::BTreeMap(entry) => entry.or_insert(default),
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> A::Value>(self, default: F) -> &'a mut A::Value {
+        match self {
+            Entry::AboveTop { key, map, distance } => {
+                map.occupied += 1;
+                &mut map.insert_above_top(key, distance).get_or_insert_with(|| (key, default())).1
+            }
+
+            Entry::RestMin { key, map } => {
+                map.rest_min = Some(key);
+                map.rest.make_mut().entry(key).or_insert_with(default)
+            }
+
+            Entry::OutsideTop { key, map, index } => {
+                map.occupied += 1;
+                &mut map.insert_outside_top(key, index).get_or_insert_with(|| (key, default())).1
+            }
+
+            Entry::AtLimit { key, map } => map.insert_at_limit(key, default()),
+
+            Entry::Vec(key, entry, occupied) => {
+                if entry.is_none() {
+                    *occupied += 1;
+                }
+
+                &mut entry.get_or_insert_with(|| (key, default())).1
+            }
+
+            Entry::BTreeMap(entry) => entry.or_insert_with(default),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut A::Value
+    where
+        A::Value: Default,
+    {
+        self.or_insert_with(Default::default)
+    }
+
+    /// Like `or_insert_with` for a fallible `default`. The map is only changed once `default` has succeeded, so on an
+    /// error it is left exactly as it was: nothing is moved out of the window, evicted at a capacity limit, or unmarked
+    /// for `DemotionPolicy::SecondChance`.
+    pub fn or_try_insert_with<E, F>(self, default: F) -> Result<&'a mut A::Value, E>
+    where
+        F: FnOnce() -> Result<A::Value, E>,
+    {
+        match self {
+            Entry::Vec(key, entry, occupied) => {
+                if entry.is_none() {
+                    *entry = Some((key, default()?));
+                    *occupied += 1;
+                }
+
+                Ok(&mut entry.as_mut().unwrap().1)
+            }
+
+            Entry::BTreeMap(btree_map::Entry::Occupied(entry)) => Ok(entry.into_mut()),
+            Entry::BTreeMap(btree_map::Entry::Vacant(entry)) => Ok(entry.insert(default()?)),
+
+            // The other variants are always vacant, and inserting into them may move the window
+            entry => {
+                let value = default()?;
+                Ok(entry.or_insert(value))
+            }
+        }
+    }
+
+    /// Stores `value`, replacing any current value, and returns an entry pointing at it.
+    pub fn insert_entry(self, value: A::Value) -> OccupiedEntry<'a, A> {
+        let key = self.key();
+
+        let value = match self {
+            Entry::Vec(key, entry, occupied) => {
+                if entry.replace((key, value)).is_none() {
+                    *occupied += 1;
+                }
+
+                &mut entry.as_mut().unwrap().1
+            }
+
+            Entry::BTreeMap(btree_map::Entry::Occupied(mut entry)) => {
+                entry.insert(value);
+                entry.into_mut()
+            }
+
+            entry => entry.or_insert(value),
+        };
+
+        OccupiedEntry { key, value }
+    }
+}
+
+impl<'a, A> Entry<'a, A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+{
+    pub fn key(&self) -> A::Key {
+        self.state().1
+    }
+
+    /// Abandons the entry and hands back its key, taking it out of the entry rather than copying it. The map is left
+    /// as it was, as when an entry is dropped.
+    pub fn into_key(self) -> A::Key {
+        match self {
+            Entry::AboveTop { key, .. } | Entry::RestMin { key, .. } | Entry::OutsideTop { key, .. } => key,
+            Entry::AtLimit { key, .. } => key,
+            Entry::Vec(key, _, _) => key,
+            Entry::BTreeMap(btree_map::Entry::Occupied(entry)) => *entry.key(),
+            Entry::BTreeMap(btree_map::Entry::Vacant(entry)) => entry.into_key(),
+        }
+    }
+
+    pub fn is_occupied(&self) -> bool {
+        self.state().2.is_some()
+    }
+
+    /// Like `{:?}` on the entry, but also shows the current value.
+    pub fn debug_value<'b>(&'b self) -> impl fmt::Debug + 'b
+    where
+        A::Key: fmt::Debug,
+        A::Value: fmt::Debug,
+    {
+        struct WithValue<'b, 'a: 'b, A: Layout + 'a>(&'b Entry<'a, A>);
+
+        impl<'b, 'a, A> fmt::Debug for WithValue<'b, 'a, A>
+        where
+            A: Layout,
+            A::Key: Copy + Ord + fmt::Debug,
+            A::Value: fmt::Debug,
+        {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                let (variant, key, value) = self.0.state();
+                f.debug_struct(variant).field("key", &key).field("value", &value).finish()
+            }
+        }
+
+        WithValue(self)
+    }
+
+    fn state(&self) -> (&'static str, A::Key, Option<&A::Value>) {
+        match *self {
+            Entry::AboveTop { key, .. } => ("AboveTop", key, None),
+            Entry::RestMin { key, .. } => ("RestMin", key, None),
+            Entry::OutsideTop { key, .. } => ("OutsideTop", key, None),
+            Entry::AtLimit { key, .. } => ("AtLimit", key, None),
+            Entry::Vec(key, ref entry, _) => ("Vec", key, entry.as_ref().map(|entry| &entry.1)),
+            Entry::BTreeMap(btree_map::Entry::Occupied(ref entry)) => ("BTreeMap", *entry.key(), Some(entry.get())),
+            Entry::BTreeMap(btree_map::Entry::Vacant(ref entry)) => ("BTreeMap", *entry.key(), None),
+        }
+    }
+}
+
+/// Shows the variant, the key and whether a value is present; see `Entry::debug_value` to include the value itself.
+impl<'a, A> fmt::Debug for Entry<'a, A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (variant, key, value) = self.state();
+        f.debug_struct(variant).field("key", &key).field("occupied", &value.is_some()).finish()
+    }
+}
+
+/// A key known to be present, with its value; returned by `insert_entry`.
+pub struct OccupiedEntry<'a, A: 'a>
+where
+    A: Layout,
+{
+    key: A::Key,
+    value: &'a mut A::Value,
+}
+
+impl<'a, A> OccupiedEntry<'a, A>
+where
+    A: Layout,
+    A::Key: Copy,
+{
+    pub fn key(&self) -> A::Key {
+        self.key
+    }
+
+    pub fn get(&self) -> &A::Value {
+        self.value
+    }
+
+    pub fn get_mut(&mut self) -> &mut A::Value {
+        self.value
+    }
+
+    pub fn into_mut(self) -> &'a mut A::Value {
+        self.value
+    }
+
+    /// Replaces the value, returning the old one.
+    pub fn insert(&mut self, value: A::Value) -> A::Value {
+        mem::replace(self.value, value)
+    }
+}
+
+impl<A> TopMap<A>
+where
+    A: Layout,
+{
+    pub fn len(&self) -> usize {
+        self.below.len() + self.occupied + self.rest.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage_breakdown().total()
+    }
+
+    pub fn memory_usage_breakdown(&self) -> MemoryUsage {
+        let top_inline = mem::size_of::<A::Storage>();
+        let rest_maps = mem::size_of::<Tree<A>>() + mem::size_of::<RestTier<Tree<A>>>();
+
+        let rest = rest_maps
+            + self.rest.heap_size()
+            + btree_heap_size::<A::Key, A::Value>(self.below.len())
+            + btree_heap_size::<A::Key, A::Value>(self.rest.len());
+
+        MemoryUsage {
+            top: top_inline + self.top.heap_size(),
+            rest,
+            other: mem::size_of::<Self>() - top_inline - rest_maps + btree_heap_size::<A::Key, ()>(self.touched.len()),
+        }
+    }
+
+    pub fn metrics(&self) -> TierMetrics {
+        self.metrics
+    }
+
+    pub fn reset_metrics(&mut self) {
+        self.metrics = TierMetrics::default();
+    }
+}
+
+fn ensure_index<T, S>(v: &mut S, index: usize) -> &mut Option<T>
+where
+    S: Storage + ops::IndexMut<usize, Output = Option<T>>,
+{
+    if let Some(count) = (index + 1).checked_sub(v.len()) {
+        for _ in 0..count {
+            *v.push_back() = None;
+        }
+    }
+
+    &mut v[index]
+}
+
+impl<A> TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+    isize: From<A::Key>,
+{
+    fn insert_above_top(&mut self, key: A::Key, distance: usize) -> &mut Option<(A::Key, A::Value)> {
+        if let Some(new_count) = self.top.max_size().checked_sub(distance) {
+            self.truncate_top(new_count);
+
+            for _ in 0..distance - 1 {
+                *self.top.push_front() = None;
+            }
+        } else {
+            let evicted = !self.top.is_empty();
+            let demoted = self.truncate_top(0);
+
+            if evicted {
+                self.metrics.window_evictions += 1;
+                trace_event!("evicted window", key = key, moved = demoted);
+            }
+        }
+
+        self.move_anchor(key);
+        *self.top.push_front() = None;
+
+        if !self.below.is_empty() {
+            for (below_key, value) in self.below.split_off(&key) {
+                match key_offset(below_key, key) {
+                    Some(index) if index < self.top.len() => {
+                        self.top[index] = Some((below_key, value));
+                        self.occupied += 1;
+                        self.notify(TierEvent::Promoted { key: below_key });
+                    }
+
+                    _ => self.insert_rest(below_key, value),
+                }
+            }
+        }
+
+        &mut self.top[0]
+    }
+
+    // Grows the window out to `index` for a key past its end, anchoring an empty window at the key. Only called once
+    // a value is known to be going in, so a dropped entry leaves no empty slots behind.
+    fn insert_outside_top(&mut self, key: A::Key, index: usize) -> &mut Option<(A::Key, A::Value)> {
+        if self.anchor.is_none() {
+            self.move_anchor(key);
+        }
+
+        ensure_index(&mut self.top, index)
+    }
+
+    fn truncate_top(&mut self, len: usize) -> usize {
+        let slots = self.top.len().saturating_sub(len);
+
+        // Demoted keys all sort before the rest map. Merging is linear in both sizes, so only worth it when the
+        // demoted batch is at least as big as the rest map; otherwise insert key by key.
+        let merge = slots > 1 && slots >= self.rest.len();
+        let mut demoted = Vec::new();
+        let mut count = 0;
+
+        while self.top.len() > len {
+            if let Some((key, value)) = self.top.pop_back().unwrap().take() {
+                self.notify(TierEvent::Demoted { key });
+
+                if merge {
+                    demoted.push((key, value));
+                } else {
+                    self.insert_rest(key, value);
+                }
+
+                count += 1;
+            }
+        }
+
+        if let Some(&(min_key, _)) = demoted.last() {
+            self.rest_min = Some(min_key);
+
+            let mut rest = tree_from::<A, _>(&self.top, demoted.into_iter().rev());
+            rest.append(self.rest.make_mut());
+            self.rest.set(rest);
+        }
+
+        self.occupied -= count;
+        self.metrics.demotions += count as u64;
+        self.debug_check_rest_min();
+        count
+    }
+
+    fn move_anchor(&mut self, key: A::Key) {
+        match self.anchor {
+            Some(old) if old != key => self.notify(TierEvent::WindowReanchored { old, new: key }),
+            Some(_) => {}
+            None => self.notify(TierEvent::WindowAnchored { key }),
+        }
+
+        self.anchor = Some(key);
+    }
+
+    fn notify(&mut self, event: TierEvent<A::Key>) {
+        self.generation.bump();
+
+        if let Some(ref observer) = self.observer {
+            let mut observer = observer.lock().unwrap_or_else(PoisonError::into_inner);
+            (*observer)(event);
+        }
+    }
+
+    fn clear_anchor(&mut self) {
+        if let Some(key) = self.anchor.take() {
+            self.notify(TierEvent::WindowCleared { key });
+        }
+
+        if let Some(&below_min) = self.below.keys().next() {
+            self.rest_min = Some(below_min);
+            self.rest.make_mut().append(&mut self.below);
+        }
+    }
+
+    // Logs the current value of `key` if a checkpoint is active and this is the key's first change since.
+    fn record(&mut self, key: A::Key) {
+        let clone = match self.undo {
+            Some(ref log) if !log.seen.contains(&key) => log.clone,
+            _ => return,
+        };
+
+        let old_value = self.get(key).map(clone);
+        let log = self.undo.as_mut().unwrap();
+        log.seen.insert(key);
+        log.entries.push((key, old_value));
+    }
+
+    fn record_range(&mut self, range: (Bound<A::Key>, Bound<A::Key>)) {
+        if self.undo.is_none() {
+            return;
+        }
+
+        let keys = self
+            .below
+            .range(range)
+            .map(|(&key, _)| key)
+            .chain(self.window_range(range).filter_map(|index| self.top[index].as_ref().map(|&(key, _)| key)))
+            .chain(self.rest.range(range).map(|(&key, _)| key))
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            self.record(key);
+        }
+    }
+
+    fn record_all(&mut self) {
+        self.record_range((Bound::Unbounded, Bound::Unbounded));
+    }
+
+    fn insert_rest(&mut self, key: A::Key, value: A::Value) {
+        if self.rest_min.is_none_or(|rest_min| key < rest_min) {
+            self.rest_min = Some(key);
+        }
+
+        self.rest.make_mut().insert(key, value);
+    }
+
+    fn remove_rest(&mut self, key: A::Key) -> Option<A::Value> {
+        if self.rest.is_shared() && !self.rest.contains_key(&key) {
+            return None;
+        }
+
+        let value = self.rest.make_mut().remove(&key)?;
+
+        if self.rest_min == Some(key) {
+            self.rest_min = self.rest.keys().next().cloned();
+        }
+
+        Some(value)
+    }
+
+    /// Verifies the layout the other methods rely on, reporting the first broken invariant. Leading holes are allowed:
+    /// `with_first_key` anchors the window ahead of its first entry.
+    pub fn check(&self) -> Result<(), InvariantViolation<A::Key>> {
+        let len = self.top.len();
+        let max_size = self.top.max_size();
+
+        if len > max_size {
+            return Err(InvariantViolation::WindowTooLong { len, max_size });
+        }
+
+        let anchor = match self.anchor {
+            Some(anchor) => isize::from(anchor),
+            None if len > 0 => return Err(InvariantViolation::WindowWithoutAnchor),
+            None => {
+                return match self.below.keys().next() {
+                    Some(&key) => Err(InvariantViolation::BelowOverlapsWindow(key)),
+                    None => self.check_rest_min(),
+                }
+            }
+        };
+
+        let mut actual = 0;
+
+        for index in 0..len {
+            if let Some((key, _)) = self.top[index] {
+                if isize::from(key).checked_sub(anchor) != Some(index as isize) {
+                    return Err(InvariantViolation::MisplacedSlot { key, index });
+                }
+
+                actual += 1;
+            }
+        }
+
+        if actual != self.occupied {
+            return Err(InvariantViolation::OccupiedCount {
+                recorded: self.occupied,
+                actual,
+            });
+        }
+
+        if let Some(&key) = self.below.keys().next_back() {
+            if isize::from(key) >= anchor {
+                return Err(InvariantViolation::BelowOverlapsWindow(key));
+            }
+        }
+
+        if let Some(&key) = self.rest.keys().next() {
+            if anchor.checked_add(len as isize).is_none_or(|end| isize::from(key) < end) {
+                return Err(InvariantViolation::RestOverlapsWindow(key));
+            }
+        }
+
+        self.check_rest_min()
+    }
+
+    fn check_rest_min(&self) -> Result<(), InvariantViolation<A::Key>> {
+        let actual = self.rest.keys().next().cloned();
+
+        if self.rest_min != actual {
+            return Err(InvariantViolation::StaleRestMin {
+                recorded: self.rest_min,
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn debug_check_rest_min(&self) {
+        debug_assert!(self.rest_min == self.rest.keys().next().cloned(), "stale rest_min");
+    }
+
+    // Tests and the `validate` feature run the full `check` at the end of each public mutation, so a broken invariant
+    // panics in the operation that broke it.
+    fn debug_check(&self) {
+        if cfg!(any(test, feature = "validate")) {
+            if let Err(violation) = self.check() {
+                panic!("{}", violation.map_key(isize::from));
+            }
+        } else {
+            self.debug_check_rest_min();
+        }
+    }
+}
+
+impl<A> TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+    isize: From<A::Key>,
+{
+    pub fn iter(&self) -> impl Iterator<Item = (A::Key, &A::Value)> {
+        self.below
+            .iter()
+            .map(|(key, value)| (*key, value))
+            .chain(
+                (0..self.top.len()).filter_map(move |index| self.top[index].as_ref().map(|(key, value)| (*key, value))),
+            )
+            .chain(self.rest.iter().map(|(key, value)| (*key, value)))
+    }
+
+    /// Iterates like `iter`, along with a guard for checking later that the keys seen are still in the same tiers.
+    pub fn iter_guarded(&self) -> (TierGuard, impl Iterator<Item = (A::Key, &A::Value)>) {
+        (self.tier_guard(), self.iter())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (A::Key, &mut A::Value)> {
+        self.record_all();
+
+        self.below
+            .iter_mut()
+            .map(|(key, value)| (*key, value))
+            .chain(
+                self.top
+                    .iter_mut()
+                    .filter_map(|entry| entry.as_mut().map(|(key, value)| (*key, value))),
+            )
+            .chain(self.rest.make_mut().iter_mut().map(|(key, value)| (*key, value)))
+    }
+
+    pub fn to_vec(&self) -> Vec<(A::Key, A::Value)>
+    where
+        A::Value: Clone,
+    {
+        let mut entries = Vec::with_capacity(self.len());
+        entries.extend(self.iter().map(|(key, value)| (key, value.clone())));
+        entries
+    }
+
+    /// Like `iter`, but starts at the first entry whose key is at least `key`.
+    pub fn iter_from(&self, key: A::Key) -> impl Iterator<Item = (A::Key, &A::Value)> {
+        let start = self.clamped_slot(key, false);
+
+        self.below
+            .range(key..)
+            .map(|(key, value)| (*key, value))
+            .chain(
+                (start..self.top.len())
+                    .filter_map(move |index| self.top[index].as_ref().map(|(key, value)| (*key, value))),
+            )
+            .chain(self.rest.range(key..).map(|(key, value)| (*key, value)))
+    }
+
+    /// Keys in either map, ascending. `other` may use a different array size.
+    pub fn union<'a, B>(&'a self, other: &'a TopMap<B>) -> impl Iterator<Item = A::Key> + 'a
+    where
+        B: Layout<Key = A::Key>,
+    {
+        self.join_with(other, JoinKind::Full).map(|(key, _, _)| key)
+    }
+
+    /// Keys in both maps, ascending.
+    pub fn intersection<'a, B>(&'a self, other: &'a TopMap<B>) -> impl Iterator<Item = A::Key> + 'a
+    where
+        B: Layout<Key = A::Key>,
+    {
+        self.join_with(other, JoinKind::Inner).map(|(key, _, _)| key)
+    }
+
+    /// Keys in this map but not in `other`, ascending.
+    pub fn difference<'a, B>(&'a self, other: &'a TopMap<B>) -> impl Iterator<Item = A::Key> + 'a
+    where
+        B: Layout<Key = A::Key>,
+    {
+        self.join_with(other, JoinKind::Left)
+            .filter(|&(_, _, right)| right.is_none())
+            .map(|(key, _, _)| key)
+    }
+
+    /// Keys in exactly one of the two maps, ascending, each with its value from the side it came from.
+    pub fn symmetric_difference<'a, B>(
+        &'a self,
+        other: &'a TopMap<B>,
+    ) -> impl Iterator<Item = (A::Key, Either<&'a A::Value, &'a B::Value>)> + 'a
+    where
+        B: Layout<Key = A::Key>,
+    {
+        self.join_with(other, JoinKind::Full)
+            .filter_map(|(key, left, right)| match (left, right) {
+                (Some(left), None) => Some((key, Either::Left(left))),
+                (None, Some(right)) => Some((key, Either::Right(right))),
+                _ => None,
+            })
+    }
+
+    /// Whether both maps hold exactly the same keys, whatever their values. Stops at the first mismatch.
+    pub fn eq_keys<B>(&self, other: &TopMap<B>) -> bool
+    where
+        B: Layout<Key = A::Key>,
+    {
+        self.len() == other.len() && self.iter().map(|(key, _)| key).eq(other.iter().map(|(key, _)| key))
+    }
+
+    /// Whether every key in this map is also in `other`. Stops at the first key `other` lacks.
+    pub fn is_subset_keys<B>(&self, other: &TopMap<B>) -> bool
+    where
+        B: Layout<Key = A::Key>,
+    {
+        self.len() <= other.len() && self.difference(other).next().is_none()
+    }
+
+    /// Every key in either map with its value on each side, ascending, from one merge pass over both maps. `other`
+    /// may use a different array size and value type.
+    pub fn join<'a, B>(
+        &'a self,
+        other: &'a TopMap<B>,
+    ) -> impl Iterator<Item = (A::Key, Option<&'a A::Value>, Option<&'a B::Value>)> + 'a
+    where
+        B: Layout<Key = A::Key>,
+    {
+        self.join_with(other, JoinKind::Full)
+    }
+
+    /// Like `join`, but only the keys present in both maps.
+    pub fn inner_join<'a, B>(
+        &'a self,
+        other: &'a TopMap<B>,
+    ) -> impl Iterator<Item = (A::Key, &'a A::Value, &'a B::Value)> + 'a
+    where
+        B: Layout<Key = A::Key>,
+    {
+        self.join_with(other, JoinKind::Inner)
+            .map(|(key, left, right)| (key, left.unwrap(), right.unwrap()))
+    }
+
+    fn join_with<'a, B>(
+        &'a self,
+        other: &'a TopMap<B>,
+        kind: JoinKind,
+    ) -> impl Iterator<Item = (A::Key, Option<&'a A::Value>, Option<&'a B::Value>)> + 'a
+    where
+        B: Layout<Key = A::Key>,
+    {
+        Join {
+            left: self.iter().peekable(),
+            right: other.iter().peekable(),
+            kind,
+        }
+    }
+
+    /// Counts the entries with keys less than `key`. See `count_range` for the cost.
+    pub fn rank(&self, key: A::Key) -> usize {
+        self.count_range(..key)
+    }
+
+    /// Counts the entries with keys in `range`. Takes a scan over the window slots in range plus a walk over the
+    /// matching keys in the maps either side, so it's O(log n + window + answer) rather than O(n).
+    pub fn count_range<R: ops::RangeBounds<A::Key>>(&self, range: R) -> usize {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let window = self.window_range(range);
+
+        let window = if window == (0..self.top.len()) {
+            self.occupied
+        } else {
+            window.filter(|&index| self.top[index].is_some()).count()
+        };
+
+        self.below.range(range).count() + window + self.rest.range(range).count()
+    }
+
+    /// Calls `f` on every entry with a key in `range`, in key order.
+    pub fn for_each_range_mut<R, F>(&mut self, range: R, mut f: F)
+    where
+        R: ops::RangeBounds<A::Key>,
+        F: FnMut(A::Key, &mut A::Value),
+    {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.record_range(range);
+
+        for (&key, value) in self.below.range_mut(range) {
+            f(key, value);
+        }
+
+        for index in self.window_range(range) {
+            if let Some((key, ref mut value)) = self.top[index] {
+                f(key, value);
+            }
+        }
+
+        for (&key, value) in self.rest.make_mut().range_mut(range) {
+            f(key, value);
+        }
+    }
+
+    // Window slots whose keys fall in `range`, clamped to the current window.
+    fn window_range(&self, range: (Bound<A::Key>, Bound<A::Key>)) -> ops::Range<usize> {
+        let start = match range.0 {
+            Bound::Included(key) => self.clamped_slot(key, false),
+            Bound::Excluded(key) => self.clamped_slot(key, true),
+            Bound::Unbounded => 0,
+        };
+
+        let end = match range.1 {
+            Bound::Included(key) => self.clamped_slot(key, true),
+            Bound::Excluded(key) => self.clamped_slot(key, false),
+            Bound::Unbounded => self.top.len(),
+        };
+
+        start..cmp::max(start, end)
+    }
+
+    /// Returns the `n`th smallest entry, counting from zero. Skips whole tiers by their length, so only the tier
+    /// holding the answer is walked.
+    pub fn select_nth(&self, n: usize) -> Option<(A::Key, &A::Value)> {
+        if n < self.below.len() {
+            return self.below.iter().nth(n).map(|(key, value)| (*key, value));
+        }
+
+        let n = n - self.below.len();
+
+        if n < self.occupied {
+            return (0..self.top.len())
+                .filter_map(|index| self.top[index].as_ref())
+                .nth(n)
+                .map(|(key, value)| (*key, value));
+        }
+
+        self.rest.iter().nth(n - self.occupied).map(|(key, value)| (*key, value))
+    }
+
+    /// Returns the entry with the smallest key greater than `key`.
+    pub fn next_after(&self, key: A::Key) -> Option<(A::Key, &A::Value)> {
+        let range = (Bound::Excluded(key), Bound::Unbounded);
+
+        if let Some((key, value)) = self.below.range(range).next() {
+            return Some((*key, value));
+        }
+
+        (self.clamped_slot(key, true)..self.top.len())
+            .filter_map(|index| self.top[index].as_ref())
+            .next()
+            .map(|(key, value)| (*key, value))
+            .or_else(|| self.rest.range(range).next().map(|(key, value)| (*key, value)))
+    }
+
+    /// Returns the entry with the largest key less than `key`.
+    pub fn prev_before(&self, key: A::Key) -> Option<(A::Key, &A::Value)> {
+        if let Some((key, value)) = self.rest.range(..key).next_back() {
+            return Some((*key, value));
+        }
+
+        let end = self.clamped_slot(key, false);
+
+        (0..end)
+            .rev()
+            .filter_map(|index| self.top[index].as_ref())
+            .next()
+            .map(|(key, value)| (*key, value))
+            .or_else(|| self.below.range(..key).next_back().map(|(key, value)| (*key, value)))
+    }
+
+    // Panics for indexing with a missing key, saying where the window sits and which keys are nearest either side.
+    #[cold]
+    #[inline(never)]
+    fn missing(&self, key: A::Key) -> !
+    where
+        A::Key: fmt::Debug,
+    {
+        let nearest = |entry: Option<(A::Key, &A::Value)>| {
+            entry.map_or("none".to_string(), |(key, _)| format!("{:?}", key))
+        };
+
+        let nearest = format!(
+            ", nearest existing: {} and {}",
+            nearest(self.prev_before(key)),
+            nearest(self.next_after(key))
+        );
+
+        missing_key(key, self.window(), self.len(), &nearest)
+    }
+
+    // The window's first key and its number of slots, if it has an anchor.
+    fn window(&self) -> Option<(isize, usize)> {
+        self.anchor.map(|anchor| (isize::from(anchor), self.top.len()))
+    }
+
+    /// Returns the entry with the smallest key whose value satisfies `p`, stopping at the first match.
+    pub fn find_by_value<P>(&self, mut p: P) -> Option<(A::Key, &A::Value)>
+    where
+        P: FnMut(&A::Value) -> bool,
+    {
+        self.iter().find(|&(_, value)| p(value))
+    }
+
+    /// Returns the entry with the largest key whose value satisfies `p`, scanning the rest map, then the window
+    /// from its back, then the below map.
+    pub fn rfind_by_value<P>(&self, mut p: P) -> Option<(A::Key, &A::Value)>
+    where
+        P: FnMut(&A::Value) -> bool,
+    {
+        self.rest
+            .iter()
+            .rev()
+            .map(|(key, value)| (*key, value))
+            .chain(
+                (0..self.top.len())
+                    .rev()
+                    .filter_map(|index| self.top[index].as_ref().map(|(key, value)| (*key, value))),
+            )
+            .chain(self.below.iter().rev().map(|(key, value)| (*key, value)))
+            .find(|&(_, value)| p(value))
+    }
+
+    /// Yields one item per key from the anchor to the end of the window, with `None` for holes. Entries outside the
+    /// window aren't visited.
+    pub fn iter_slots(&self) -> impl Iterator<Item = (A::Key, Option<&A::Value>)>
+    where
+        A::Key: TryFrom<isize>,
+    {
+        (0..self.top.len()).map(move |index| match self.top[index] {
+            Some((key, ref value)) => (key, Some(value)),
+            None => (self.slot_key(index).expect("window runs past the range of the key type"), None),
+        })
+    }
+
+    // The key that belongs in window slot `index`, if the key type has one.
+    fn slot_key(&self, index: usize) -> Option<A::Key>
+    where
+        A::Key: TryFrom<isize>,
+    {
+        let key = isize::from(self.anchor?).checked_add(isize::try_from(index).ok()?)?;
+        A::Key::try_from(key).ok()
+    }
+
+    // The window slot for `key`, or the one after it if `after`, clamped to the window. Keys too far from the anchor
+    // for an `isize` offset clamp to the nearer end.
+    fn clamped_slot(&self, key: A::Key, after: bool) -> usize {
+        match self.anchor {
+            Some(anchor) if key >= anchor => isize::from(key)
+                .checked_sub(isize::from(anchor))
+                .and_then(positive)
+                .map_or(self.top.len(), |index| cmp::min(self.top.len(), index.saturating_add(after as usize))),
+            _ => 0,
+        }
+    }
+
+    /// Returns the anchor and one bit per window slot, set where the slot is occupied. Bit 0 of the first word is the
+    /// anchor itself. `None` if the map has no anchor.
+    pub fn occupancy_bitmap(&self) -> Option<(A::Key, Vec<u64>)> {
+        let anchor = self.anchor?;
+        let mut words = vec![0u64; self.top.len().div_ceil(64)];
+
+        for index in 0..self.top.len() {
+            if self.top[index].is_some() {
+                words[index / 64] |= 1 << (index % 64);
+            }
+        }
+
+        Some((anchor, words))
+    }
+
+    /// Yields the keys of the holes in the window, in order.
+    pub fn gaps(&self) -> impl Iterator<Item = A::Key> + '_
+    where
+        A::Key: TryFrom<isize>,
+    {
+        self.gaps_between(0, self.top.len())
+    }
+
+    /// Like `gaps`, but only reports holes whose keys fall in `range`.
+    pub fn gaps_in(&self, range: ops::Range<A::Key>) -> impl Iterator<Item = A::Key> + '_
+    where
+        A::Key: TryFrom<isize>,
+    {
+        self.gaps_between(self.clamped_slot(range.start, false), self.clamped_slot(range.end, false))
+    }
+
+    fn gaps_between(&self, start: usize, end: usize) -> impl Iterator<Item = A::Key> + '_
+    where
+        A::Key: TryFrom<isize>,
+    {
+        (start..end)
+            .filter(move |&index| self.top[index].is_none())
+            .map(move |index| self.slot_key(index).expect("window runs past the range of the key type"))
+    }
+
+    /// Splits the map into its window entries, in key order, and a tree of every other entry. The anchor isn't kept:
+    /// `from_parts` anchors at the first window entry, so empty slots at the start of the window are lost.
+    pub fn into_parts(self) -> Parts<A> {
+        let TopMap {
+            mut below,
+            mut top,
+            mut rest,
+            ..
+        } = self;
+
+        let top = top
+            .iter_mut()
+            .filter_map(|entry| entry.take())
+            .collect();
+
+        below.append(rest.make_mut());
+        (top, below)
+    }
+
+    pub fn from_parts(top: Vec<(A::Key, A::Value)>, rest: Tree<A>) -> Result<Self, InvalidParts>
+    where
+        A::Storage: Default,
+    {
+        if top.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(InvalidParts::UnsortedTop);
+        }
+
+        let mut map = Self::new();
+        map.rest.set(rest);
+
+        if let (Some(&(first_key, _)), Some(&(last_key, _))) = (top.first(), top.last()) {
+            let span = isize::from(last_key).checked_sub(isize::from(first_key)).and_then(positive);
+
+            if span.is_none_or(|span| span >= map.top.max_size()) {
+                return Err(InvalidParts::TopTooWide);
+            }
+
+            let rest = map.rest.make_mut().split_off(&first_key);
+
+            if rest.keys().next().is_some_and(|&rest_key| rest_key <= last_key) {
+                return Err(InvalidParts::RestOverlapsTop);
+            }
+
+            map.below = mem::replace(map.rest.make_mut(), rest);
+        }
+
+        for (key, value) in top {
+            let anchor = *map.anchor.get_or_insert(key);
+            let index = key_offset(key, anchor).expect("top keys should fit the span checked above");
+            *ensure_index(&mut map.top, index) = Some((key, value));
+            map.occupied += 1;
+        }
+
+        map.rest_min = map.rest.keys().next().cloned();
+        Ok(map)
+    }
+
+    pub fn stats(&self) -> Stats<A::Key> {
+        let top_occupied = self.occupied;
+
+        let (leading_holes, trailing_holes) = if top_occupied == 0 {
+            (self.top.len(), 0)
+        } else {
+            (
+                (0..self.top.len()).take_while(|&index| self.top[index].is_none()).count(),
+                (0..self.top.len()).rev().take_while(|&index| self.top[index].is_none()).count(),
+            )
+        };
+
+        Stats {
+            top_capacity: self.top.max_size(),
+            top_occupied,
+            leading_holes,
+            trailing_holes,
+            rest_len: self.below.len() + self.rest.len(),
+            anchor: self.anchor,
+            span: self.top.len(),
+        }
+    }
+
+    /// Describes the internal layout for bug reports: the anchor, one token per window slot with runs of more than
+    /// three holes collapsed to `_(xN)`, and the first and last few keys of the below and rest maps.
+    pub fn dump(&self) -> String
+    where
+        A::Key: fmt::Debug,
+    {
+        let mut out = String::new();
+        self.dump_to(&mut out).expect("writing to a String cannot fail");
+        out
+    }
+
+    pub fn dump_to<W: fmt::Write>(&self, out: &mut W) -> fmt::Result
+    where
+        A::Key: fmt::Debug,
+    {
+        fn flush_holes(slots: &mut Vec<String>, holes: &mut usize) {
+            if *holes > 3 {
+                slots.push(format!("_(x{})", holes));
+            } else {
+                slots.extend((0..*holes).map(|_| "_".to_owned()));
+            }
+
+            *holes = 0;
+        }
+
+        fn dump_keys<'a, W, K, I>(out: &mut W, name: &str, map_keys: I) -> fmt::Result
+        where
+            W: fmt::Write,
+            K: fmt::Debug + 'a,
+            I: DoubleEndedIterator<Item = &'a K> + ExactSizeIterator + Clone,
+        {
+            const EDGE: usize = 3;
+
+            let len = map_keys.len();
+
+            let keys = if len > 2 * EDGE {
+                let mut last = map_keys.clone().rev().take(EDGE).map(|key| format!("{:?}", key)).collect::<Vec<_>>();
+                last.reverse();
+
+                let first = map_keys.take(EDGE).map(|key| format!("{:?}", key));
+                first.chain(Some("…".to_owned())).chain(last).collect::<Vec<_>>()
+            } else {
+                map_keys.map(|key| format!("{:?}", key)).collect()
+            };
+
+            writeln!(out, "{}: {} [{}]", name, len, keys.join(" "))
+        }
+
+        writeln!(out, "anchor: {:?}", self.anchor)?;
+
+        let mut slots = Vec::new();
+        let mut holes = 0;
+
+        for index in 0..self.top.len() {
+            match self.top[index] {
+                Some((ref key, _)) => {
+                    flush_holes(&mut slots, &mut holes);
+                    slots.push(format!("{:?}", key));
+                }
+
+                None => holes += 1,
+            }
+        }
+
+        flush_holes(&mut slots, &mut holes);
+
+        writeln!(
+            out,
+            "window: {} occupied in {} of {} slots [{}]",
+            self.occupied,
+            self.top.len(),
+            self.top.max_size(),
+            slots.join(" ")
+        )?;
+
+        dump_keys(out, "below", self.below.keys())?;
+        dump_keys(out, "rest", self.rest.keys())
+    }
+
+    pub fn clear(&mut self) {
+        self.record_all();
+        self.below.clear();
+        self.clear_anchor();
+        self.top.clear();
+        self.occupied = 0;
+        self.rest.set(new_tree::<A>(&self.top));
+        self.rest_min = None;
+        self.touched.clear();
+    }
+
+    /// Removes every entry in the window and returns them in key order. The rest map is left alone and the next
+    /// insert picks a new anchor.
+    pub fn drain_top(&mut self) -> impl Iterator<Item = (A::Key, A::Value)> {
+        if self.undo.is_some() {
+            for index in 0..self.top.len() {
+                if let Some((key, _)) = self.top[index] {
+                    self.record(key);
+                }
+            }
+        }
+
+        let mut entries = Vec::with_capacity(self.occupied);
+
+        while let Some(entry) = self.top.pop_front() {
+            entries.extend(entry.take());
+        }
+
+        self.occupied = 0;
+        self.clear_anchor();
+        self.debug_check();
+        entries.into_iter()
+    }
+
+    /// Drops empty slots from both ends of the window; occupied entries stay where they are.
+    pub fn shrink_to_fit(&mut self) {
+        self.trim_front();
+        self.trim_back();
+        self.debug_check();
+    }
+
+    /// Demotes everything past `min_size` slots into the rest map.
+    pub fn shrink_to_min(&mut self) {
+        self.shrink_to(self.top.min_size());
+    }
+
+    pub fn shrink_to(&mut self, target: usize) {
+        let demoted = self.truncate_top(cmp::min(target, self.top.max_size()));
+
+        if let Some(anchor) = self.anchor.filter(|_| demoted > 0) {
+            trace_event!("shrank window", key = anchor, moved = demoted);
+        }
+
+        if self.top.is_empty() {
+            self.clear_anchor();
+        }
+
+        self.debug_check();
+    }
+
+    /// Promotes the smallest rest keys until `n` top slots are occupied, stopping early at the first rest key past
+    /// the end of the window. The counterpart of `shrink_to`; returns how many keys were promoted.
+    pub fn reserve_top(&mut self, n: usize) -> usize {
+        let wanted = cmp::min(n, self.top.max_size()).saturating_sub(self.occupied);
+
+        let anchor = match self.anchor.or(self.rest_min) {
+            Some(anchor) if wanted > 0 => anchor,
+            _ => return 0,
+        };
+
+        let max_size = self.top.max_size();
+
+        let last_index = self
+            .rest
+            .keys()
+            .take(wanted)
+            .map_while(|&key| key_offset(key, anchor).filter(|&index| index < max_size))
+            .last();
+
+        let count = match last_index {
+            Some(index) => self.fill_top(index + 1),
+            None => 0,
+        };
+
+        self.debug_check();
+        count
+    }
+
+    pub fn set_reanchor_policy(&mut self, policy: Option<ReanchorPolicy>) {
+        self.reanchor = policy;
+    }
+
+    pub fn set_far_below_policy(&mut self, policy: FarBelowPolicy) {
+        self.far_below = policy;
+    }
+
+    pub fn set_demotion_policy(&mut self, policy: DemotionPolicy) {
+        self.demotion = policy;
+    }
+
+    /// Caps the map at `limit` entries, evicting the entries the demotion policy picks to make room; any callback from
+    /// `with_eviction_limit` keeps receiving them. The largest entries past the limit are evicted now.
+    pub fn set_capacity_limit(&mut self, limit: usize) {
+        assert!(limit > 0, "capacity limit must be positive");
+
+        self.flush_overflow();
+
+        let on_evict = self.eviction.take().and_then(|mut eviction| eviction.on_evict.take());
+
+        self.eviction = Some(Eviction {
+            limit,
+            on_evict,
+            overflow: None,
+        });
+
+        while self.len() > limit {
+            self.evict_last();
+        }
+
+        self.debug_check();
+    }
+
+    /// Lets the top tier shrink `margin` slots below `min_size` before removing its first key refills it, so entries
+    /// near the boundary aren't promoted and demoted again on every insert/remove pair.
+    pub fn set_refill_margin(&mut self, margin: usize) {
+        self.refill_margin = margin;
+    }
+
+    /// Calls `f` whenever an entry moves into or out of the window, or the window itself moves. Inserts and removes
+    /// that leave every other entry where it was don't call it, and bulk rebuilds such as `append` only report the
+    /// window's anchor.
+    pub fn set_tier_observer<F>(&mut self, f: F)
+    where
+        F: FnMut(TierEvent<A::Key>) + Send + 'static,
+    {
+        self.observer = Some(Arc::new(Mutex::new(f)));
+    }
+
+    /// Takes a `TierGuard` that checks, under the `debug-guards` feature, that no key has changed tier since.
+    pub fn tier_guard(&self) -> TierGuard {
+        TierGuard {
+            generation: self.generation,
+        }
+    }
+
+    fn index(&self, key: A::Key) -> Index {
+        if self.top.max_size() == 0 {
+            return Index::Rest;
+        }
+
+        let index = if let Some(anchor) = self.anchor {
+            match isize::from(key).checked_sub(isize::from(anchor)) {
+                Some(index) => index,
+                None if key < anchor => return Index::AboveTop { distance: usize::MAX },
+                None => return Index::Rest,
+            }
+        } else {
+            return Index::OutsideTop {
+                index: 0,
+                _pd: PhantomData,
+            };
+        };
+
+        if let Some(index) = positive(index) {
+            if index >= self.top.max_size() {
+                Index::Rest
+            } else if index >= self.top.len() {
+                Index::OutsideTop {
+                    index,
+                    _pd: PhantomData,
+                }
+            } else {
+                Index::InsideTop {
+                    index,
+                    _pd: PhantomData,
+                }
+            }
+        } else {
+            Index::AboveTop {
+                distance: (index as usize).wrapping_neg(),
+            }
+        }
+    }
+
+    pub fn entry(&mut self, key: A::Key) -> Entry<A> {
+        self.flush_overflow();
+        self.record(key);
+
+        if self.at_eviction_limit(key) {
+            return Entry::AtLimit { key, map: self };
+        }
+
+        self.tier_entry(key)
+    }
+
+    // The entry for `key` in whichever tier it belongs, leaving any capacity limit to the caller.
+    fn tier_entry(&mut self, key: A::Key) -> Entry<A> {
+        match self.index(key) {
+            Index::AboveTop { .. } if self.below.contains_key(&key) => Entry::BTreeMap(self.below.entry(key)),
+
+            Index::AboveTop { distance }
+                if distance >= self.top.max_size() && self.far_below == FarBelowPolicy::KeepWindow =>
+            {
+                Entry::BTreeMap(self.below.entry(key))
+            }
+
+            Index::AboveTop { distance } => Entry::AboveTop {
+                key,
+                map: self,
+                distance,
+            },
+
+            Index::InsideTop { index, .. } => Entry::Vec(key, &mut self.top[index], &mut self.occupied),
+
+            Index::OutsideTop { index, .. } => {
+                if let Some(rest_min) = self.rest_min {
+                    if key >= rest_min {
+                        return Entry::BTreeMap(self.rest.make_mut().entry(key));
+                    }
+                }
+
+                Entry::OutsideTop { key, map: self, index }
+            }
+
+            Index::Rest if self.rest_min.is_none_or(|rest_min| key < rest_min) => Entry::RestMin { key, map: self },
+            Index::Rest => Entry::BTreeMap(self.rest.make_mut().entry(key)),
+        }
+    }
+
+    pub fn get(&self, key: A::Key) -> Option<&A::Value> {
+        match self.index(key) {
+            Index::AboveTop { distance: _ } => self.below.get(&key),
+            Index::InsideTop { index, .. } => Some(&self.top[index].as_ref()?.1),
+            Index::OutsideTop { .. } | Index::Rest => self.rest.get(&key),
+        }
+    }
+
+    /// Which tier holds `key`, or `None` if the map doesn't contain it.
+    pub fn tier_of(&self, key: A::Key) -> Option<Tier> {
+        match self.index(key) {
+            Index::AboveTop { distance: _ } => self.below.get(&key).map(|_| Tier::Below),
+            Index::InsideTop { index, .. } => self.top[index].as_ref().map(|_| Tier::Top { slot: index }),
+            Index::OutsideTop { .. } | Index::Rest => self.rest.get(&key).map(|_| Tier::Rest),
+        }
+    }
+
+    /// Marks `key` as recently used for `DemotionPolicy::SecondChance`. Returns false, marking nothing, if `key` isn't
+    /// in the window.
+    pub fn touch(&mut self, key: A::Key) -> bool {
+        let in_top = matches!(self.tier_of(key), Some(Tier::Top { .. }));
+
+        if in_top {
+            self.touched.insert(key);
+        }
+
+        in_top
+    }
+
+    pub fn get_mut(&mut self, key: A::Key) -> Option<&mut A::Value> {
+        self.record(key);
+
+        match self.index(key) {
+            Index::AboveTop { distance: _ } => self.below.get_mut(&key),
+            Index::InsideTop { index, .. } => Some(&mut self.top[index].as_mut()?.1),
+            Index::OutsideTop { .. } | Index::Rest => self.rest.make_mut().get_mut(&key),
+        }
+    }
+
+    /// Returns the value for `key` with no tier lookup, bounds check or emptiness check.
+    ///
+    /// # Safety
+    ///
+    /// `key` must fall inside the window's current span and its slot must be occupied: `tier_of(key)` returns
+    /// `Some(Tier::Top { .. })` and nothing has mutated the map since. Debug builds check both.
+    pub unsafe fn get_unchecked(&self, key: A::Key) -> &A::Value {
+        let index = self.unchecked_index(key);
+        let slot = self.top.get_unchecked(index);
+        debug_assert!(matches!(*slot, Some((found, _)) if found == key), "slot {} doesn't hold the key", index);
+        &slot.as_ref().unwrap_unchecked().1
+    }
+
+    /// Mutable counterpart of `get_unchecked`, with the same preconditions.
+    ///
+    /// # Safety
+    ///
+    /// As for `get_unchecked`.
+    pub unsafe fn get_unchecked_mut(&mut self, key: A::Key) -> &mut A::Value {
+        self.record(key);
+
+        let index = self.unchecked_index(key);
+        let slot = self.top.get_unchecked_mut(index);
+        debug_assert!(matches!(*slot, Some((found, _)) if found == key), "slot {} doesn't hold the key", index);
+        &mut slot.as_mut().unwrap_unchecked().1
+    }
+
+    // The window slot for `key`, trusting the caller that the map has an anchor and `key` is inside the window.
+    unsafe fn unchecked_index(&self, key: A::Key) -> usize {
+        debug_assert!(
+            matches!(self.index(key), Index::InsideTop { .. }),
+            "key {:?} is outside the window",
+            isize::from(key)
+        );
+
+        isize::from(key).wrapping_sub(isize::from(self.anchor.unwrap_unchecked())) as usize
+    }
+
+    /// Looks up each of `keys`, returning the results in the same order. The anchor is read once for the batch and
+    /// keys inside the window go straight to their slot; the others are sorted, so runs of nearby keys are found in
+    /// one walk over the ordered map.
+    pub fn get_many<'a>(&'a self, keys: &[A::Key]) -> Vec<Option<&'a A::Value>> {
+        let anchor = self.anchor.map(isize::from);
+        let len = self.top.len();
+        let mut values = Vec::with_capacity(keys.len());
+        let mut misses = Vec::new();
+
+        for (position, &key) in keys.iter().enumerate() {
+            match anchor.and_then(|anchor| isize::from(key).checked_sub(anchor)).and_then(positive) {
+                Some(index) if index < len => values.push(self.top[index].as_ref().map(|entry| &entry.1)),
+                _ => {
+                    values.push(None);
+                    misses.push((key, position));
+                }
+            }
+        }
+
+        misses.sort_unstable_by_key(|&(key, _)| key);
+
+        let below = misses.partition_point(|&(key, _)| self.anchor.is_some_and(|anchor| key < anchor));
+        Self::get_sorted(&self.below, &misses[..below], &mut values);
+        Self::get_sorted(&self.rest, &misses[below..], &mut values);
+        values
+    }
+
+    // Fills in `values` for `keys`, which are sorted. A run of keys close together shares one walk over `tree`; a key
+    // far from both neighbours is a plain lookup, since seeking a range costs more than a `get`.
+    fn get_sorted<'a>(tree: &'a Tree<A>, keys: &[(A::Key, usize)], values: &mut [Option<&'a A::Value>]) {
+        let close = |key: A::Key, next: A::Key| {
+            isize::from(next).checked_sub(isize::from(key)).is_some_and(|gap| gap <= SORTED_WALK_GAP)
+        };
+
+        let mut run = None;
+
+        for (i, &(key, position)) in keys.iter().enumerate() {
+            let next_is_close = keys.get(i + 1).is_some_and(|&(next, _)| close(key, next));
+
+            if run.is_none() && next_is_close {
+                run = Some(tree.range(key..).peekable());
+            }
+
+            values[position] = match run {
+                Some(ref mut entries) => {
+                    while entries.next_if(|&(&found, _)| found < key).is_some() {}
+                    entries.peek().filter(|&&(&found, _)| found == key).map(|&(_, value)| value)
+                }
+                None => tree.get(&key),
+            };
+
+            if !next_is_close {
+                run = None;
+            }
+        }
+    }
+
+    pub fn get_copied(&self, key: A::Key) -> Option<A::Value>
+    where
+        A::Value: Copy,
+    {
+        self.get(key).cloned()
+    }
+
+    /// Returns the value for `key`, or `A::Value::default()` if there is none. Unlike going through `entry`, a missing
+    /// key inserts nothing.
+    pub fn get_or_default(&self, key: A::Key) -> A::Value
+    where
+        A::Value: Copy + Default,
+    {
+        self.get_copied(key).unwrap_or_default()
+    }
+
+    /// Like `get`, but never panics: a missing key, or a window slot holding the wrong key, comes back as an error.
+    pub fn try_get(&self, key: A::Key) -> Result<&A::Value, TopMapError<A::Key>> {
+        let value = match self.index(key) {
+            Index::AboveTop { .. } => self.below.get(&key),
+            Index::InsideTop { index, .. } => match self.top[index] {
+                Some((found, ref value)) if found == key => Some(value),
+                Some((found, _)) => return Err(TopMapError::MisplacedSlot { key, found }),
+                None => None,
+            },
+            Index::OutsideTop { .. } | Index::Rest => self.rest.get(&key),
+        };
+
+        value.ok_or(TopMapError::Missing(key))
+    }
+
+    pub fn try_get_mut(&mut self, key: A::Key) -> Result<&mut A::Value, TopMapError<A::Key>> {
+        self.record(key);
+
+        let value = match self.index(key) {
+            Index::AboveTop { .. } => self.below.get_mut(&key),
+            Index::InsideTop { index, .. } => match self.top[index] {
+                Some((found, ref mut value)) if found == key => Some(value),
+                Some((found, _)) => return Err(TopMapError::MisplacedSlot { key, found }),
+                None => None,
+            },
+            Index::OutsideTop { .. } | Index::Rest => self.rest.make_mut().get_mut(&key),
+        };
+
+        value.ok_or(TopMapError::Missing(key))
+    }
+
+    /// Passes the value for `key` to `f` if there is one, returning whether there was. An absent key leaves the map,
+    /// window included, untouched.
+    pub fn update<F: FnOnce(&mut A::Value)>(&mut self, key: A::Key, f: F) -> bool {
+        match self.get_mut(key) {
+            Some(value) => {
+                f(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Clones the map without copying the rest tier: the two maps share it until either one changes it, at which point
+    /// that map takes its own copy. The window and the below tier are copied straight away. This map and every later
+    /// clone of either map keep sharing the same way. The handle the maps share comes from the global allocator, even
+    /// for a map that keeps its nodes elsewhere.
+    pub fn cow_clone(&mut self) -> Self
+    where
+        A::Value: Clone,
+        A::Storage: Clone,
+        Tree<A>: Clone + Send + Sync + 'static,
+    {
+        if let RestTier::Owned(ref mut rest) = self.rest {
+            let rest = mem::replace(rest, new_tree::<A>(&self.top));
+            self.rest = RestTier::Shared(Arc::new(rest));
+        }
+
+        self.clone()
+    }
+
+    /// Starts logging the prior value of every key changed from here on, so `rollback` can undo the changes at a cost
+    /// proportional to them rather than to the map. Bulk operations log every key they touch. Only one checkpoint can
+    /// be active at a time.
+    pub fn checkpoint(&mut self) -> Checkpoint
+    where
+        A::Value: Clone,
+    {
+        assert!(self.undo.is_none(), "a checkpoint is already active");
+
+        self.undo = Some(UndoLog {
+            clone: A::Value::clone,
+            seen: BTreeSet::new(),
+            entries: Vec::new(),
+        });
+
+        Checkpoint { _private: () }
+    }
+
+    /// Restores every key changed since `checkpoint` to its earlier value. The contents match the checkpoint exactly;
+    /// the tiers are rebuilt through `insert` and `remove`, so the layout may differ.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        let _ = checkpoint;
+        let mut entries = self.undo.take().expect("no active checkpoint").entries;
+
+        // Removals go first. The map then holds only keys it held at the checkpoint, so restoring the rest can't take
+        // it past a capacity limit and evict.
+        entries.sort_by_key(|(_, old_value)| old_value.is_some());
+
+        for (key, old_value) in entries {
+            match old_value {
+                Some(value) => self.insert(key, value),
+                None => self.remove(key),
+            };
+        }
+    }
+
+    /// Keeps the changes made since `checkpoint` and stops logging.
+    pub fn commit(&mut self, checkpoint: Checkpoint) {
+        let _ = checkpoint;
+        assert!(self.undo.take().is_some(), "no active checkpoint");
+    }
+
+    /// Adds `delta` to the value for `key`, starting from `A::Value::default()` if there is none, and returns the
+    /// new total.
+    pub fn add(&mut self, key: A::Key, delta: A::Value) -> A::Value
+    where
+        A::Value: ops::AddAssign + Copy + Default,
+    {
+        let total = {
+            let value = self.entry(key).or_default();
+            *value += delta;
+            *value
+        };
+
+        self.maybe_reanchor();
+        self.debug_check();
+        total
+    }
+
+    /// Like `add`, but returns `None` if the total would overflow, leaving the map as it was.
+    pub fn checked_add(&mut self, key: A::Key, delta: A::Value) -> Option<A::Value>
+    where
+        A::Value: Counter,
+    {
+        // Only an existing value can overflow: an absent key starts from zero
+        let total = {
+            let value = self.entry(key).or_default();
+            *value = value.checked_add(delta)?;
+            *value
+        };
+
+        self.maybe_reanchor();
+        self.debug_check();
+        Some(total)
+    }
+
+    /// Like `add`, but stops at the value type's bounds instead of overflowing.
+    pub fn saturating_add(&mut self, key: A::Key, delta: A::Value) -> A::Value
+    where
+        A::Value: Counter,
+    {
+        let total = {
+            let value = self.entry(key).or_default();
+            *value = value.saturating_add(delta);
+            *value
+        };
+
+        self.maybe_reanchor();
+        self.debug_check();
+        total
+    }
+
+    /// Inserts like `entry(key).insert_entry(value)`, returning an entry for the stored value so it can be updated
+    /// again without a second lookup.
+    pub fn insert_entry(&mut self, key: A::Key, value: A::Value) -> OccupiedEntry<A> {
+        self.entry(key).insert_entry(value)
+    }
+
+    pub fn insert(&mut self, key: A::Key, value: A::Value) -> Option<A::Value> {
+        self.record(key);
+
+        let old_value = match self.anchor.and_then(|anchor| key_offset(key, anchor)) {
+            // Slot already inside the window: write it directly instead of going through `entry`
+            Some(index) if index < self.top.len() && self.eviction.is_none() => {
+                let old_entry = self.top[index].replace((key, value));
+
+                if old_entry.is_none() {
+                    self.occupied += 1;
+                }
+
+                old_entry.map(|(_, value)| value)
+            }
+
+            _ => self.entry(key).insert(value),
+        };
+
+        self.maybe_reanchor();
+        self.debug_check();
+        old_value
+    }
+
+    /// Inserts like `insert` on a map with a capacity limit, but hands back the evicted entry instead of passing it
+    /// to the eviction callback. A new key larger than every other key of a full map is itself returned. Overwriting
+    /// a key that's already present never evicts, and the value it replaces is dropped; use `insert` to get it back.
+    pub fn insert_bounded(&mut self, key: A::Key, value: A::Value) -> Option<(A::Key, A::Value)> {
+        let evicted = if self.at_eviction_limit(key) {
+            match self.take_victim(key) {
+                Some(victim) => Some(victim),
+                None => return Some((key, value)),
+            }
+        } else {
+            None
+        };
+
+        self.insert(key, value);
+        evicted
+    }
+
+    fn at_eviction_limit(&self, key: A::Key) -> bool {
+        match self.eviction {
+            Some(ref eviction) => self.len() >= eviction.limit && self.get(key).is_none(),
+            None => false,
+        }
+    }
+
+    fn last_key(&self) -> Option<A::Key> {
+        if let Some(&key) = self.rest.keys().next_back() {
+            return Some(key);
+        }
+
+        (0..self.top.len())
+            .rev()
+            .filter_map(|index| self.top[index].as_ref().map(|&(key, _)| key))
+            .next()
+            .or_else(|| self.below.keys().next_back().cloned())
+    }
+
+    fn evict_last(&mut self) {
+        if let Some((key, value)) = self.pop_last() {
+            self.evict_entry(key, value);
+        }
+    }
+
+    fn evict_entry(&self, key: A::Key, value: A::Value) {
+        if let Some(ref eviction) = self.eviction {
+            eviction.evict(key, value);
+        }
+    }
+
+    // Passes a new key held back by `insert_at_limit` on to the eviction callback.
+    fn flush_overflow(&mut self) {
+        if let Some(ref mut eviction) = self.eviction {
+            if let Some((key, value)) = eviction.overflow.take() {
+                eviction.evict(key, value);
+            }
+        }
+    }
+
+    // Picks the entry to evict so that `key`, which isn't in the map, fits under the capacity limit, and removes it.
+    // Returns `None` when the demotion policy picks `key` itself, which then shouldn't be inserted.
+    fn take_victim(&mut self, key: A::Key) -> Option<(A::Key, A::Value)> {
+        let victim = match self.demotion {
+            DemotionPolicy::LargestFirst => self.last_key(),
+            DemotionPolicy::SecondChance => self.second_chance_victim(key),
+        };
+
+        match victim {
+            Some(victim) if victim > key => self.remove(victim).map(|value| (victim, value)),
+            _ => None,
+        }
+    }
+
+    // Walks down from the largest key, clearing the marks from `touch` as it passes them, and stops at the first
+    // unmarked key or the first key smaller than the new `key`. Once every key has been spared the largest goes.
+    fn second_chance_victim(&mut self, key: A::Key) -> Option<A::Key> {
+        let largest = self.last_key();
+        let mut spared = Vec::new();
+
+        let victim = {
+            let window = (0..self.top.len()).rev().filter_map(|index| self.top[index].as_ref().map(|&(key, _)| key));
+            let mut keys = self.rest.keys().rev().cloned().chain(window).chain(self.below.keys().rev().cloned());
+
+            keys.find(|&candidate| {
+                let found = candidate < key || !self.touched.contains(&candidate);
+
+                if !found {
+                    spared.push(candidate);
+                }
+
+                found
+            })
+        };
+
+        for key in spared {
+            self.touched.remove(&key);
+        }
+
+        victim.or(largest)
+    }
+
+    // Inserts `key`, which isn't in the map, once the entry the demotion policy picks has been evicted to make room.
+    // When the policy picks `key` itself, the returned value sits in the overflow slot until the next insert.
+    fn insert_at_limit(&mut self, key: A::Key, value: A::Value) -> &mut A::Value {
+        match self.take_victim(key) {
+            Some((victim, victim_value)) => {
+                self.evict_entry(victim, victim_value);
+                self.tier_entry(key).or_insert(value)
+            }
+
+            None => &mut self.eviction.as_mut().unwrap().overflow.insert((key, value)).1,
+        }
+    }
+
+    // Removes the entry with the largest key: the rest map's last, else the window's last, else the below map's.
+    fn pop_last(&mut self) -> Option<(A::Key, A::Value)> {
+        if self.undo.is_some() {
+            if let Some(key) = self.last_key() {
+                self.record(key);
+            }
+        }
+
+        if let Some(key) = self.rest.keys().next_back().cloned() {
+            self.remove_rest(key).map(|value| (key, value))
+        } else {
+            let mut last = None;
+
+            while last.is_none() {
+                last = match self.top.pop_back() {
+                    Some(entry) => entry.take(),
+                    None => break,
+                };
+            }
+
+            self.trim_back();
+
+            if last.is_some() {
+                self.occupied -= 1;
+            } else {
+                if let Some(key) = self.below.keys().next_back().cloned() {
+                    last = self.below.remove(&key).map(|value| (key, value));
+                }
+            }
+
+            last
+        }
+    }
+
+    fn trim_back(&mut self) {
+        while let Some(&None) = self.top.back() {
+            self.top.pop_back();
+        }
+
+        if self.top.is_empty() {
+            self.clear_anchor();
+        }
+    }
+
+    pub fn rebalance(&mut self) -> usize {
+        self.trim_front();
+        let count = self.fill_top(self.top.max_size());
+        self.debug_check();
+        count
+    }
+
+    pub fn compact(&mut self) {
+        self.rebalance();
+        self.trim_back();
+        self.debug_check();
+    }
+
+    /// Moves every entry of `other` into this map, keeping `other`'s value where both have a key. Both maps are
+    /// walked once in key order and the tiers rebuilt around the combined minimum.
+    pub fn append(&mut self, other: &mut Self) {
+        if other.is_empty() {
+            return;
+        }
+
+        let entries = other.drain_sorted();
+        self.merge_sorted(entries);
+    }
+
+    /// Moves every entry of `other` into this map, calling `f(key, mine, theirs)` for keys in both and keeping its
+    /// result. Takes the same single merge pass as `append`.
+    pub fn merge_with<F>(&mut self, mut other: Self, f: F)
+    where
+        F: FnMut(A::Key, A::Value, A::Value) -> A::Value,
+    {
+        if other.is_empty() {
+            return;
+        }
+
+        let entries = other.drain_sorted();
+        self.merge_sorted_with(entries, f);
+    }
+
+    // Merges strictly ascending `entries` into the map, their values winning on shared keys.
+    fn merge_sorted(&mut self, entries: Vec<(A::Key, A::Value)>) {
+        self.merge_sorted_with(entries, |_, _, theirs| theirs);
+    }
+
+    fn merge_sorted_with<F>(&mut self, entries: Vec<(A::Key, A::Value)>, mut combine: F)
+    where
+        F: FnMut(A::Key, A::Value, A::Value) -> A::Value,
+    {
+        for &(key, _) in entries.iter() {
+            self.record(key);
+        }
+
+        let mut left = self.drain_sorted().into_iter().peekable();
+        let mut right = entries.into_iter().peekable();
+        let mut merged = Vec::with_capacity(left.len() + right.len());
+
+        loop {
+            let take_left = match (left.peek(), right.peek()) {
+                (Some(&(left_key, _)), Some(&(right_key, _))) => {
+                    if left_key == right_key {
+                        let (key, mine) = left.next().unwrap();
+                        let (_, theirs) = right.next().unwrap();
+                        merged.push((key, combine(key, mine, theirs)));
+                        continue;
+                    }
+
+                    left_key < right_key
+                }
+
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+
+            merged.push(if take_left { left.next() } else { right.next() }.unwrap());
+        }
+
+        self.extend_ascending(&mut merged.into_iter().peekable());
+
+        if let Some(limit) = self.eviction.as_ref().map(|eviction| eviction.limit) {
+            while self.len() > limit {
+                self.evict_last();
+            }
+        }
+
+        self.maybe_reanchor();
+        self.debug_check();
+    }
+
+    /// Appends entries whose keys ascend and all follow the current last key: free window slots are filled directly
+    /// and the remainder goes to the rest map in one pass. Keys out of order panic in debug builds; release builds
+    /// fall back to `insert` from the first offending key.
+    pub fn insert_sorted_iter<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (A::Key, A::Value)>,
+    {
+        let mut iter = iter.into_iter();
+
+        if self.eviction.is_some() || self.undo.is_some() {
+            for (key, value) in iter {
+                self.insert(key, value);
+            }
+
+            return;
+        }
+
+        let mut last_key = self.last_key();
+        let mut pending = Vec::new();
+        let mut out_of_order = None;
+
+        for (key, value) in &mut iter {
+            if last_key.is_some_and(|last_key| key <= last_key) {
+                out_of_order = Some((key, value));
+                break;
+            }
+
+            last_key = Some(key);
+
+            if !pending.is_empty() || self.rest_min.is_some() {
+                pending.push((key, value));
+                continue;
+            }
+
+            match self.index(key) {
+                Index::InsideTop { index, .. } | Index::OutsideTop { index, .. } => {
+                    if self.anchor.is_none() {
+                        self.move_anchor(key);
+                    }
+
+                    *ensure_index(&mut self.top, index) = Some((key, value));
+                    self.occupied += 1;
+                }
+
+                Index::Rest => pending.push((key, value)),
+
+                // Only reachable before the window holds anything, e.g. below a `with_first_key` anchor
+                Index::AboveTop { .. } => {
+                    self.insert(key, value);
+                }
+            }
+        }
+
+        if let Some(&(first_key, _)) = pending.first() {
+            if self.rest.is_empty() {
+                self.rest_min = Some(first_key);
+                self.rest.set(tree_from::<A, _>(&self.top, pending));
+            } else {
+                self.rest.make_mut().extend(pending);
+            }
+        }
+
+        if let Some((key, value)) = out_of_order {
+            if cfg!(debug_assertions) {
+                panic!("insert_sorted_iter: keys must ascend and follow the map's last key");
+            }
+
+            self.insert(key, value);
+
+            for (key, value) in iter {
+                self.insert(key, value);
+            }
+        }
+
+        self.maybe_reanchor();
+        self.debug_check();
+    }
+
+    /// Inserts `values[i]` at key `start_key + i`, overwriting existing entries.
+    pub fn extend_from_dense_slice(&mut self, start_key: A::Key, values: &[A::Value])
+    where
+        A::Key: TryFrom<isize>,
+        A::Value: Copy,
+    {
+        let start = isize::from(start_key);
+
+        let entries = values.iter().enumerate().map(|(offset, &value)| {
+            let key = start
+                .checked_add(offset as isize)
+                .and_then(|key| A::Key::try_from(key).ok())
+                .expect("dense slice runs past the range of the key type");
+
+            (key, value)
+        });
+
+        if self.last_key().is_none_or(|last_key| last_key < start_key) {
+            self.insert_sorted_iter(entries);
+        } else {
+            for (key, value) in entries {
+                self.insert(key, value);
+            }
+        }
+    }
+
+    fn drain_sorted(&mut self) -> Vec<(A::Key, A::Value)> {
+        let mut entries = Vec::with_capacity(self.len());
+        self.drain_sorted_with(|key, value| entries.push((key, value)));
+        entries
+    }
+
+    // Empties the map, passing each entry to `f` in key order.
+    fn drain_sorted_with<F: FnMut(A::Key, A::Value)>(&mut self, mut f: F) {
+        self.record_all();
+
+        for (key, value) in mem::replace(&mut self.below, new_tree::<A>(&self.top)) {
+            f(key, value);
+        }
+
+        for index in 0..self.top.len() {
+            if let Some((key, value)) = self.top[index].take() {
+                f(key, value);
+            }
+        }
+
+        for (key, value) in self.rest.take(new_tree::<A>(&self.top)) {
+            f(key, value);
+        }
+
+        self.top.clear();
+        self.clear_anchor();
+        self.occupied = 0;
+        self.rest_min = None;
+    }
+
+    /// Splits the map in one pass into the entries `f` accepts and the ones it rejects. Each half is loaded in bulk
+    /// around its own smallest key and keeps this map's settings.
+    pub fn partition<F>(mut self, mut f: F) -> (Self, Self)
+    where
+        F: FnMut(A::Key, &A::Value) -> bool,
+        A::Storage: Default,
+    {
+        let mut accepted = Vec::new();
+        let mut rejected = Vec::new();
+
+        self.drain_sorted_with(|key, value| {
+            if f(key, &value) {
+                accepted.push((key, value));
+            } else {
+                rejected.push((key, value));
+            }
+        });
+
+        let mut other = TopMap::new();
+        other.eviction = self.eviction.clone();
+        other.reanchor = self.reanchor;
+        other.far_below = self.far_below;
+        other.demotion = self.demotion;
+        other.refill_margin = self.refill_margin;
+
+        self.extend_ascending(&mut accepted.into_iter().peekable());
+        other.extend_ascending(&mut rejected.into_iter().peekable());
+        (self, other)
+    }
+
+    /// Converts every value with `f` in key order, keeping the anchor, the window's holes and the below and rest key
+    /// sets exactly as they are. `B` is the same layout with a new value type, e.g. `[Option<(isize, W)>; 128]`.
+    /// The eviction callback is not carried over, since it takes the old value type.
+    pub fn map_values<B, F>(self, f: F) -> TopMap<B>
+    where
+        B: Layout<Key = A::Key>,
+        B::Storage: Default,
+        F: FnMut(A::Key, A::Value) -> B::Value,
+    {
+        self.map_values_to(B::Storage::default(), f)
+    }
+
+    /// Like `map_values`, for layouts such as `DynArray` that are sized at runtime.
+    pub fn map_values_with_array<B, F>(self, array: B, f: F) -> TopMap<B>
+    where
+        B: Layout<Key = A::Key>,
+        F: FnMut(A::Key, A::Value) -> B::Value,
+    {
+        self.map_values_to(array.into_storage(), f)
+    }
+
+    fn map_values_to<B, F>(mut self, mut top: B::Storage, mut f: F) -> TopMap<B>
+    where
+        B: Layout<Key = A::Key>,
+        F: FnMut(A::Key, A::Value) -> B::Value,
+    {
+        assert_eq!(self.top.max_size(), top.max_size(), "map_values needs a window of the same size");
+
+        let below = mem::replace(&mut self.below, new_tree::<A>(&self.top))
+            .into_iter()
+            .map(|(key, value)| (key, f(key, value)));
+
+        let below = tree_from::<B, _>(&top, below);
+
+        top.clear();
+
+        for index in 0..self.top.len() {
+            *top.push_back() = self.top[index].take().map(|(key, value)| (key, f(key, value)));
+        }
+
+        let rest = self
+            .rest
+            .take(new_tree::<A>(&self.top))
+            .into_iter()
+            .map(|(key, value)| (key, f(key, value)));
+
+        let rest = tree_from::<B, _>(&top, rest);
+
+        TopMap {
+            anchor: self.anchor,
+            below,
+            top,
+            occupied: self.occupied,
+            rest: RestTier::Owned(rest),
+            rest_min: self.rest_min,
+            metrics: self.metrics,
+            eviction: None,
+            reanchor: self.reanchor,
+            far_below: self.far_below,
+            demotion: self.demotion,
+            touched: mem::take(&mut self.touched),
+            refill_margin: self.refill_margin,
+            undo: None,
+            observer: None,
+            generation: Generation::default(),
+        }
+    }
+
+    fn trim_front(&mut self) {
+        while let Some(None) = self.top.front() {
+            self.top.pop_front();
+        }
+
+        if let Some(&Some((min_key, _))) = self.top.front() {
+            self.move_anchor(min_key);
+        } else {
+            self.clear_anchor();
+        }
+    }
+
+    /// Moves `key` from the rest map into its top slot, along with any rest entries that sort before it.
+    pub fn promote(&mut self, key: A::Key) -> bool {
+        if !self.rest.contains_key(&key) {
+            return false;
+        }
+
+        let min_key = if let Some(anchor) = self.anchor {
+            anchor
+        } else {
+            self.rest_min.unwrap()
+        };
+
+        match key_offset(key, min_key) {
+            Some(index) if index < self.top.max_size() => {
+                self.fill_top(index + 1);
+                self.debug_check();
+                true
+            }
+
+            _ => false,
+        }
+    }
+
+    /// Like `get_mut`, but first promotes `key` as `promote` would if it is in the rest map and fits in the window, so
+    /// later lookups go straight to its slot.
+    pub fn get_promoting(&mut self, key: A::Key) -> Option<&mut A::Value> {
+        self.promote(key);
+        self.get_mut(key)
+    }
+
+    /// Moves `key` from its top slot into the rest map, along with any top entries that sort after it.
+    pub fn demote(&mut self, key: A::Key) -> bool {
+        match self.index(key) {
+            Index::InsideTop { index, .. } if self.top[index].is_some() => {
+                self.truncate_top(index);
+
+                if self.top.is_empty() {
+                    self.clear_anchor();
+                }
+
+                self.debug_check();
+                true
+            }
+
+            _ => false,
+        }
+    }
+
+    fn fill_top(&mut self, size: usize) -> usize {
+        assert!(size <= self.top.max_size());
+
+        let mut count = 0;
+
+        let min_top_key = if let Some(anchor) = self.anchor {
+            anchor
+        } else if self.top.max_size() == 0 {
+            return count;
+        } else if let Some(rest_key) = self.rest_min {
+            let rest_value = self.remove_rest(rest_key).unwrap();
+            *self.top.push_back() = Some((rest_key, rest_value));
+            self.move_anchor(rest_key);
+            self.notify(TierEvent::Promoted { key: rest_key });
+            count += 1;
+            rest_key
+        } else {
+            return count;
+        };
+
+        // A key too far from the anchor for an `isize` offset is past the end of any window.
+        let index_of = |key: A::Key| {
+            assert!(
+                key >= min_top_key,
+                "everything in the rest map should have an index higher than everything in the top vec"
+            );
+
+            key_offset(key, min_top_key).unwrap_or(usize::MAX)
+        };
+
+        while let Some(key) = self.rest_min {
+            if index_of(key) >= size {
+                break;
+            }
+
+            // Splitting the rest map has a fixed cost of a few tree walks, so the first few keys come out one at a
+            // time and anything beyond that in a single split. Finding where to split only walks the keys being
+            // promoted: distinct integer keys below the bound all fit in the window.
+            if count == REFILL_SPLIT_AFTER {
+                let bound = self.rest.keys().cloned().find(|&key| index_of(key) >= size);
+
+                let promoted = match bound {
+                    Some(bound) => {
+                        let upper = self.rest.make_mut().split_off(&bound);
+                        mem::replace(self.rest.make_mut(), upper)
+                    }
+
+                    None => self.rest.take(new_tree::<A>(&self.top)),
+                };
+
+                self.rest_min = bound;
+
+                for (key, value) in promoted {
+                    *ensure_index(&mut self.top, index_of(key)) = Some((key, value));
+                    self.notify(TierEvent::Promoted { key });
+                    count += 1;
+                }
+
+                break;
+            }
+
+            let value = self.remove_rest(key).unwrap();
+            *ensure_index(&mut self.top, index_of(key)) = Some((key, value));
+            self.notify(TierEvent::Promoted { key });
+            count += 1;
+        }
+
+        self.occupied += count;
+        self.metrics.promotions += count as u64;
+        self.debug_check_rest_min();
+        count
+    }
+
+    pub fn remove(&mut self, key: A::Key) -> Option<A::Value> {
+        self.record(key);
+
+        if !self.touched.is_empty() {
+            self.touched.remove(&key);
+        }
+
+        let value = match self.index(key) {
+            Index::AboveTop { distance: _ } => self.below.remove(&key),
+
+            Index::InsideTop { index: 0, .. } => {
+                let (_, value) = self.top[0].take()?;
+                self.occupied -= 1;
+                self.trim_front();
+                let promoted = self.refill();
+
+                if promoted > 0 {
+                    trace_event!("refilled window", key = key, moved = promoted);
+                }
+
+                Some(value)
+            }
+
+            Index::InsideTop { index, .. } => {
+                let (_, value) = self.top[index].take()?;
+                self.occupied -= 1;
+                let promoted = self.refill_sparse_top();
+
+                if promoted > 0 {
+                    trace_event!("refilled window", key = key, moved = promoted);
+                }
+
+                Some(value)
+            }
+
+            Index::OutsideTop { .. } | Index::Rest => self.remove_rest(key),
+        };
+
+        self.maybe_reanchor();
+        self.debug_check();
+        value
+    }
+
+    /// Removes every entry with a key below `key`, returning how many went. The window prefix is cleared in one pass,
+    /// the rest prefix split off, and the window refilled once.
+    pub fn advance_to(&mut self, key: A::Key) -> usize {
+        let mut count = 0;
+        self.advance_to_with(key, |_, _| count += 1);
+        count
+    }
+
+    /// Like `advance_to`, but returns the removed entries in key order.
+    pub fn advance_to_drain(&mut self, key: A::Key) -> impl Iterator<Item = (A::Key, A::Value)> {
+        let mut entries = Vec::new();
+        self.advance_to_with(key, |key, value| entries.push((key, value)));
+        entries.into_iter()
+    }
+
+    fn advance_to_with<F: FnMut(A::Key, A::Value)>(&mut self, key: A::Key, mut f: F) {
+        self.record_range((Bound::Unbounded, Bound::Excluded(key)));
+
+        if self.below.keys().next().is_some_and(|&below_min| below_min < key) {
+            let upper = self.below.split_off(&key);
+
+            for (key, value) in mem::replace(&mut self.below, upper) {
+                f(key, value);
+            }
+        }
+
+        let window = match self.anchor {
+            Some(anchor) if anchor < key => {
+                key_offset(key, anchor).map_or(self.top.len(), |distance| cmp::min(self.top.len(), distance))
+            }
+
+            _ => 0,
+        };
+
+        if window > 0 {
+            for _ in 0..window {
+                if let Some((key, value)) = self.top.pop_front().and_then(|slot| slot.take()) {
+                    self.occupied -= 1;
+                    f(key, value);
+                }
+            }
+
+            self.trim_front();
+        }
+
+        if self.rest_min.is_some_and(|rest_min| rest_min < key) {
+            let upper = self.rest.make_mut().split_off(&key);
+            self.rest_min = upper.keys().next().cloned();
+
+            for (key, value) in mem::replace(self.rest.make_mut(), upper) {
+                f(key, value);
+            }
+        }
+
+        if window > 0 || self.top.is_empty() {
+            self.refill();
+        }
+
+        self.maybe_reanchor();
+        self.debug_check();
+    }
+
+    /// Removes every entry whose key falls in `range`, returning how many went. Window slots in range are cleared in
+    /// place and the other tiers carved up with `split_off`.
+    pub fn remove_range<R: ops::RangeBounds<A::Key>>(&mut self, range: R) -> usize {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.record_range(range);
+        let mut count = remove_map_range::<A>(&mut self.below, range, &self.top)
+            + remove_map_range::<A>(self.rest.make_mut(), range, &self.top);
+        self.rest_min = self.rest.keys().next().cloned();
+
+        let window = self.window_range(range);
+
+        for index in window.clone() {
+            if self.top[index].take().is_some() {
+                self.occupied -= 1;
+                count += 1;
+            }
+        }
+
+        if window.start == 0 && !window.is_empty() {
+            self.trim_front();
+            self.refill();
+        } else if !window.is_empty() {
+            self.refill_sparse_top();
+        }
+
+        self.maybe_reanchor();
+        self.debug_check();
+        count
+    }
+
+    /// Applies a batch of upserts (`Some`) and deletes (`None`) with the same result as applying them one at a time.
+    /// Keys that would move the window down are held back and the window refilled once, after the whole batch.
+    /// Maps with an eviction limit apply the batch one operation at a time, since what gets evicted depends on order.
+    pub fn apply_delta<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (A::Key, Option<A::Value>)>,
+    {
+        if self.eviction.is_some() {
+            for (key, value) in iter {
+                match value {
+                    Some(value) => self.insert(key, value),
+                    None => self.remove(key),
+                };
+            }
+
+            return;
+        }
+
+        let mut above = new_tree::<A>(&self.top);
+        let mut front_cleared = false;
+
+        for (key, value) in iter {
+            match value {
+                Some(value) => match self.entry(key) {
+                    Entry::AboveTop { .. } => {
+                        above.insert(key, value);
+                    }
+
+                    entry => {
+                        entry.insert(value);
+                    }
+                },
+
+                None if above.remove(&key).is_some() => {}
+
+                None => {
+                    front_cleared |= self.remove_in_place(key).1;
+                }
+            }
+        }
+
+        // The smallest held-back key moves the window down once; the others then land inside it.
+        for (key, value) in above {
+            self.entry(key).insert(value);
+        }
+
+        if front_cleared {
+            self.trim_front();
+            self.refill();
+        } else {
+            self.refill_sparse_top();
+        }
+
+        self.maybe_reanchor();
+        self.debug_check();
+    }
+
+    /// Removes each of `keys`, returning the old values in the same order, with the same result as calling `remove` for
+    /// each in turn; a key repeated in the batch gives `None` the second time. The window is refilled once, at the end.
+    pub fn remove_many(&mut self, keys: &[A::Key]) -> Vec<Option<A::Value>> {
+        let mut values = Vec::with_capacity(keys.len());
+        let mut front_cleared = false;
+
+        for &key in keys {
+            let (value, front) = self.remove_in_place(key);
+            values.push(value);
+            front_cleared |= front;
+        }
+
+        if front_cleared {
+            self.trim_front();
+            self.refill();
+        } else if !keys.is_empty() {
+            self.refill_sparse_top();
+        }
+
+        self.maybe_reanchor();
+        self.debug_check();
+        values
+    }
+
+    /// Keeps only the entries for which `f` returns true, visiting every entry once in key order. `f` may also change
+    /// the values it keeps. The window is refilled once, at the end.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(A::Key, &mut A::Value) -> bool,
+    {
+        self.record_all();
+
+        // A key that goes has to lose its touch mark wherever it was, or reinserting it would find it already touched.
+        let touched = &mut self.touched;
+
+        let mut keep = |key: A::Key, value: &mut A::Value| {
+            let keep = f(key, value);
+
+            if !keep {
+                touched.remove(&key);
+            }
+
+            keep
+        };
+
+        self.below.retain(|&key, value| keep(key, value));
+
+        let mut front_cleared = false;
+        let mut top_cleared = false;
+
+        for index in 0..self.top.len() {
+            let kept = match self.top[index] {
+                Some((key, ref mut value)) => keep(key, value),
+                None => continue,
+            };
+
+            if !kept {
+                self.top[index] = None;
+                self.occupied -= 1;
+                front_cleared |= index == 0;
+                top_cleared = true;
+            }
+        }
+
+        self.rest.make_mut().retain(|&key, value| keep(key, value));
+        self.rest_min = self.rest.keys().next().cloned();
+
+        if front_cleared {
+            self.trim_front();
+            self.refill();
+        } else if top_cleared {
+            self.refill_sparse_top();
+        }
+
+        self.maybe_reanchor();
+        self.debug_check();
+    }
+
+    // Removes `key` without trimming or refilling the window, for batches that fix the window up once at the end.
+    // Also returns whether the key was in the front slot.
+    fn remove_in_place(&mut self, key: A::Key) -> (Option<A::Value>, bool) {
+        self.record(key);
+
+        if !self.touched.is_empty() {
+            self.touched.remove(&key);
+        }
+
+        match self.index(key) {
+            Index::AboveTop { .. } => (self.below.remove(&key), false),
+
+            Index::InsideTop { index, .. } => match self.top[index].take() {
+                Some((_, value)) => {
+                    self.occupied -= 1;
+                    (Some(value), index == 0)
+                }
+
+                None => (None, false),
+            },
+
+            Index::OutsideTop { .. } | Index::Rest => (self.remove_rest(key), false),
+        }
+    }
+
+    // After entries leave the front of the window: refills it to `min_size` if it got that short, then tops up the
+    // live entries. Returns how many keys were promoted.
+    fn refill(&mut self) -> usize {
+        let mut promoted = 0;
+
+        if self.top.is_empty() || self.top.len() + self.refill_margin <= self.top.min_size() {
+            self.metrics.refills += 1;
+            promoted += self.fill_top(self.top.min_size());
+        }
+
+        promoted + self.refill_sparse_top()
+    }
+
+    // Holes don't count towards `min_size`: once the live entries drop below it, promote rest keys that fit in the
+    // window until they reach it again.
+    fn refill_sparse_top(&mut self) -> usize {
+        let min_size = self.top.min_size();
+
+        let anchor = match self.anchor {
+            Some(anchor) if self.occupied + self.refill_margin < min_size => anchor,
+            _ => return 0,
+        };
+
+        let mut count = 0;
+
+        while self.occupied < min_size {
+            let key = match self.rest_min {
+                Some(key) => key,
+                None => break,
+            };
+
+            assert!(
+                key >= anchor,
+                "everything in the rest map should have an index higher than everything in the top vec"
+            );
+
+            // A key too far from the anchor for an `isize` offset is past the end of the window too.
+            let index = match key_offset(key, anchor) {
+                Some(index) if index < self.top.max_size() => index,
+                _ => break,
+            };
+
+            let value = self.remove_rest(key).unwrap();
+            *ensure_index(&mut self.top, index) = Some((key, value));
+            self.notify(TierEvent::Promoted { key });
+            self.occupied += 1;
+            count += 1;
+        }
+
+        if count > 0 {
+            self.metrics.refills += 1;
+            self.metrics.promotions += count as u64;
+        }
+
+        count
+    }
+
+    // Moves the window to start exactly at `key`, which must not be greater than any key in the map, then promotes
+    // whatever now fits.
+    fn anchor_at(&mut self, key: A::Key) {
+        match self.anchor {
+            Some(anchor) if anchor < key => {
+                let distance = key_offset(key, anchor).unwrap_or(usize::MAX);
+
+                for _ in 0..cmp::min(distance, self.top.len()) {
+                    let slot = self.top.pop_front();
+                    debug_assert!(slot.is_none_or(|slot| slot.is_none()), "anchor_at past an entry");
+                }
+            }
+
+            Some(anchor) if anchor > key => {
+                self.insert_above_top(key, key_offset(anchor, key).unwrap_or(usize::MAX));
+            }
+
+            _ => {}
+        }
+
+        self.move_anchor(key);
+        self.fill_top(self.top.max_size());
+    }
+
+    fn maybe_reanchor(&mut self) {
+        let policy = match self.reanchor {
+            Some(policy) => policy,
+            None => return,
+        };
+
+        let first_rest_key = match self.rest_min {
+            Some(key) => key,
+            None => return,
+        };
+
+        if self.occupied >= policy.max_occupied {
+            return;
+        }
+
+        let hot_rest = self
+            .rest
+            .keys()
+            .take(policy.min_hot_rest)
+            .take_while(|&&key| key_offset(key, first_rest_key).is_some_and(|index| index < self.top.max_size()))
+            .count();
+
+        if hot_rest < policy.min_hot_rest {
+            return;
+        }
+
+        let mut count = 0;
+
+        for index in 0..self.top.len() {
+            if let Some((key, value)) = self.top[index].take() {
+                self.notify(TierEvent::Demoted { key });
+                self.below.insert(key, value);
+                count += 1;
+            }
+        }
+
+        self.top.clear();
+        self.occupied = 0;
+        self.move_anchor(first_rest_key);
+        self.metrics.demotions += count;
+        self.metrics.reanchors += 1;
+        let promoted = self.fill_top(self.top.max_size());
+        trace_event!("reanchored window", key = first_rest_key, moved = count as usize + promoted);
+    }
+}
+
+impl<A> TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+    isize: From<A::Key>,
+{
+    /// Loads an empty map from the strictly ascending prefix of `iter`, filling top slots directly and building the
+    /// rest map in bulk. Stops at the first key that is out of order, leaving it in `iter`.
+    fn extend_ascending<I>(&mut self, iter: &mut Peekable<I>)
+    where
+        I: Iterator<Item = (A::Key, A::Value)>,
+    {
+        let mut rest = Vec::new();
+        let mut last_key = None;
+
+        while let Some(key) = iter.peek().map(|&(key, _)| key) {
+            if last_key.is_some_and(|last_key| key <= last_key) {
+                break;
+            }
+
+            match self.index(key) {
+                Index::OutsideTop { index, .. } if rest.is_empty() => {
+                    let (key, value) = iter.next().unwrap();
+
+                    if self.anchor.is_none() {
+                        self.move_anchor(key);
+                    }
+
+                    *ensure_index(&mut self.top, index) = Some((key, value));
+                    self.occupied += 1;
+                }
+
+                Index::OutsideTop { .. } | Index::Rest => rest.push(iter.next().unwrap()),
+                Index::AboveTop { .. } | Index::InsideTop { .. } => break,
+            }
+
+            last_key = Some(key);
+        }
+
+        if let Some(&(rest_min, _)) = rest.first() {
+            self.rest_min = Some(rest_min);
+            self.rest.set(tree_from::<A, _>(&self.top, rest));
+        }
+
+        self.debug_check();
+    }
+}
+
+/// Parallel iteration over the three tiers chained together. The window is split by slot ranges; the ordered maps are
+/// bridged from their own iterators, so the items don't come out in key order.
+#[cfg(feature = "rayon")]
+impl<A> TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + Send + Sync,
+    isize: From<A::Key>,
+{
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = (A::Key, &A::Value)>
+    where
+        A::Value: Sync,
+    {
+        let (front, back) = self.top.as_slices();
+
+        let top = front
+            .par_iter()
+            .chain(back.par_iter())
+            .filter_map(|slot| slot.as_ref().map(|&(key, ref value)| (key, value)));
+
+        self.below
+            .iter()
+            .map(|(key, value)| (*key, value))
+            .par_bridge()
+            .chain(top)
+            .chain(self.rest.iter().map(|(key, value)| (*key, value)).par_bridge())
+    }
+
+    pub fn par_iter_mut(&mut self) -> impl ParallelIterator<Item = (A::Key, &mut A::Value)>
+    where
+        A::Value: Send,
+    {
+        let (front, back) = self.top.as_mut_slices();
+
+        let top = front
+            .par_iter_mut()
+            .chain(back.par_iter_mut())
+            .filter_map(|slot| slot.as_mut().map(|&mut (key, ref mut value)| (key, value)));
+
+        self.below
+            .iter_mut()
+            .map(|(key, value)| (*key, value))
+            .par_bridge()
+            .chain(top)
+            .chain(self.rest.make_mut().iter_mut().map(|(key, value)| (*key, value)).par_bridge())
+    }
+
+    pub fn par_values_mut(&mut self) -> impl ParallelIterator<Item = &mut A::Value>
+    where
+        A::Value: Send,
+    {
+        self.par_iter_mut().map(|(_, value)| value)
+    }
+}
+
+/// Gathers the entries in parallel, sorts them in parallel and merges them in one pass. A key produced more than once
+/// keeps its last value in the iterator's order, as `extend` would.
+#[cfg(feature = "rayon")]
+impl<A> ParallelExtend<(A::Key, A::Value)> for TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + Send,
+    A::Value: Send,
+    isize: From<A::Key>,
+{
+    fn par_extend<I>(&mut self, par_iter: I)
+    where
+        I: IntoParallelIterator<Item = (A::Key, A::Value)>,
+    {
+        let mut entries = par_iter.into_par_iter().collect::<Vec<_>>();
+        entries.par_sort_by_key(|&(key, _)| key);
+
+        let mut sorted: Vec<(A::Key, A::Value)> = Vec::with_capacity(entries.len());
+
+        for entry in entries {
+            if sorted.last().is_some_and(|&(last_key, _)| last_key == entry.0) {
+                sorted.pop();
+            }
+
+            sorted.push(entry);
+        }
+
+        self.merge_sorted(sorted);
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<A> FromParallelIterator<(A::Key, A::Value)> for TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + Send,
+    A::Value: Send,
+    A::Storage: Default,
+    isize: From<A::Key>,
+{
+    fn from_par_iter<I>(par_iter: I) -> Self
+    where
+        I: IntoParallelIterator<Item = (A::Key, A::Value)>,
+    {
+        let mut map = TopMap::new();
+        map.par_extend(par_iter);
+        map
+    }
+}
+
+// Panics for indexing with a missing key. `window` is as from `TopMap::window`, and `nearest` says which keys are
+// nearest either side when the caller could still look them up.
+#[cold]
+#[inline(never)]
+fn missing_key<K: fmt::Debug>(key: K, window: Option<(isize, usize)>, len: usize, nearest: &str) -> ! {
+    let window = match window {
+        Some((start, slots)) => match isize::try_from(slots).ok().and_then(|slots| start.checked_add(slots)) {
+            Some(end) => format!("window {}..{}", start, end),
+            None => format!("window {}.. ({} slots)", start, slots),
+        },
+
+        None => "no window".to_string(),
+    };
+
+    panic!("no item with key {:?} ({}, len {}{})", key, window, len, nearest)
+}
+
+impl<A> ops::Index<A::Key> for TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + fmt::Debug,
+    isize: From<A::Key>,
+{
+    type Output = A::Value;
+
+    fn index(&self, index: A::Key) -> &A::Value {
+        match self.get(index) {
+            Some(value) => value,
+            None => self.missing(index),
+        }
+    }
+}
+
+impl<A> ops::IndexMut<A::Key> for TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + fmt::Debug,
+    isize: From<A::Key>,
+{
+    fn index_mut(&mut self, index: A::Key) -> &mut A::Value {
+        // `get_mut` keeps the map borrowed on its `None` path too, so the message makes do with what's captured here.
+        let (window, len) = (self.window(), self.len());
+
+        match self.get_mut(index) {
+            Some(value) => value,
+            None => missing_key(index, window, len, ""),
+        }
+    }
+}
+
+impl<A> Extend<(A::Key, A::Value)> for TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+    isize: From<A::Key>,
+{
+    fn extend<T: IntoIterator<Item = (A::Key, A::Value)>>(&mut self, iter: T) {
+        let mut iter = iter.into_iter().peekable();
+
+        // Hooks see every insert in input order, so only reorder keys when nothing is watching.
+        if self.eviction.is_some() || self.reanchor.is_some() || self.undo.is_some() || self.observer.is_some() {
+            for (key, value) in iter {
+                self.insert(key, value);
+            }
+
+            return;
+        }
+
+        // Load each descending run smallest key first, rather than dragging the window down one key at a time and
+        // demoting its far end on every insert. Keys in a run are distinct, so the reordering can't change a value.
+        let mut run = Vec::new();
+
+        let mut ordered = iter::from_fn(|| {
+            if run.is_empty() {
+                run.push(iter.next()?);
+
+                while let Some(key) = iter.peek().map(|&(key, _)| key) {
+                    if key >= run[run.len() - 1].0 {
+                        break;
+                    }
+
+                    run.push(iter.next().unwrap());
+                }
+            }
+
+            run.pop()
+        })
+        .peekable();
+
+        if self.is_empty() {
+            self.extend_ascending(&mut ordered);
+        }
+
+        for (key, value) in ordered {
+            self.insert(key, value);
+        }
+    }
+}
+
+/// Quickcheck needs this to show a generated `TopMap` that fails a property.
+impl<A> fmt::Debug for TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + fmt::Debug,
+    A::Value: fmt::Debug,
+    isize: From<A::Key>,
+{
+    /// `{:?}` prints a flat map. `{:#?}` shows the tiers: the anchor, each window slot by its offset from the anchor
+    /// with `_` for holes, then the rest map and its length.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !f.alternate() {
+            return f.debug_map().entries(self.iter()).finish();
+        }
+
+        struct Slot<'a, V: 'a>(Option<&'a V>);
+
+        impl<'a, V: fmt::Debug> fmt::Debug for Slot<'a, V> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self.0 {
+                    Some(value) => value.fmt(f),
+                    None => f.write_str("_"),
+                }
+            }
+        }
+
+        struct Window<'a, A: Layout + 'a>(&'a A::Storage);
+
+        impl<'a, A: Layout> fmt::Debug for Window<'a, A>
+        where
+            A::Value: fmt::Debug,
+        {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                let slots = (0..self.0.len()).map(|index| (index, Slot(self.0[index].as_ref().map(|entry| &entry.1))));
+                f.debug_map().entries(slots).finish()
+            }
+        }
+
+        let mut s = f.debug_struct("TopMap");
+        s.field("anchor", &self.anchor);
+
+        if !self.below.is_empty() {
+            s.field("below", &self.below);
+        }
+
+        s.field("window", &Window::<A>(&self.top))
+            .field("rest_len", &self.rest.len())
+            .field("rest", &*self.rest)
+            .finish()
+    }
+}
+
+impl<A> FromIterator<(A::Key, A::Value)> for TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+    A::Storage: Default,
+    isize: From<A::Key>,
+{
+    fn from_iter<T: IntoIterator<Item = (A::Key, A::Value)>>(iter: T) -> Self {
+        let mut m = TopMap::new();
+        m.extend(iter);
+        m
+    }
+}
+
+/// Sorts the keys once and bulk-loads the window and the rest map.
+impl<A> From<HashMap<A::Key, A::Value>> for TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + Hash,
+    A::Storage: Default,
+    isize: From<A::Key>,
+{
+    fn from(map: HashMap<A::Key, A::Value>) -> Self {
+        let mut entries = map.into_iter().collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|&(key, _)| key);
+
+        let mut m = TopMap::new();
+        m.extend_ascending(&mut entries.into_iter().peekable());
+        m
+    }
+}
+
+impl<A> From<TopMap<A>> for HashMap<A::Key, A::Value>
+where
+    A: Layout,
+    A::Key: Copy + Ord + Hash,
+    isize: From<A::Key>,
+{
+    fn from(mut map: TopMap<A>) -> Self {
+        let mut hash_map = HashMap::with_capacity(map.len());
+        hash_map.extend(map.drain_sorted());
+        hash_map
+    }
+}
+
+/// Serializes as a map in ascending key order.
+#[cfg(feature = "serde")]
+impl<A> Serialize for TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + Serialize,
+    A::Value: Serialize,
+    isize: From<A::Key>,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+
+        for (key, value) in self.iter() {
+            map.serialize_entry(&key, value)?;
+        }
+
+        map.end()
+    }
+}
+
+/// Accepts keys in any order, the last value winning for a repeated key; sorted input takes the same bulk-load path
+/// as `collect`.
+#[cfg(feature = "serde")]
+impl<'de, A> Deserialize<'de> for TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + Deserialize<'de>,
+    A::Value: Deserialize<'de>,
+    A::Storage: Default,
+    isize: From<A::Key>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = deserializer.deserialize_map(EntriesVisitor(PhantomData))?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+// Reads either a map or a sequence of pairs into a `Vec` of entries, in input order.
+#[cfg(feature = "serde")]
+struct EntriesVisitor<K, V>(PhantomData<(K, V)>);
+
+#[cfg(feature = "serde")]
+impl<'de, K, V> Visitor<'de> for EntriesVisitor<K, V>
+where
+    K: Deserialize<'de>,
+    V: Deserialize<'de>,
+{
+    type Value = Vec<(K, V)>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map or a sequence of key-value pairs")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<Self::Value, M::Error> {
+        let mut entries = Vec::with_capacity(cmp::min(access.size_hint().unwrap_or(0), 4096));
+
+        while let Some(entry) = access.next_entry()? {
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+
+    fn visit_seq<S: SeqAccess<'de>>(self, mut access: S) -> Result<Self::Value, S::Error> {
+        let mut entries = Vec::with_capacity(cmp::min(access.size_hint().unwrap_or(0), 4096));
+
+        while let Some(entry) = access.next_element()? {
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(feature = "serde")]
+fn collect_unique<A, E>(entries: Vec<(A::Key, A::Value)>) -> Result<TopMap<A>, E>
+where
+    A: Layout,
+    A::Key: Copy + Ord + fmt::Debug,
+    A::Storage: Default,
+    isize: From<A::Key>,
+    E: de::Error,
+{
+    if entries.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+        return Ok(entries.into_iter().collect());
+    }
+
+    let mut map = TopMap::new();
+
+    for (key, value) in entries {
+        if map.insert(key, value).is_some() {
+            return Err(E::custom(format_args!("duplicate key {:?}", key)));
+        }
+    }
+
+    Ok(map)
+}
+
+/// `#[serde(with = "top_map::serde_map")]` helpers for the map representation that `TopMap` uses by default.
+/// Formats such as JSON write integer keys as strings.
+#[cfg(feature = "serde")]
+pub mod serde_map {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    use super::{collect_unique, EntriesVisitor, Layout, TopMap};
+
+    pub fn serialize<A, S>(map: &TopMap<A>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        A: Layout,
+        A::Key: Copy + Ord + Serialize,
+        A::Value: Serialize,
+        isize: From<A::Key>,
+        S: Serializer,
+    {
+        map.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, A, D>(deserializer: D) -> Result<TopMap<A>, D::Error>
+    where
+        A: Layout,
+        A::Key: Copy + Ord + Deserialize<'de>,
+        A::Value: Deserialize<'de>,
+        A::Storage: Default,
+        isize: From<A::Key>,
+        D: Deserializer<'de>,
+    {
+        TopMap::deserialize(deserializer)
+    }
+
+    /// Like the parent module, but rejects input that repeats a key.
+    pub mod unique {
+        pub use super::serialize;
+
+        use super::*;
+
+        pub fn deserialize<'de, A, D>(deserializer: D) -> Result<TopMap<A>, D::Error>
+        where
+            A: Layout,
+            A::Key: Copy + Ord + fmt::Debug + Deserialize<'de>,
+            A::Value: Deserialize<'de>,
+            A::Storage: Default,
+            isize: From<A::Key>,
+            D: Deserializer<'de>,
+        {
+            collect_unique(deserializer.deserialize_map(EntriesVisitor(PhantomData))?)
+        }
+    }
+}
+
+/// `#[serde(with = "top_map::serde_seq")]` helpers that write a `TopMap` as a sequence of `(key, value)` pairs in
+/// ascending key order, for formats whose maps can't take the key type.
+#[cfg(feature = "serde")]
+pub mod serde_seq {
+    use serde::ser::SerializeSeq;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    use super::{collect_unique, EntriesVisitor, Layout, TopMap};
+
+    pub fn serialize<A, S>(map: &TopMap<A>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        A: Layout,
+        A::Key: Copy + Ord + Serialize,
+        A::Value: Serialize,
+        isize: From<A::Key>,
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(map.len()))?;
+
+        for entry in map.iter() {
+            seq.serialize_element(&entry)?;
+        }
+
+        seq.end()
+    }
+
+    /// Accepts pairs in any order, the last value winning for a repeated key.
+    pub fn deserialize<'de, A, D>(deserializer: D) -> Result<TopMap<A>, D::Error>
+    where
+        A: Layout,
+        A::Key: Copy + Ord + Deserialize<'de>,
+        A::Value: Deserialize<'de>,
+        A::Storage: Default,
+        isize: From<A::Key>,
+        D: Deserializer<'de>,
+    {
+        let entries = deserializer.deserialize_seq(EntriesVisitor(PhantomData))?;
+        Ok(entries.into_iter().collect())
+    }
+
+    /// Like the parent module, but rejects input that repeats a key.
+    pub mod unique {
+        pub use super::serialize;
+
+        use super::*;
+
+        pub fn deserialize<'de, A, D>(deserializer: D) -> Result<TopMap<A>, D::Error>
+        where
+            A: Layout,
+            A::Key: Copy + Ord + fmt::Debug + Deserialize<'de>,
+            A::Value: Deserialize<'de>,
+            A::Storage: Default,
+            isize: From<A::Key>,
+            D: Deserializer<'de>,
+        {
+            collect_unique(deserializer.deserialize_seq(EntriesVisitor(PhantomData))?)
+        }
+    }
+}
+
+/// Builds maps through a mix of inserts and removals, so they come with interior holes and a realistic split between
+/// the tiers: mostly keys packed near a random anchor, with the occasional far outlier. Keys that don't fit the key
+/// type are skipped. Shrinking removes entries.
+#[cfg(feature = "quickcheck")]
+impl<A> Arbitrary for TopMap<A>
+where
+    A: Layout + 'static,
+    A::Key: Arbitrary + Copy + Ord + TryFrom<isize>,
+    A::Value: Arbitrary,
+    A::Storage: Clone + Default,
+    Self: Send,
+    isize: From<A::Key>,
+{
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let mut map = Self::new();
+        let mut keys = Vec::new();
+        let anchor = isize::from(A::Key::arbitrary(g));
+
+        for _ in 0..usize::arbitrary(g) * 4 {
+            let key = match usize::arbitrary(g) % 8 {
+                0 | 1 if !keys.is_empty() => {
+                    let key = keys[usize::arbitrary(g) % keys.len()];
+                    map.remove(key);
+                    continue;
+                }
+
+                2 => anchor.saturating_add(isize::arbitrary(g).saturating_mul(1000)),
+                _ => anchor.saturating_add(usize::arbitrary(g) as isize),
+            };
+
+            if let Ok(key) = A::Key::try_from(key) {
+                map.insert(key, A::Value::arbitrary(g));
+                keys.push(key);
+            }
+        }
+
+        map
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new(self.to_vec().shrink().map(|entries| entries.into_iter().collect()))
+    }
+}
+
+// Caps the entries decoded from one fuzz input, so a long input can't turn into an arbitrarily slow build.
+#[cfg(feature = "arbitrary")]
+const MAX_ARBITRARY_ENTRIES: usize = 4096;
+
+/// Decodes `(key, value)` pairs until the input runs out or says stop, inserting each in turn.
+#[cfg(feature = "arbitrary")]
+impl<'a, A> arbitrary::Arbitrary<'a> for TopMap<A>
+where
+    A: Layout,
+    A::Key: arbitrary::Arbitrary<'a> + Copy + Ord,
+    A::Value: arbitrary::Arbitrary<'a>,
+    A::Storage: Default,
+    isize: From<A::Key>,
+{
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let mut map = Self::new();
+
+        for entry in u.arbitrary_iter::<(A::Key, A::Value)>()?.take(MAX_ARBITRARY_ENTRIES) {
+            let (key, value) = entry?;
+            map.insert(key, value);
+        }
+
+        Ok(map)
+    }
+
+    fn size_hint(_depth: usize) -> (usize, Option<usize>) {
+        (0, None)
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a, A> arbitrary::Arbitrary<'a> for TopSet<A>
+where
+    A: Layout<Value = ()>,
+    A::Key: arbitrary::Arbitrary<'a> + Copy + Ord,
+    A::Storage: Default,
+    isize: From<A::Key>,
+{
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(TopSet { map: <TopMap<A> as arbitrary::Arbitrary>::arbitrary(u)? })
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        <TopMap<A> as arbitrary::Arbitrary>::size_hint(depth)
+    }
+}
+
+/// Proptest strategies for maps and for the operation sequences that build them.
+#[cfg(feature = "proptest")]
+pub mod proptest {
+    use proptest_crate::collection::{self, SizeRange};
+    use proptest_crate::strategy::Strategy;
+    use std::fmt;
+
+    use super::{Layout, TopMap};
+
+    #[derive(Clone, Debug)]
+    pub enum Action<K, V> {
+        Insert { key: K, value: V },
+        Remove { key: K },
+        Get { key: K },
+    }
+
+    impl<K, V> Action<K, V> {
+        /// Applies an insert or remove to `map`; `Get` leaves it alone.
+        pub fn apply<A>(self, map: &mut TopMap<A>)
+        where
+            A: Layout<Key = K, Value = V>,
+            K: Copy + Ord,
+            isize: From<K>,
+        {
+            match self {
+                Action::Insert { key, value } => {
+                    map.insert(key, value);
+                }
+
+                Action::Remove { key } => {
+                    map.remove(key);
+                }
+
+                Action::Get { .. } => {}
+            }
+        }
+    }
+
+    /// Half inserts, a quarter each removes and gets.
+    pub fn action<K, V>(key: K, value: V) -> impl Strategy<Value = Action<K::Value, V::Value>>
+    where
+        K: Strategy,
+        V: Strategy,
+    {
+        (0..4u8, key, value).prop_map(|(kind, key, value)| match kind {
+            0 => Action::Remove { key },
+            1 => Action::Get { key },
+            _ => Action::Insert { key, value },
+        })
+    }
+
+    pub fn actions<K, V, S>(key: K, value: V, size: S) -> impl Strategy<Value = Vec<Action<K::Value, V::Value>>>
+    where
+        K: Strategy,
+        V: Strategy,
+        S: Into<SizeRange>,
+    {
+        collection::vec(action(key, value), size)
+    }
+
+    /// Builds maps by applying `size` actions to an empty map, so removes leave holes in the window and keys that
+    /// land outside it fill the rest tier. Shrinks by dropping and simplifying actions.
+    pub fn top_map<A, K, V, S>(key: K, value: V, size: S) -> impl Strategy<Value = TopMap<A>>
+    where
+        A: Layout,
+        A::Key: Copy + Ord + fmt::Debug,
+        A::Value: fmt::Debug,
+        A::Storage: Default,
+        isize: From<A::Key>,
+        K: Strategy<Value = A::Key>,
+        V: Strategy<Value = A::Value>,
+        S: Into<SizeRange>,
+    {
+        actions(key, value, size).prop_map(|actions| {
+            let mut map = TopMap::new();
+
+            for action in actions {
+                action.apply(&mut map);
+            }
+
+            map
+        })
+    }
+}
+
+#[cfg(feature = "rkyv")]
+pub use archive::ArchivedTopMap;
+
+#[cfg(feature = "rkyv")]
+mod archive {
+    use rkyv::collections::util::Entry;
+    use rkyv::ser::{ScratchSpace, Serializer};
+    use rkyv::vec::{ArchivedVec, VecResolver};
+    use rkyv::{Archive, Archived, Deserialize, Fallible, Serialize};
+    use std::borrow::Borrow;
+    use std::cmp::Ordering;
+
+    use super::{Layout, TopMap};
+
+    /// Archived form of a `TopMap`: its entries as one flat slice sorted by key, searched in place.
+    #[repr(transparent)]
+    pub struct ArchivedTopMap<K, V> {
+        entries: ArchivedVec<Entry<K, V>>,
+    }
+
+    impl<K, V> ArchivedTopMap<K, V> {
+        pub fn len(&self) -> usize {
+            self.entries.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.entries.is_empty()
+        }
+
+        pub fn get<Q>(&self, key: &Q) -> Option<&V>
+        where
+            K: Borrow<Q>,
+            Q: Ord + ?Sized,
+        {
+            self.entries
+                .binary_search_by(|entry| entry.key.borrow().cmp(key))
+                .ok()
+                .map(|index| &self.entries[index].value)
+        }
+
+        pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where
+            K: Borrow<Q>,
+            Q: Ord + ?Sized,
+        {
+            self.get(key).is_some()
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+            self.entries.iter().map(|entry| (&entry.key, &entry.value))
+        }
+
+        /// Like `iter`, but starts at the first entry whose key is at least `key`.
+        pub fn iter_from<Q>(&self, key: &Q) -> impl Iterator<Item = (&K, &V)>
+        where
+            K: Borrow<Q>,
+            Q: Ord + ?Sized,
+        {
+            let start = self
+                .entries
+                .binary_search_by(|entry| match entry.key.borrow().cmp(key) {
+                    Ordering::Equal => Ordering::Greater,
+                    ordering => ordering,
+                })
+                .unwrap_err();
+
+            self.entries[start..].iter().map(|entry| (&entry.key, &entry.value))
+        }
+    }
+
+    impl<A> Archive for TopMap<A>
+    where
+        A: Layout,
+        A::Key: Archive,
+        A::Value: Archive,
+    {
+        type Archived = ArchivedTopMap<Archived<A::Key>, Archived<A::Value>>;
+        type Resolver = VecResolver;
+
+        unsafe fn resolve(&self, pos: usize, resolver: VecResolver, out: *mut Self::Archived) {
+            let out = out.cast::<ArchivedVec<Entry<Archived<A::Key>, Archived<A::Value>>>>();
+            ArchivedVec::resolve_from_len(self.len(), pos, resolver, out);
+        }
+    }
+
+    impl<A, S> Serialize<S> for TopMap<A>
+    where
+        A: Layout,
+        A::Key: Copy + Ord + Serialize<S>,
+        A::Value: Serialize<S>,
+        isize: From<A::Key>,
+        S: ScratchSpace + Serializer + ?Sized,
+    {
+        fn serialize(&self, serializer: &mut S) -> Result<VecResolver, S::Error> {
+            let entries = self.iter().collect::<Vec<_>>();
+
+            ArchivedVec::serialize_from_iter::<Entry<&A::Key, &A::Value>, _, _, _>(
+                entries.iter().map(|&(ref key, value)| Entry { key, value }),
+                serializer,
+            )
+        }
+    }
+
+    /// Rebuilds a live map through the sorted bulk-load path used by `collect`.
+    impl<A, D> Deserialize<TopMap<A>, D> for ArchivedTopMap<Archived<A::Key>, Archived<A::Value>>
+    where
+        A: Layout,
+        A::Key: Archive + Copy + Ord,
+        A::Value: Archive,
+        A::Storage: Default,
+        Archived<A::Key>: Deserialize<A::Key, D>,
+        Archived<A::Value>: Deserialize<A::Value, D>,
+        isize: From<A::Key>,
+        D: Fallible + ?Sized,
+    {
+        fn deserialize(&self, deserializer: &mut D) -> Result<TopMap<A>, D::Error> {
+            let mut entries = Vec::with_capacity(self.len());
+
+            for entry in self.entries.iter() {
+                entries.push((entry.key.deserialize(deserializer)?, entry.value.deserialize(deserializer)?));
+            }
+
+            Ok(entries.into_iter().collect())
+        }
+    }
+}
+
+pub struct TopSet<A>
+where
+    A: Layout<Value = ()>,
+{
+    map: TopMap<A>,
+}
+
+impl<A> TopSet<A>
+where
+    A: Layout<Value = ()>,
+    A::Key: Ord,
+    A::Storage: Default,
+{
+    pub fn new() -> Self {
+        TopSet { map: TopMap::new() }
+    }
+}
+
+impl<A> Default for TopSet<A>
+where
+    A: Layout<Value = ()>,
+    A::Key: Ord,
+    A::Storage: Default,
+{
+    fn default() -> Self {
+        TopSet::new()
+    }
+}
+
+impl<A> TopSet<A>
+where
+    A: Layout<Value = ()>,
+    A::Key: Copy + Ord,
+    isize: From<A::Key>,
+{
+    pub fn with_array(array: A) -> Self {
+        TopSet {
+            map: TopMap::with_array(array),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = A::Key> + '_ {
+        self.map.iter().map(|(key, _)| key)
+    }
+
+    pub fn contains(&self, key: A::Key) -> bool {
+        self.map.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: A::Key) -> bool {
+        self.map.insert(key, ()).is_none()
+    }
+
+    pub fn remove(&mut self, key: A::Key) -> bool {
+        self.map.remove(key).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+impl<A> fmt::Debug for TopSet<A>
+where
+    A: Layout<Value = ()>,
+    A::Key: Copy + Ord + fmt::Debug,
+    isize: From<A::Key>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl<A> Extend<A::Key> for TopSet<A>
+where
+    A: Layout<Value = ()>,
+    A::Key: Copy + Ord,
+    isize: From<A::Key>,
+{
+    fn extend<T: IntoIterator<Item = A::Key>>(&mut self, iter: T) {
+        self.map.extend(iter.into_iter().map(|key| (key, ())));
+    }
+}
+
+impl<A> FromIterator<A::Key> for TopSet<A>
+where
+    A: Layout<Value = ()>,
+    A::Key: Copy + Ord,
+    A::Storage: Default,
+    isize: From<A::Key>,
+{
+    fn from_iter<T: IntoIterator<Item = A::Key>>(iter: T) -> Self {
+        let mut s = TopSet::new();
+        s.extend(iter);
+        s
+    }
+}
+
+/// A map whose `IndexMut` inserts `A::Value::default()` for a missing key, so `counts[key] += 1` builds a histogram.
+/// Reading a missing key through `Index` still panics; everything else goes through `Deref` to the inner map.
+pub struct DefaultTopMap<A>
+where
+    A: Layout,
+{
+    map: TopMap<A>,
+}
+
+impl<A> DefaultTopMap<A>
+where
+    A: Layout,
+    A::Key: Ord,
+    A::Storage: Default,
+{
+    pub fn new() -> Self {
+        DefaultTopMap { map: TopMap::new() }
+    }
+}
+
+impl<A> Default for DefaultTopMap<A>
+where
+    A: Layout,
+    A::Key: Ord,
+    A::Storage: Default,
+{
+    fn default() -> Self {
+        DefaultTopMap::new()
+    }
+}
+
+impl<A> DefaultTopMap<A>
+where
+    A: Layout,
+{
+    pub fn into_inner(self) -> TopMap<A> {
+        self.map
+    }
+}
+
+impl<A> From<TopMap<A>> for DefaultTopMap<A>
+where
+    A: Layout,
+{
+    fn from(map: TopMap<A>) -> Self {
+        DefaultTopMap { map }
+    }
+}
+
+impl<A> ops::Deref for DefaultTopMap<A>
+where
+    A: Layout,
+{
+    type Target = TopMap<A>;
+
+    fn deref(&self) -> &TopMap<A> {
+        &self.map
+    }
+}
+
+impl<A> ops::DerefMut for DefaultTopMap<A>
+where
+    A: Layout,
+{
+    fn deref_mut(&mut self) -> &mut TopMap<A> {
+        &mut self.map
+    }
+}
+
+impl<A> ops::Index<A::Key> for DefaultTopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + fmt::Debug,
+    isize: From<A::Key>,
+{
+    type Output = A::Value;
+
+    fn index(&self, index: A::Key) -> &A::Value {
+        &self.map[index]
+    }
+}
+
+impl<A> ops::IndexMut<A::Key> for DefaultTopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + fmt::Debug,
+    A::Value: Default,
+    isize: From<A::Key>,
+{
+    fn index_mut(&mut self, index: A::Key) -> &mut A::Value {
+        self.map.entry(index).or_default()
+    }
+}
+
+/// An immutable, read-optimised copy of a `TopMap` made by `freeze`: window lookups index a boxed slice and the
+/// entries either side are binary searched in sorted slices.
+#[derive(Clone)]
+pub struct FrozenTopMap<K, V> {
+    below: Box<[(K, V)]>,
+    anchor: Option<K>,
+    window: Box<[Option<(K, V)>]>,
+    rest: Box<[(K, V)]>,
+    len: usize,
+}
+
+// Narrows ascending `entries` to the ones with keys in `range`.
+fn slice_range<K: Ord, V>(entries: &[(K, V)], range: (Bound<K>, Bound<K>)) -> &[(K, V)] {
+    let start = match range.0 {
+        Bound::Included(ref start) => entries.partition_point(|entry| entry.0 < *start),
+        Bound::Excluded(ref start) => entries.partition_point(|entry| entry.0 <= *start),
+        Bound::Unbounded => 0,
+    };
+
+    let end = match range.1 {
+        Bound::Included(ref end) => entries.partition_point(|entry| entry.0 <= *end),
+        Bound::Excluded(ref end) => entries.partition_point(|entry| entry.0 < *end),
+        Bound::Unbounded => entries.len(),
+    };
+
+    &entries[start..cmp::max(start, end)]
+}
+
+impl<A> TopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+    isize: From<A::Key>,
+{
+    /// Gives up mutability for cheaper lookups; `unfreeze` converts back.
+    pub fn freeze(mut self) -> FrozenTopMap<A::Key, A::Value> {
+        let len = self.len();
+
+        let window = (0..self.top.len())
+            .map(|index| self.top[index].take())
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+
+        FrozenTopMap {
+            below: mem::replace(&mut self.below, new_tree::<A>(&self.top))
+                .into_iter()
+                .collect::<Vec<_>>()
+                .into_boxed_slice(),
+            anchor: self.anchor,
+            window,
+            rest: self.rest.take(new_tree::<A>(&self.top)).into_iter().collect::<Vec<_>>().into_boxed_slice(),
+            len,
+        }
+    }
+}
+
+impl<K, V> FrozenTopMap<K, V>
+where
+    K: Copy + Ord,
+    isize: From<K>,
+{
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: K) -> Option<&V> {
+        let anchor = match self.anchor {
+            Some(anchor) if key >= anchor => anchor,
+            Some(_) => return Self::find(&self.below, key),
+            None => return Self::find(&self.rest, key),
+        };
+
+        match isize::from(key).checked_sub(isize::from(anchor)).and_then(positive) {
+            Some(index) if index < self.window.len() => self.window[index].as_ref().map(|entry| &entry.1),
+            _ => Self::find(&self.rest, key),
+        }
+    }
+
+    fn find(entries: &[(K, V)], key: K) -> Option<&V> {
+        entries.binary_search_by(|entry| entry.0.cmp(&key)).ok().map(|index| &entries[index].1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, &V)> {
+        self.below
+            .iter()
+            .chain(self.window.iter().filter_map(Option::as_ref))
+            .chain(self.rest.iter())
+            .map(|&(key, ref value)| (key, value))
+    }
+
+    /// Entries with keys in `range`, ascending. The slices either side of the window are narrowed by binary search,
+    /// and the window itself by offset from the anchor.
+    pub fn range<R: ops::RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = (K, &V)> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+
+        slice_range(&self.below, range)
+            .iter()
+            .chain(self.window_range(range).iter().filter_map(Option::as_ref))
+            .chain(slice_range(&self.rest, range).iter())
+            .map(|&(key, ref value)| (key, value))
+    }
+
+    // The window slots for keys in `range`.
+    fn window_range(&self, range: (Bound<K>, Bound<K>)) -> &[Option<(K, V)>] {
+        let anchor = match self.anchor {
+            Some(anchor) => anchor,
+            None => return &[],
+        };
+
+        // The slot of `key`, or the one after it, clamped to the window
+        let len = self.window.len();
+
+        let slot = |key: K, after: bool| {
+            if key < anchor {
+                return 0;
+            }
+
+            isize::from(key)
+                .checked_sub(isize::from(anchor))
+                .and_then(positive)
+                .map_or(len, |index| cmp::min(len, index.saturating_add(after as usize)))
+        };
+
+        let start = match range.0 {
+            Bound::Included(start) => slot(start, false),
+            Bound::Excluded(start) => slot(start, true),
+            Bound::Unbounded => 0,
+        };
+
+        let end = match range.1 {
+            Bound::Included(end) => slot(end, true),
+            Bound::Excluded(end) => slot(end, false),
+            Bound::Unbounded => len,
+        };
+
+        &self.window[start..cmp::max(start, end)]
+    }
+
+    /// Rebuilds a live map, loading the entries in bulk around the smallest key.
+    pub fn unfreeze<A>(self) -> TopMap<A>
+    where
+        A: Layout<Key = K, Value = V>,
+        A::Storage: Default,
+    {
+        let mut map = TopMap::new();
+
+        let entries = self
+            .below
+            .into_vec()
+            .into_iter()
+            .chain(self.window.into_vec().into_iter().flatten())
+            .chain(self.rest.into_vec());
+
+        map.extend_ascending(&mut entries.peekable());
+        map
+    }
+}
+
+impl<K, V, A> From<FrozenTopMap<K, V>> for TopMap<A>
+where
+    A: Layout<Key = K, Value = V>,
+    A::Storage: Default,
+    K: Copy + Ord,
+    isize: From<K>,
+{
+    fn from(map: FrozenTopMap<K, V>) -> Self {
+        map.unfreeze()
+    }
+}
+
+impl<K, V> ops::Index<K> for FrozenTopMap<K, V>
+where
+    K: Copy + Ord + fmt::Debug,
+    isize: From<K>,
+{
+    type Output = V;
+
+    fn index(&self, index: K) -> &V {
+        self.get(index)
+            .unwrap_or_else(|| panic!("no item with key {:?}", index))
+    }
+}
+
+impl<K, V> fmt::Debug for FrozenTopMap<K, V>
+where
+    K: Copy + Ord + fmt::Debug,
+    V: fmt::Debug,
+    isize: From<K>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// A timer wheel: values are scheduled a number of ticks ahead of the current tick, and `tick` fires the current tick
+/// and advances by exactly one. The window always starts at the current tick, so it covers the next `max_size` ticks
+/// and later timers wait in the rest map until the wheel reaches them.
+pub struct TickMap<A>
+where
+    A: Layout,
+{
+    map: TopMap<A>,
+    current: A::Key,
+}
+
+impl<A> TickMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+    A::Storage: Default,
+    isize: From<A::Key>,
+{
+    pub fn new(start: A::Key) -> Self {
+        TickMap {
+            map: TopMap::with_first_key(start),
+            current: start,
+        }
+    }
+}
+
+impl<A> TickMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord + TryFrom<isize>,
+    isize: From<A::Key>,
+{
+    pub fn current_tick(&self) -> A::Key {
+        self.current
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The underlying map, keyed by absolute tick.
+    pub fn map(&self) -> &TopMap<A> {
+        &self.map
+    }
+
+    fn tick_at(&self, delta: usize) -> A::Key {
+        isize::try_from(delta)
+            .ok()
+            .and_then(|delta| isize::from(self.current).checked_add(delta))
+            .and_then(|tick| A::Key::try_from(tick).ok())
+            .expect("tick runs past the range of the key type")
+    }
+
+    /// Schedules `value` to fire `delta` ticks from now, replacing and returning any value already due then.
+    pub fn schedule(&mut self, delta: usize, value: A::Value) -> Option<A::Value> {
+        let tick = self.tick_at(delta);
+        self.map.insert(tick, value)
+    }
+
+    pub fn entry(&mut self, delta: usize) -> Entry<A> {
+        let tick = self.tick_at(delta);
+        self.map.entry(tick)
+    }
+
+    /// Removes the value due at `tick`, an absolute tick number.
+    pub fn cancel(&mut self, tick: A::Key) -> Option<A::Value> {
+        let value = self.map.remove(tick);
+        self.map.anchor_at(self.current);
+        value
+    }
+
+    /// Fires the current tick, returning its value if one was scheduled, and moves on to the next.
+    pub fn tick(&mut self) -> Option<A::Value> {
+        let value = self.map.remove(self.current);
+        self.current = self.tick_at(1);
+        self.map.anchor_at(self.current);
+        self.map.debug_check();
+        value
+    }
+}
+
+/// A keyspace split across independently locked `TopMap`s. Keys are routed in window-sized runs, so neighbouring keys
+/// share a shard and each shard's window stays dense. `len` and `snapshot` lock one shard at a time: under concurrent
+/// writes they are best effort, good for monitoring but not a consistent cut.
+pub struct ShardedTopMap<A>
+where
+    A: Layout,
+{
+    shards: Vec<Mutex<TopMap<A>>>,
+    window: isize,
+}
+
+impl<A> ShardedTopMap<A>
+where
+    A: Layout,
+    A::Key: Ord,
+    A::Storage: Default,
+{
+    pub fn new(shards: usize) -> Self {
+        assert!(shards > 0, "shard count must be positive");
+
+        Self {
+            shards: (0..shards).map(|_| Mutex::new(TopMap::new())).collect(),
+            window: cmp::max(A::Storage::default().max_size(), 1) as isize,
+        }
+    }
+}
+
+impl<A> ShardedTopMap<A>
+where
+    A: Layout,
+    A::Key: Copy + Ord,
+    isize: From<A::Key>,
+{
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard(&self, key: A::Key) -> MutexGuard<TopMap<A>> {
+        let run = isize::from(key).div_euclid(self.window);
+        let index = run.rem_euclid(self.shards.len() as isize) as usize;
+        self.shards[index].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn insert(&self, key: A::Key, value: A::Value) -> Option<A::Value> {
+        self.shard(key).insert(key, value)
+    }
+
+    pub fn get_cloned(&self, key: A::Key) -> Option<A::Value>
+    where
+        A::Value: Clone,
+    {
+        self.shard(key).get(key).cloned()
+    }
+
+    pub fn contains_key(&self, key: A::Key) -> bool {
+        self.shard(key).get(key).is_some()
+    }
+
+    pub fn remove(&self, key: A::Key) -> Option<A::Value> {
+        self.shard(key).remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies every shard's entries out and merges them into one ascending iterator.
+    pub fn snapshot(&self) -> impl Iterator<Item = (A::Key, A::Value)>
+    where
+        A::Value: Clone,
+    {
+        let mut entries = Vec::new();
+
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+            entries.extend(shard.iter().map(|(key, value)| (key, value.clone())));
+        }
+
+        entries.sort_by_key(|&(key, _)| key);
+        entries.into_iter()
+    }
+}
+
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod test_common;
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::cmp;
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::mem;
+    use std::ops::{self, Bound};
+    use std::ptr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use quickcheck::{quickcheck, Arbitrary, Gen};
+
+    #[cfg(feature = "serde")]
+    use {bincode, serde_json};
+
+    use super::{
+        btree_heap_size, Array, DefaultTopMap, DemotionPolicy, DynArray, Either, Entry, FarBelowPolicy, InvalidParts,
+        InvariantViolation, Layout, ReanchorPolicy, RestTier, ShardedTopMap, Stats, Storage, TickMap, Tier, TierEvent,
+        TierMetrics, TopMap, TopMapBuilder, TopMapError, TopSet,
+    };
+    use test_common::Rng;
+
+    static ITEMS: &[(isize, &str)] = &[
+        (100, "a1"),
+        (101, "a2"),
+        (200, "b1"),
+        (201, "b2"),
+        (300, "c1"),
+        (301, "c2"),
+    ];
+
+    fn lens<A>(m: &TopMap<A>) -> [usize; 3]
+    where
+        A: Layout,
+        A::Key: Copy + Ord,
+        isize: From<A::Key>,
+    {
+        let stats = m.stats();
+        [m.len(), stats.top_occupied, stats.rest_len]
+    }
+
+    struct Wide;
+
+    impl Array for Wide {
+        type Key = isize;
+        type Value = isize;
+        type Array = [Option<(isize, isize)>; 256];
+
+        fn min_size() -> usize {
+            224
+        }
+    }
+
+    #[test]
+    fn custom_min_size() {
+        let mut m = (0..1000).map(|n| (n, n)).collect::<TopMap<Wide>>();
+        assert_eq!([1000, 256, 744], lens(&m));
+
+        m.shrink_to_min();
+        assert_eq!([1000, 224, 776], lens(&m));
+
+        for n in 0..224 {
+            assert_eq!(Some(n), m.remove(n));
+        }
+
+        assert_eq!([776, 224, 552], lens(&m));
+        assert_eq!(Some(224), m.stats().anchor);
+    }
+
+    #[test]
+    fn dyn_array() {
+        let mut m = TopMap::with_array(DynArray::<isize, isize>::new(300));
+        m.extend((0..1000).map(|n| (n, n)));
+        assert_eq!(300, m.stats().top_capacity);
+        assert_eq!([1000, 300, 700], lens(&m));
+
+        m.shrink_to_min();
+        assert_eq!([1000, 150, 850], lens(&m));
+
+        for n in 0..150 {
+            assert_eq!(Some(n), m.remove(n));
+        }
+
+        assert_eq!([850, 150, 700], lens(&m));
+        assert_eq!((150..1000).collect::<Vec<_>>(), m.iter().map(|(key, _)| key).collect::<Vec<_>>());
+
+        let usage = m.memory_usage_breakdown();
+        assert!(usage.top >= 300 * mem::size_of::<Option<(isize, isize)>>());
+    }
+
+    #[test]
+    fn collect_descending() {
+        let m = (0..1000).rev().map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        assert_eq!([1000, 128, 872], lens(&m));
+        assert_eq!(Some(0), m.stats().anchor);
+        assert_eq!(0, m.metrics().demotions);
+        assert_eq!((0..1000).collect::<Vec<_>>(), m.iter().map(|(key, _)| key).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn extend_descending_runs() {
+        let mut m = (1000..1100).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.extend((500..1000).rev().chain((0..500).rev()).map(|n| (n, n)));
+        assert_eq!([1100, 128, 972], lens(&m));
+        assert_eq!(Some(0), m.stats().anchor);
+        assert!(m.metrics().demotions <= 228);
+        assert_eq!((0..1100).collect::<Vec<_>>(), m.iter().map(|(key, _)| key).collect::<Vec<_>>());
+
+        // A tier observer sees the same events as inserting one key at a time
+        let observed = |extend: bool| {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let mut m = TopMap::<[Option<(isize, isize)>; 4]>::new();
+
+            {
+                let events = events.clone();
+                m.set_tier_observer(move |event| events.lock().unwrap().push(event));
+            }
+
+            let items = (0..10).rev().map(|n| (n, n));
+
+            if extend {
+                m.extend(items);
+            } else {
+                for (key, value) in items {
+                    m.insert(key, value);
+                }
+            }
+
+            let events = events.lock().unwrap().clone();
+            events
+        };
+
+        assert_eq!(observed(false), observed(true));
+    }
+
+    #[test]
+    fn top_set() {
+        let mut s = [1, 2, 3, 300, 2].iter().cloned().collect::<TopSet<[Option<(isize, ())>; 4]>>();
+        assert_eq!(4, s.len());
+        assert_eq!("{1, 2, 3, 300}", format!("{:?}", s));
+
+        assert!(s.contains(300));
+        assert!(!s.contains(4));
+        assert!(!s.insert(3));
+        assert!(s.insert(0));
+        assert!(s.remove(1));
+        assert!(!s.remove(1));
+        assert_eq!(vec![0, 2, 3, 300], s.iter().collect::<Vec<_>>());
+
+        s.clear();
+        assert!(s.is_empty());
+    }
+
+    top_array!(type Named, Key = isize, Value = &'static str, N = 10);
+
+    #[test]
+    fn top_array() {
+        let m = ITEMS.iter().cloned().collect::<TopMap<top_array!(Key = isize, Value = &str, N = 10)>>();
+        let named = ITEMS.iter().cloned().collect::<TopMap<Named>>();
+        assert_eq!(m.iter().collect::<Vec<_>>(), named.iter().collect::<Vec<_>>());
+        assert_eq!(10, <Named as Array>::max_size());
+    }
+
+    #[test]
+    fn collect() {
+        let m = ITEMS.iter().cloned().collect::<TopMap<[Option<(isize, &str)>; 10]>>();
+        assert_eq!([6, 2, 4], lens(&m));
+
+        let items = m.iter()
+            .map(|(key, &value)| (key, value))
+            .collect::<Vec<(isize, &'static str)>>();
+
+        assert_eq!(ITEMS, &items[..]);
+    }
+
+    #[test]
+    fn insert() {
+        let mut m = TopMap::<[Option<(isize, &str)>; 10]>::new();
+        assert_eq!(None, m.get(100isize));
+        assert_eq!([0, 0, 0], lens(&m));
+
+        assert_eq!(None, m.insert(200, "b1"));
+        assert_eq!([1, 1, 0], lens(&m));
+
+        assert_eq!(None, m.insert(201, "b2"));
+        assert_eq!([2, 2, 0], lens(&m));
+
+        assert_eq!(None, m.insert(300, "c1"));
+        assert_eq!([3, 2, 1], lens(&m));
+
+        assert_eq!(None, m.insert(301, "c2"));
+        assert_eq!([4, 2, 2], lens(&m));
+
+        assert_eq!(None, m.insert(100, "a1"));
+        assert_eq!([5, 1, 4], lens(&m));
+
+        assert_eq!(None, m.insert(101, "a2"));
+        assert_eq!([6, 2, 4], lens(&m));
+
+        let items = m.iter()
+            .map(|(key, &value)| (key, value))
+            .collect::<Vec<(isize, &'static str)>>();
+
+        assert_eq!(ITEMS, &items[..]);
+    }
+
+    #[test]
+    fn remove() {
+        let mut m = ITEMS.iter().cloned().collect::<TopMap<[Option<(isize, &str)>; 10]>>();
+        assert_eq!(None, m.remove(-1));
+        assert_eq!([6, 2, 4], lens(&m));
+
+        assert_eq!(Some("a1"), m.remove(100));
+        assert_eq!([5, 1, 4], lens(&m));
+
+        assert_eq!(Some("a2"), m.remove(101));
+        assert_eq!([4, 2, 2], lens(&m));
+
+        assert_eq!(Some("c1"), m.remove(300));
+        assert_eq!([3, 2, 1], lens(&m));
+
+        assert_eq!(Some("c2"), m.remove(301));
+        assert_eq!([2, 2, 0], lens(&m));
+
+        assert_eq!(Some("b1"), m.remove(200));
+        assert_eq!([1, 1, 0], lens(&m));
+
+        assert_eq!(Some("b2"), m.remove(201));
+        assert_eq!([0, 0, 0], lens(&m));
+
+        let items = m.iter()
+            .map(|(key, &value)| (key, value))
+            .collect::<Vec<(isize, &'static str)>>();
+
+        assert!(items.is_empty());
+    }
+
+    #[test]
+    fn insert_remove_existing_m1() {
+        let mut m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        assert_eq!([1000, 128, 872], lens(&m));
+        assert_eq!(127, m[127]);
+
+        let index = -1;
+        assert_eq!(None, m.insert(index, index));
+        assert_eq!([1001, 128, 873], lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(Some(index), m.remove(index));
+        assert_eq!([1000, 127, 873], lens(&m));
+        assert_eq!(127, m[127]);
+
+        m.shrink_to_min();
+        assert_eq!([1000, 64, 936], lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(None, m.insert(index, index));
+        assert_eq!([1001, 65, 936], lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(Some(index), m.remove(index));
+        assert_eq!([1000, 64, 936], lens(&m));
+        assert_eq!(127, m[127]);
+    }
+
+    #[test]
+    fn insert_remove_existing_m3() {
+        let mut m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        assert_eq!([1000, 128, 872], lens(&m));
+        assert_eq!(127, m[127]);
+
+        let index = -3;
+        assert_eq!(None, m.insert(index, index));
+        assert_eq!([1001, 126, 875], lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(Some(index), m.remove(index));
+        assert_eq!([1000, 125, 875], lens(&m));
+        assert_eq!(127, m[127]);
+
+        m.shrink_to_min();
+        assert_eq!([1000, 64, 936], lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(None, m.insert(index, index));
+        assert_eq!([1001, 65, 936], lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(Some(index), m.remove(index));
+        assert_eq!([1000, 64, 936], lens(&m));
+        assert_eq!(127, m[127]);
+    }
+
+    #[test]
+    fn insert_remove_existing_m999() {
+        let mut m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        assert_eq!([1000, 128, 872], lens(&m));
+        assert_eq!(127, m[127]);
+
+        let index = -999;
+        assert_eq!(None, m.insert(index, index));
+        assert_eq!([1001, 1, 1000], lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(Some(index), m.remove(index));
+        assert_eq!([1000, 64, 936], lens(&m));
+        assert_eq!(127, m[127]);
+
+        m.shrink_to_min();
+        assert_eq!([1000, 64, 936], lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(None, m.insert(index, index));
+        assert_eq!([1001, 1, 1000], lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(Some(index), m.remove(index));
+        assert_eq!([1000, 64, 936], lens(&m));
+        assert_eq!(127, m[127]);
+    }
+
+    fn insert_remove_existing_shrink_to(target: usize, shrunk: [usize; 3], inserted: [usize; 3], removed: [usize; 3]) {
+        let mut m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        let index = -1;
+        assert_eq!(None, m.insert(index, index));
+        assert_eq!(Some(index), m.remove(index));
+        assert_eq!([1000, 127, 873], lens(&m));
+        assert_eq!(127, m[127]);
+
+        m.shrink_to(target);
+        assert_eq!(shrunk, lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(None, m.insert(index, index));
+        assert_eq!(inserted, lens(&m));
+        assert_eq!(127, m[127]);
+
+        assert_eq!(Some(index), m.remove(index));
+        assert_eq!(removed, lens(&m));
+        assert_eq!(127, m[127]);
+    }
+
+    #[test]
+    fn insert_remove_existing_shrink_to_0() {
+        insert_remove_existing_shrink_to(0, [1000, 0, 1000], [1001, 1, 1000], [1000, 64, 936]);
+    }
+
+    #[test]
+    fn insert_remove_existing_shrink_to_32() {
+        insert_remove_existing_shrink_to(32, [1000, 32, 968], [1001, 33, 968], [1000, 64, 936]);
+    }
+
+    #[test]
+    fn insert_remove_existing_shrink_to_128() {
+        insert_remove_existing_shrink_to(128, [1000, 127, 873], [1001, 128, 873], [1000, 127, 873]);
+        insert_remove_existing_shrink_to(1000, [1000, 127, 873], [1001, 128, 873], [1000, 127, 873]);
+    }
+
+    fn in_top<A>(m: &TopMap<A>, key: A::Key) -> bool
+    where
+        A: Layout,
+        A::Key: PartialEq,
+    {
+        (0..m.top.len()).any(|index| m.top[index].as_ref().is_some_and(|(k, _)| *k == key))
+    }
+
+    #[test]
+    fn rebalance() {
+        let mut m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        m.shrink_to_min();
+        assert_eq!([1000, 64, 936], lens(&m));
+
+        for n in 0..50 {
+            assert_eq!(Some(n), m.remove(n));
+        }
+
+        for n in (60..120).filter(|n| n % 3 == 0) {
+            assert_eq!(Some(n), m.remove(n));
+        }
+
+        assert_eq!([930, 64, 866], lens(&m));
+        assert_eq!(44, m.rebalance());
+        assert_eq!([930, 108, 822], lens(&m));
+
+        for n in (50..178).filter(|n| *n < 60 || *n >= 120 || n % 3 != 0) {
+            assert!(in_top(&m, n), "{} should be in the top", n);
+            assert_eq!(n, m[n]);
+        }
+
+        assert_eq!(0, m.rebalance());
+    }
+
+    #[test]
+    fn promote_demote() {
+        let mut m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        m.shrink_to_min();
+        assert_eq!([1000, 64, 936], lens(&m));
+
+        assert!(!m.promote(10));
+        assert!(!m.promote(128));
+        assert!(!m.promote(-1));
+        assert!(m.promote(100));
+        assert_eq!([1000, 101, 899], lens(&m));
+        assert!(in_top(&m, 100));
+        assert_eq!(100, m[100]);
+
+        assert!(!m.demote(101));
+        assert!(m.demote(90));
+        assert_eq!([1000, 90, 910], lens(&m));
+        assert!(!in_top(&m, 90));
+        assert_eq!(90, m[90]);
+        assert_eq!(100, m[100]);
+
+        assert!(m.demote(0));
+        assert_eq!([1000, 0, 1000], lens(&m));
+        assert_eq!(0, m[0]);
+
+        assert!(m.promote(5));
+        assert_eq!([1000, 6, 994], lens(&m));
+        assert!(in_top(&m, 0));
+        assert!(in_top(&m, 5));
+
+        let items = m.to_vec();
+        assert_eq!((0..1000).map(|n| (n, n)).collect::<Vec<_>>(), items);
+    }
+
+    #[test]
+    fn with_first_key() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 128]>::with_first_key(0);
+        assert_eq!(None, m.get(0));
+        assert_eq!(None, m.remove(0));
+        assert_eq!([0, 0, 0], lens(&m));
+
+        for n in (0..128).rev() {
+            assert_eq!(None, m.insert(n, n));
+            assert_eq!([128 - n as usize, 128 - n as usize, 0], lens(&m));
+        }
+
+        assert_eq!(None, m.insert(-1, -1));
+        assert_eq!([129, 128, 1], lens(&m));
+        assert_eq!(-1, m[-1]);
+        assert_eq!(126, m[126]);
+        assert_eq!(127, m[127]);
+
+        let items = m.to_vec();
+        assert_eq!((-1..128).map(|n| (n, n)).collect::<Vec<_>>(), items);
+    }
+
+    #[test]
+    fn stats() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        assert_eq!(
+            Stats {
+                top_capacity: 8,
+                top_occupied: 0,
+                leading_holes: 0,
+                trailing_holes: 0,
+                rest_len: 0,
+                anchor: None,
+                span: 0,
+            },
+            m.stats()
+        );
+
+        m.extend((10..20).map(|n| (n, n)));
+        m.remove(13);
+        m.remove(17);
+
+        assert_eq!(
+            Stats {
+                top_capacity: 8,
+                top_occupied: 6,
+                leading_holes: 0,
+                trailing_holes: 1,
+                rest_len: 2,
+                anchor: Some(10),
+                span: 8,
+            },
+            m.stats()
+        );
+
+        let m = TopMap::<[Option<(isize, isize)>; 8]>::with_first_key(5);
+        assert_eq!(Some(5), m.stats().anchor);
+    }
+
+    #[test]
+    fn metrics() {
+        let mut m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        m.reset_metrics();
+        assert_eq!(TierMetrics::default(), m.metrics());
+
+        assert_eq!(None, m.insert(-1, -1));
+        assert_eq!(Some(-1), m.remove(-1));
+        m.shrink_to_min();
+        assert_eq!(None, m.insert(-1000, -1000));
+        assert_eq!(Some(-1000), m.remove(-1000));
+
+        assert_eq!(
+            TierMetrics {
+                demotions: 128,
+                promotions: 64,
+                window_evictions: 1,
+                refills: 1,
+                reanchors: 0,
+            },
+            m.metrics()
+        );
+    }
+
+    #[test]
+    fn memory_usage() {
+        type M = TopMap<[Option<(isize, isize)>; 128]>;
+
+        let mut m = M::new();
+        assert_eq!(mem::size_of::<M>(), m.memory_usage());
+
+        m.extend((0..128).map(|n| (n, n)));
+        assert_eq!(mem::size_of::<M>(), m.memory_usage());
+
+        m.extend((128..100128).map(|n| (n, n)));
+        let usage = m.memory_usage_breakdown();
+        assert_eq!(usage.total(), m.memory_usage());
+        assert!(usage.top >= 128 * mem::size_of::<Option<(isize, isize)>>());
+
+        assert!(usage.rest >= btree_heap_size::<isize, isize>(100000));
+
+        // On 64-bit, a leaf is 8 + 11 * 16 + 4 bytes rounded up to 192 and an internal node adds 12 edges. 100,000 keys
+        // in key order fill 14,286 leaves of 7 keys under 2,380 internal nodes. tests/alloc.rs checks this against the
+        // bytes actually allocated.
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(14286 * 192 + 2380 * 288, btree_heap_size::<isize, isize>(100000));
+        }
+    }
+
+    #[test]
+    fn capacity_1() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 1]>::new();
+        assert_eq!(None, m.insert(3, 3));
+        assert_eq!(None, m.insert(1, 1));
+        assert_eq!(None, m.insert(2, 2));
+        assert_eq!([3, 1, 2], lens(&m));
+        assert!(in_top(&m, 1));
+
+        assert_eq!(Some(1), m.remove(1));
+        assert_eq!([2, 1, 1], lens(&m));
+        assert!(in_top(&m, 2));
+
+        m.shrink_to_min();
+        assert_eq!([2, 0, 2], lens(&m));
+        assert_eq!(2, m[2]);
+        assert_eq!(3, m[3]);
+    }
+
+    #[test]
+    fn eviction_limit() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+
+        let mut m = {
+            let evicted = evicted.clone();
+            TopMap::<[Option<(isize, isize)>; 64]>::with_eviction_limit(1000, move |key, value| {
+                assert_eq!(key, value);
+                evicted.lock().unwrap().push(key);
+            })
+        };
+
+        let mut keys = (0..1_000_000).collect::<Vec<isize>>();
+        Rng(0x2545_f491_4f6c_dd1d).shuffle(&mut keys);
+
+        for key in keys {
+            m.insert(key, key);
+            assert!(m.len() <= 1000);
+        }
+
+        assert_eq!(1000, m.len());
+        assert_eq!((0..1000).collect::<Vec<_>>(), m.iter().map(|(key, _)| key).collect::<Vec<_>>());
+
+        let mut evicted = evicted.lock().unwrap();
+        assert_eq!(999_000, evicted.len());
+        evicted.sort();
+        assert_eq!((1000..1_000_000).collect::<Vec<_>>(), *evicted);
+    }
+
+    #[test]
+    fn eviction_limit_unsync_callback() {
+        // `Cell` is `Send` but not `Sync`
+        let evicted = Cell::new(0);
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::with_eviction_limit(2, move |key, _| {
+            evicted.set(evicted.get() + 1);
+            assert_eq!(key, evicted.get() + 1);
+        });
+
+        m.extend((0..5).map(|n| (n, n)));
+        assert_eq!(vec![(0, 0), (1, 1)], m.to_vec());
+
+        let evicted = Cell::new(0);
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::builder()
+            .eviction_limit(1, move |_, _| evicted.set(evicted.get() + 1))
+            .build();
+
+        m.insert(1, 1);
+        m.insert(0, 0);
+        assert_eq!(vec![(0, 0)], m.to_vec());
+    }
+
+    #[test]
+    fn capacity_limit() {
+        let mut keys = (0..1_000_000).collect::<Vec<isize>>();
+        Rng(12345).shuffle(&mut keys);
+
+        let mut m = TopMap::<[Option<(isize, isize)>; 128]>::new();
+        m.set_capacity_limit(1000);
+        let mut evicted = 0;
+
+        for key in keys {
+            if let Some((evicted_key, value)) = m.insert_bounded(key, -key) {
+                assert_eq!(-evicted_key, value);
+                assert!(evicted_key >= 1000);
+                evicted += 1;
+            }
+
+            assert!(m.len() <= 1000);
+        }
+
+        assert_eq!(999_000, evicted);
+        assert_eq!((0..1000).map(|n| (n, -n)).collect::<Vec<_>>(), m.to_vec());
+
+        assert_eq!(None, m.insert_bounded(5, 0));
+        assert_eq!((1000, Some(&0)), (m.len(), m.get(5)));
+        assert_eq!(None, m.insert_bounded(999, 1));
+        assert_eq!((1000, Some(&1)), (m.len(), m.get(999)));
+        assert_eq!(Some((2000, 0)), m.insert_bounded(2000, 0));
+        assert_eq!(Some((999, 1)), m.insert_bounded(-1, 1));
+
+        m.set_capacity_limit(10);
+        assert_eq!((-1..9).collect::<Vec<_>>(), m.iter().map(|(key, _)| key).collect::<Vec<_>>());
+        m.insert(-2, 2);
+        assert_eq!((10, Some((-2, &2)), None), (m.len(), m.iter().next(), m.get(8)));
+    }
+
+    #[test]
+    fn capacity_limit_keeps_callback() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let evicted2 = evicted.clone();
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::with_eviction_limit(5, move |key, _| {
+            evicted2.lock().unwrap().push(key)
+        });
+
+        m.extend((0..5).map(|n| (n, n)));
+        m.set_capacity_limit(3);
+        m.insert(-1, -1);
+        assert_eq!(vec![4, 3, 2], *evicted.lock().unwrap());
+        assert_eq!(vec![(-1, -1), (0, 0), (1, 1)], m.to_vec());
+    }
+
+    #[test]
+    fn eviction_limit_entry() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 4]>::with_eviction_limit(3, |_, _| {});
+        m.extend((0..3).map(|n| (n, n)));
+
+        *m.entry(-1).or_insert(0) += 10;
+        assert_eq!(3, m.len());
+        assert_eq!(vec![(-1, 10), (0, 0), (1, 1)], m.to_vec());
+
+        *m.entry(0).or_insert(0) += 10;
+        assert_eq!(3, m.len());
+        assert_eq!(10, m[0]);
+    }
+
+    #[test]
+    fn compact() {
+        let mut m = (0..1000)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        for n in (0..128).filter(|n| n % 2 == 0) {
+            assert_eq!(Some(n), m.remove(n));
+        }
+
+        assert_eq!(936, m.len());
+
+        m.compact();
+
+        let stats = m.stats();
+        assert_eq!(Some(1), stats.anchor);
+        assert_eq!(0, stats.leading_holes);
+        assert_eq!(0, stats.trailing_holes);
+        assert_eq!(128, stats.span);
+        assert_eq!(65, stats.top_occupied);
+        assert_eq!(871, stats.rest_len);
+
+        for n in 1..129 {
+            assert_eq!(n % 2 == 1 || n == 128, in_top(&m, n));
+        }
+
+        m.compact();
+        assert_eq!(stats, m.stats());
+    }
+
+    #[test]
+    fn append() {
+        type M = TopMap<[Option<(isize, isize)>; 128]>;
+
+        let mut m1 = (0..1000).filter(|n| n % 2 == 0).map(|n| (n, 1)).collect::<M>();
+        let mut m2 = (0..1000).filter(|n| n % 3 == 0).map(|n| (n, 2)).collect::<M>();
+        let mut b1 = m1.iter().map(|(key, &value)| (key, value)).collect::<BTreeMap<_, _>>();
+        let mut b2 = m2.iter().map(|(key, &value)| (key, value)).collect::<BTreeMap<_, _>>();
+
+        m1.append(&mut m2);
+        b1.append(&mut b2);
+        assert_eq!(0, m2.len());
+        assert_eq!(b1.len(), m1.len());
+        assert_eq!(b1.into_iter().collect::<Vec<_>>(), m1.iter().map(|(key, &value)| (key, value)).collect::<Vec<_>>());
+        assert_eq!([667, 85, 582], lens(&m1));
+        assert_eq!(Some(0), m1.stats().anchor);
+
+        m2.insert(-5000, 3);
+        m1.append(&mut m2);
+        assert_eq!([668, 1, 667], lens(&m1));
+        assert_eq!(Some(-5000), m1.stats().anchor);
+    }
+
+    #[test]
+    fn far_below() {
+        let mut m = (1000..1100).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+
+        assert_eq!(None, m.insert(0, 0));
+        assert_eq!(None, m.insert(872, 872));
+        assert_eq!([102, 100, 2], lens(&m));
+        assert_eq!(Some(1000), m.stats().anchor);
+        assert_eq!(Some(&0), m.get(0));
+        assert_eq!(vec![0, 872, 1000], m.iter().map(|(key, _)| key).take(3).collect::<Vec<_>>());
+
+        assert_eq!(Some(0), m.remove(0));
+        assert_eq!(None, m.get(0));
+        assert_eq!([101, 100, 1], lens(&m));
+
+        // Still near enough to move the window down
+        assert_eq!(None, m.insert(900, 900));
+        assert_eq!(Some(900), m.stats().anchor);
+        assert!(in_top(&m, 1027));
+        assert!(!in_top(&m, 1028));
+        assert_eq!(Some(&872), m.get(872));
+
+        m.set_far_below_policy(FarBelowPolicy::Reanchor);
+        assert_eq!(None, m.insert(0, 0));
+        assert_eq!(Some(0), m.stats().anchor);
+        assert_eq!([103, 1, 102], lens(&m));
+    }
+
+    #[test]
+    fn refill_margin() {
+        fn tier_moves(margin: usize) -> u64 {
+            let mut m = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+            m.shrink_to_min();
+            m.set_refill_margin(margin);
+            m.reset_metrics();
+
+            for _ in 0..100 {
+                m.insert(-70, -70);
+                m.remove(-70);
+            }
+
+            assert_eq!(1000, m.len());
+            let metrics = m.metrics();
+            metrics.promotions + metrics.demotions
+        }
+
+        assert_eq!(1200, tier_moves(0));
+        assert_eq!(6, tier_moves(8));
+    }
+
+    #[test]
+    fn insert_sorted_iter() {
+        let mut m = (0..100).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.insert_sorted_iter((100..1000).map(|n| (n, n)));
+        assert_eq!([1000, 128, 872], lens(&m));
+
+        m.insert_sorted_iter((2000..2010).map(|n| (n, n)));
+        assert_eq!([1010, 128, 882], lens(&m));
+        assert_eq!(Some(&2009), m.get(2009));
+        assert!((0..1000).chain(2000..2010).eq(m.iter().map(|(key, _)| key)));
+
+        let mut m = TopMap::<[Option<(isize, isize)>; 128]>::with_first_key(50);
+        m.insert_sorted_iter((0..1000).map(|n| (n, n)));
+        assert_eq!([1000, 128, 872], lens(&m));
+        assert_eq!(Some(0), m.stats().anchor);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "keys must ascend")]
+    fn insert_sorted_iter_out_of_order() {
+        let mut m = (0..100).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.insert_sorted_iter(vec![(200, 200), (150, 150)]);
+    }
+
+    #[test]
+    fn extend_from_dense_slice() {
+        let values = (0..1000).collect::<Vec<isize>>();
+        let mut m = TopMap::<[Option<(isize, isize)>; 128]>::new();
+        m.extend_from_dense_slice(0, &values[..500]);
+        m.extend_from_dense_slice(500, &values[500..]);
+        assert_eq!([1000, 128, 872], lens(&m));
+        assert!(m.iter().all(|(key, &value)| key == value));
+
+        m.extend_from_dense_slice(990, &[-1; 20]);
+        assert_eq!(1010, m.len());
+        assert_eq!(Some(&989), m.get(989));
+        assert_eq!(Some(&-1), m.get(990));
+        assert_eq!(Some(&-1), m.get(1009));
+    }
+
+    #[test]
+    #[should_panic(expected = "dense slice runs past the range of the key type")]
+    fn extend_from_dense_slice_past_key_range() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 128]>::new();
+        m.extend_from_dense_slice(isize::MAX, &[1, 2]);
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let mut m = (0..128).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.shrink_to_fit();
+        assert_eq!([128, 128, 0], lens(&m));
+
+        for n in (0..10).chain(100..128) {
+            m.remove(n);
+        }
+
+        assert_eq!(128 - 10, m.stats().span);
+        m.shrink_to_fit();
+        assert_eq!([90, 90, 0], lens(&m));
+
+        let stats = m.stats();
+        assert_eq!((90, 0, 0, Some(10)), (stats.span, stats.leading_holes, stats.trailing_holes, stats.anchor));
+    }
+
+    #[test]
+    fn dropped_entry() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        let _ = m.entry(5);
+        assert_eq!(TopMap::<[Option<(isize, isize)>; 8]>::new().stats(), m.stats());
+        assert_eq!(None, m.get(5));
+
+        assert_eq!(None, m.insert(100, 100));
+        assert_eq!(Some(100), m.stats().anchor);
+        assert_eq!(vec![(100, 100)], m.iter().map(|(key, &value)| (key, value)).collect::<Vec<_>>());
+
+        m.insert(0, 0);
+        let _ = m.entry(3);
+        assert_eq!((1, 0), (m.stats().span, m.stats().trailing_holes));
+
+        assert_eq!(Some(0), m.remove(0));
+        assert_eq!(Some(100), m.stats().anchor);
+        assert_eq!(vec![(100, 100)], m.to_vec());
+        assert!(m.check().is_ok());
+    }
+
+    #[test]
+    fn entry_into_key() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        assert_eq!(5, m.entry(5).into_key());
+
+        m.extend((10..20).map(|n| (n, n)));
+        let before = (m.stats(), m.to_vec());
+
+        for &key in [5, -100, 11, 12, 18, 30].iter() {
+            assert_eq!(key, m.entry(key).into_key());
+        }
+
+        assert_eq!(before, (m.stats(), m.to_vec()));
+    }
+
+    #[test]
+    fn entry_at_capacity_limit() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+
+        let mut m = {
+            let evicted = evicted.clone();
+            TopMap::<[Option<(isize, isize)>; 8]>::with_eviction_limit(3, move |key, value| {
+                evicted.lock().unwrap().push((key, value));
+            })
+        };
+
+        m.extend((0..3).map(|n| (n, n)));
+
+        // Entries that are dropped or abandoned leave the map alone
+        let _ = m.entry(-1);
+        assert_eq!(5, m.entry(5).into_key());
+        assert!(!m.entry(4).is_occupied());
+        assert_eq!(vec![(0, &0), (1, &1), (2, &2)], m.iter().collect::<Vec<_>>());
+        assert!(evicted.lock().unwrap().is_empty());
+
+        // A key past the largest is evicted itself, as with `insert`, once the reference to it is released
+        *m.entry(5).or_insert(5) += 1;
+        assert_eq!((None, 3), (m.get(5), m.len()));
+        m.insert(6, 6);
+        assert_eq!(vec![(5, 6), (6, 6)], *evicted.lock().unwrap());
+
+        *m.entry(-1).or_default() -= 1;
+        assert_eq!(vec![(-1, &-1), (0, &0), (1, &1)], m.iter().collect::<Vec<_>>());
+        assert_eq!(Some(&(2, 2)), evicted.lock().unwrap().last());
+
+        m.entry(7).or_insert(7);
+        drop(m);
+        assert_eq!(Some(&(7, 7)), evicted.lock().unwrap().last());
+    }
+
+    #[test]
+    fn failed_try_insert() {
+        fn layout<A: Layout<Key = isize, Value = isize>>(m: &TopMap<A>) -> (Stats<isize>, Vec<(isize, Option<Tier>)>) {
+            (m.stats(), m.iter().map(|(key, _)| (key, m.tier_of(key))).collect())
+        }
+
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        assert_eq!(Err("failed"), m.entry(5).or_try_insert_with(|| Err("failed")));
+        assert_eq!(TopMap::<[Option<(isize, isize)>; 8]>::new().stats(), m.stats());
+
+        m.extend((10..20).map(|n| (n, n)));
+        m.remove(12);
+        let before = layout(&m);
+
+        for &key in [5, -100, 12, 30].iter() {
+            assert_eq!(Err("failed"), m.entry(key).or_try_insert_with(|| Err("failed")));
+            assert_eq!(before, layout(&m), "{}", key);
+        }
+
+        assert_eq!(Ok(&mut 11), m.entry(11).or_try_insert_with(|| Err(())));
+        assert_eq!(Ok(&mut 18), m.entry(18).or_try_insert_with(|| Err(())));
+        assert_eq!(Ok(&mut -5), m.entry(5).or_try_insert_with(|| Ok::<_, ()>(-5)));
+        assert_eq!(Some(Tier::Top { slot: 0 }), m.tier_of(5));
+        assert!(m.check().is_ok());
+
+        // At a capacity limit nothing is evicted, and no `touch` mark spent, until the value exists
+        let mut m = (10..18).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        m.set_capacity_limit(8);
+        m.set_demotion_policy(DemotionPolicy::SecondChance);
+        assert!(m.touch(17));
+        let before = layout(&m);
+
+        for &key in [5, -100, 30].iter() {
+            assert_eq!(Err("failed"), m.entry(key).or_try_insert_with(|| Err("failed")));
+            assert_eq!(before, layout(&m), "{}", key);
+        }
+
+        assert_eq!(Ok(&mut 5), m.entry(5).or_try_insert_with(|| Ok::<_, ()>(5)));
+        assert_eq!((Some(&17), None), (m.get(17), m.get(16)));
+    }
+
+    #[test]
+    fn rest_keys_stay_reachable() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        m.insert(0, 0);
+        m.insert(5, 5);
+        m.demote(5);
+        m.shrink_to_fit();
+        assert_eq!(([2, 1, 1], 1), (lens(&m), m.stats().span));
+
+        *m.entry(6).or_insert(0) += 6;
+        m.insert(3, 3);
+        m.insert(4, 4);
+        assert_eq!(([5, 3, 2], 5), (lens(&m), m.stats().span));
+
+        m.insert(7, 7);
+        m.remove(0);
+        assert!(m.check().is_ok());
+
+        for (key, &value) in m.iter() {
+            assert_eq!(Some(&value), m.get(key), "{} should be reachable", key);
+        }
+
+        assert_eq!(vec![(3, 3), (4, 4), (5, 5), (6, 6), (7, 7)], m.to_vec());
+    }
+
+    #[test]
+    fn reserve_top() {
+        let mut m = (0..1000).map(|n| (3 * n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.shrink_to(4);
+        assert_eq!([1000, 2, 998], lens(&m));
+
+        assert_eq!(8, m.reserve_top(10));
+        assert_eq!([1000, 10, 990], lens(&m));
+        assert_eq!(0, m.reserve_top(10));
+        assert_eq!(0, m.reserve_top(5));
+
+        assert_eq!(33, m.reserve_top(128));
+        assert_eq!([1000, 43, 957], lens(&m));
+
+        for key in (0..128).filter(|key| key % 3 == 0) {
+            assert!(in_top(&m, key), "{} should be in the top tier", key);
+            assert_eq!(Some(&(key / 3)), m.get(key));
+        }
+
+        let mut m = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.shrink_to(0);
+        assert_eq!([1000, 0, 1000], lens(&m));
+        assert_eq!(128, m.reserve_top(1000));
+        assert_eq!(Some(0), m.stats().anchor);
+        assert!((0..128).all(|key| in_top(&m, key)));
+        assert_eq!(0, TopMap::<[Option<(isize, isize)>; 4]>::new().reserve_top(4));
+    }
+
+    #[test]
+    fn sparse_top_refills() {
+        let mut m = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.shrink_to_min();
+        m.reset_metrics();
+
+        for n in 1..63 {
+            m.remove(n);
+        }
+
+        assert_eq!([938, 64, 874], lens(&m));
+        assert!(in_top(&m, 0));
+        assert!(in_top(&m, 63));
+        assert!(in_top(&m, 125));
+        assert!(!in_top(&m, 126));
+        assert_eq!(62, m.metrics().refills);
+        assert_eq!(62, m.metrics().promotions);
+    }
+
+    #[test]
+    fn freeze() {
+        let mut m = (100..400).map(|n| (n, n * 10)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.insert(-1000, 1);
+        m.remove(150);
+        let expected = m.to_vec();
+
+        let f = m.freeze();
+        assert_eq!((300, false), (f.len(), f.is_empty()));
+        assert_eq!(expected, f.iter().map(|(key, &value)| (key, value)).collect::<Vec<_>>());
+
+        for key in -1001..410 {
+            assert_eq!(expected.iter().find(|entry| entry.0 == key).map(|entry| &entry.1), f.get(key));
+        }
+
+        assert_eq!(1990, f[199]);
+        assert_eq!(vec![-1000, 100, 101], f.range(..102).map(|(key, _)| key).collect::<Vec<_>>());
+        assert_eq!(vec![149, 151], f.range(149..=151).map(|(key, _)| key).collect::<Vec<_>>());
+        let tail = f.range((Bound::Excluded(397), Bound::Unbounded));
+        assert_eq!(vec![398, 399], tail.map(|(key, _)| key).collect::<Vec<_>>());
+        assert_eq!(0, f.range((Bound::Included(200), Bound::Excluded(100))).count());
+
+        let bounds = |key| vec![Bound::Included(key), Bound::Excluded(key)];
+        let edges = [isize::MIN, -1000, 99, 100, 150, 226, 227, 228, 300, 399, isize::MAX];
+        let mut ends = edges.iter().flat_map(|&key| bounds(key)).collect::<Vec<_>>();
+        ends.push(Bound::Unbounded);
+
+        for &start in ends.iter() {
+            for &end in ends.iter() {
+                let range = (start, end);
+                let keys = f.range(range).map(|(key, _)| key).collect::<Vec<_>>();
+                let in_range = expected.iter().map(|entry| entry.0);
+                let in_range = in_range.filter(|key| ops::RangeBounds::contains(&range, key)).collect::<Vec<_>>();
+                assert_eq!(in_range, keys, "{:?}", range);
+            }
+        }
+
+        let m2: TopMap<[Option<(isize, isize)>; 16]> = f.clone().unfreeze();
+        assert_eq!(expected, m2.to_vec());
+        assert_eq!(Some(-1000), m2.stats().anchor);
+        assert_eq!(expected, TopMap::<[Option<(isize, isize)>; 128]>::from(f).to_vec());
+
+        let empty = TopMap::<[Option<(isize, isize)>; 8]>::new().freeze();
+        assert_eq!((true, None, 0), (empty.is_empty(), empty.get(0), empty.range(..).count()));
+    }
+
+    #[test]
+    fn find_by_value() {
+        let mut m = (100..400).map(|n| (n, n % 50)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.insert(-1000, 49);
+        m.remove(149);
+
+        assert_eq!(Some((-1000, &49)), m.find_by_value(|&value| value == 49));
+        assert_eq!(Some((399, &49)), m.rfind_by_value(|&value| value == 49));
+        assert_eq!(Some((101, &1)), m.find_by_value(|&value| value == 1));
+        assert_eq!(None, m.find_by_value(|&value| value > 50));
+        assert_eq!(None, m.rfind_by_value(|&value| value > 50));
+
+        m.remove(-1000);
+        assert_eq!(Some((199, &49)), m.find_by_value(|&value| value == 49));
+
+        m.insert(120, 1000);
+        m.insert(130, 1000);
+        assert_eq!(Some((130, &1000)), m.rfind_by_value(|&value| value == 1000));
+
+        let mut visited = 0;
+        assert_eq!(Some((398, &48)), m.rfind_by_value(|&value| {
+            visited += 1;
+            value == 48
+        }));
+        assert_eq!(2, visited);
+    }
+
+    #[test]
+    fn iter_slots() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 128]>::new();
+        assert_eq!(0, m.iter_slots().count());
+
+        m.extend(vec![(10, 0), (12, 2), (13, 3), (500, 490)]);
+        assert_eq!(
+            vec![(10, Some(&0)), (11, None), (12, Some(&2)), (13, Some(&3))],
+            m.iter_slots().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn gaps() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 128]>::new();
+        assert_eq!(0, m.gaps().count());
+
+        for n in 0..500 {
+            let key = (n * 37) % 200;
+
+            if n % 3 == 0 {
+                m.remove(key);
+            } else {
+                m.insert(key, n);
+            }
+
+            let stats = m.stats();
+            let anchor = stats.anchor.unwrap_or(0);
+            let expected = (anchor..anchor + stats.span as isize)
+                .filter(|&key| m.get(key).is_none())
+                .collect::<Vec<_>>();
+
+            assert_eq!(expected, m.gaps().collect::<Vec<_>>());
+            assert_eq!(
+                expected.iter().cloned().filter(|key| (50..100).contains(key)).collect::<Vec<_>>(),
+                m.gaps_in(50..100).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn occupancy_bitmap() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 128]>::new();
+        assert_eq!(None, m.occupancy_bitmap());
+
+        m.insert(1000, 0);
+        assert_eq!(Some((1000, vec![1])), m.occupancy_bitmap());
+
+        for n in 0..300 {
+            m.insert(1000 + (n * 37) % 200, n);
+
+            if n % 4 == 0 {
+                m.remove(1000 + (n * 13) % 200);
+            }
+
+            let span = m.stats().span;
+            let (anchor, words) = match m.occupancy_bitmap() {
+                Some(bitmap) => bitmap,
+                None => {
+                    assert_eq!(0, span);
+                    continue;
+                }
+            };
+
+            assert_eq!(span.div_ceil(64), words.len());
+
+            let gaps = m.gaps().collect::<BTreeSet<_>>();
+
+            for index in 0..words.len() * 64 {
+                let key = anchor + index as isize;
+                let bit = words[index / 64] & (1 << (index % 64)) != 0;
+
+                if index < span {
+                    assert_eq!(m.get(key).is_some(), bit, "key {}", key);
+                    assert_eq!(!gaps.contains(&key), bit, "key {}", key);
+                } else {
+                    assert!(!bit, "key {} is past the window", key);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn for_each_range_mut() {
+        let mut m = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.insert(-500, -500);
+        m.remove(10);
+
+        m.for_each_range_mut(5..5, |_, value| *value = -1);
+        m.for_each_range_mut(8..12, |key, value| *value += key);
+        m.for_each_range_mut(990.., |_, value| *value = 0);
+        m.for_each_range_mut(..=0, |_, value| *value = 1);
+        m.for_each_range_mut(126..130, |key, value| *value = -key);
+
+        assert_eq!(Some(&1), m.get(-500));
+        assert_eq!(Some(&1), m.get(0));
+        assert_eq!(Some(&5), m.get(5));
+        assert_eq!(Some(&16), m.get(8));
+        assert_eq!(None, m.get(10));
+        assert_eq!(Some(&22), m.get(11));
+        assert_eq!(Some(&12), m.get(12));
+        assert_eq!(Some(&-127), m.get(127));
+        assert_eq!(Some(&-128), m.get(128));
+        assert_eq!(Some(&130), m.get(130));
+        assert_eq!(Some(&0), m.get(999));
+        assert_eq!(1000, m.len());
+    }
+
+    #[test]
+    fn iter_from() {
+        let mut m = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.insert(-500, -500);
+        m.remove(10);
+
+        let keys = |from| m.iter_from(from).map(|(key, _)| key).take(3).collect::<Vec<_>>();
+        assert_eq!(vec![-500, 0, 1], keys(-1000));
+        assert_eq!(vec![0, 1, 2], keys(-499));
+        assert_eq!(vec![5, 6, 7], keys(5));
+        assert_eq!(vec![11, 12, 13], keys(10));
+        assert_eq!(vec![127, 128, 129], keys(127));
+        assert_eq!(vec![998, 999], keys(998));
+        assert_eq!(Vec::<isize>::new(), keys(1000));
+    }
+
+    #[test]
+    fn next_after_prev_before() {
+        let mut m = (0..1000).filter(|n| n % 7 != 0).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.insert(-500, -500);
+        m.shrink_to_min();
+
+        let b = m.iter().map(|(key, &value)| (key, value)).collect::<BTreeMap<_, _>>();
+
+        for key in -600..1100 {
+            assert_eq!(
+                b.range(key + 1..).next().map(|(&key, value)| (key, value)),
+                m.next_after(key),
+                "next_after({})",
+                key
+            );
+
+            assert_eq!(
+                b.range(..key).next_back().map(|(&key, value)| (key, value)),
+                m.prev_before(key),
+                "prev_before({})",
+                key
+            );
+        }
+
+        // Keys too far above the anchor for an `isize` offset land past the window
+        let m = (-5..5).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 16]>>();
+        assert_eq!(None, m.next_after(isize::MAX - 1));
+        assert_eq!(Some((-5, &-5)), m.next_after(isize::MIN));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_iter() {
+        use rayon::iter::ParallelIterator;
+
+        let mut m = (0..100_000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.remove(10);
+        m.shrink_to_min();
+
+        let sum = m.iter().map(|(_, &value)| value).sum::<isize>();
+        assert_eq!(sum, m.par_iter().map(|(_, &value)| value).sum::<isize>());
+        assert_eq!(m.len(), m.par_iter().count());
+
+        m.par_values_mut().for_each(|value| *value *= 2);
+        m.par_iter_mut().filter(|&(key, _)| key % 2 == 0).for_each(|(_, value)| *value += 1);
+        assert_eq!(2 * sum + 50_000 - 1, m.par_iter().map(|(_, &value)| value).sum::<isize>());
+        assert!(m.iter().all(|(key, &value)| value == 2 * key + (1 - key % 2)));
+
+        // A wrapped ring splits the window into two slices
+        let mut d = TopMap::with_array(DynArray::new(16));
+        d.extend((10..30isize).map(|n| (n, n)));
+        d.insert(9, 9);
+        d.par_values_mut().for_each(|value| *value *= 2);
+        assert_eq!((9..30).sum::<isize>() * 2, d.par_iter().map(|(_, &value)| value).sum::<isize>());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        type M = TopMap<[Option<(isize, isize)>; 128]>;
+
+        let mut m = (0..1000).map(|n| (n, -n)).collect::<M>();
+        m.remove(5);
+
+        let json = serde_json::to_string(&m).unwrap();
+        assert!(json.starts_with(r#"{"0":0,"1":-1,"2":-2,"3":-3,"4":-4,"6":-6,"#));
+
+        let m2 = serde_json::from_str::<M>(&json).unwrap();
+        assert_eq!(m.to_vec(), m2.to_vec());
+        assert_eq!([999, 127, 872], lens(&m2));
+        assert_eq!(Some(0), m2.stats().anchor);
+
+        let m3 = bincode::deserialize::<M>(&bincode::serialize(&m).unwrap()).unwrap();
+        assert_eq!(m.to_vec(), m3.to_vec());
+        assert_eq!(lens(&m2), lens(&m3));
+
+        let m4 = serde_json::from_str::<M>(r#"{"50":1,"10":2,"30":3}"#).unwrap();
+        assert_eq!(vec![(10, 2), (30, 3), (50, 1)], m4.to_vec());
+        assert_eq!(Some(10), m4.stats().anchor);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_with() {
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+        use {serde_map, serde_seq};
+
+        type M = TopMap<[Option<(isize, isize)>; 128]>;
+
+        struct Seq(M);
+
+        impl Serialize for Seq {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serde_seq::serialize(&self.0, serializer)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for Seq {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                serde_seq::unique::deserialize(deserializer).map(Seq)
+            }
+        }
+
+        let m = (0..1000).filter(|n| n % 3 != 0).map(|n| (n, -n)).collect::<M>();
+
+        let json = serde_json::to_string(&Seq(m)).unwrap();
+        assert!(json.starts_with("[[1,-1],[2,-2],[4,-4],"));
+
+        let Seq(m) = serde_json::from_str(&json).unwrap();
+        assert_eq!(666, m.len());
+        assert_eq!(Some(1), m.stats().anchor);
+
+        let Seq(m2) = bincode::deserialize(&bincode::serialize(&Seq(m)).unwrap()).unwrap();
+        assert_eq!((0..1000).filter(|n| n % 3 != 0).map(|n| (n, -n)).collect::<Vec<_>>(), m2.to_vec());
+
+        let json = |s| serde_json::Deserializer::from_str(s);
+        let seq = serde_seq::deserialize::<[Option<(isize, isize)>; 128], _>(&mut json("[[5,1],[2,2],[5,3]]")).unwrap();
+        assert_eq!(vec![(2, 2), (5, 3)], seq.to_vec());
+
+        let err = serde_seq::unique::deserialize::<[Option<(isize, isize)>; 128], _>(&mut json("[[5,1],[2,2],[5,3]]"));
+        assert!(err.err().unwrap().to_string().starts_with("duplicate key 5"));
+
+        let map = serde_map::deserialize::<[Option<(isize, isize)>; 128], _>(&mut json(r#"{"7":1,"7":2}"#)).unwrap();
+        assert_eq!(vec![(7, 2)], map.to_vec());
+
+        let err = serde_map::unique::deserialize::<[Option<(isize, isize)>; 128], _>(&mut json(r#"{"7":1,"7":2}"#));
+        assert!(err.err().unwrap().to_string().starts_with("duplicate key 7"));
+    }
+
+    #[cfg(feature = "rkyv")]
+    #[test]
+    fn rkyv() {
+        use rkyv::{self, Deserialize, Infallible};
+
+        type M = TopMap<[Option<(isize, i64)>; 128]>;
+
+        let m = (0..1000).filter(|n| n % 3 != 0).map(|n| (n, -n as i64)).collect::<M>();
+        let bytes = rkyv::to_bytes::<_, 256>(&m).unwrap();
+        let archived = unsafe { rkyv::archived_root::<M>(&bytes) };
+
+        assert_eq!(666, archived.len());
+        assert_eq!(Some(&-1), archived.get(&1));
+        assert_eq!(None, archived.get(&3));
+        assert_eq!(Some(&-998), archived.get(&998));
+        assert!(!archived.contains_key(&1000));
+        assert_eq!(vec![(&1, &-1), (&2, &-2), (&4, &-4)], archived.iter().take(3).collect::<Vec<_>>());
+        assert_eq!(vec![(&4, &-4), (&5, &-5)], archived.iter_from(&3).take(2).collect::<Vec<_>>());
+        assert_eq!(vec![(&4, &-4), (&5, &-5)], archived.iter_from(&4).take(2).collect::<Vec<_>>());
+        assert_eq!(0, archived.iter_from(&999).count());
+
+        let m2: M = archived.deserialize(&mut Infallible).unwrap();
+        assert_eq!(m.to_vec(), m2.to_vec());
+        assert_eq!(lens(&m), lens(&m2));
+        assert_eq!(Some(1), m2.stats().anchor);
+    }
+
+    #[test]
+    fn clone() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+
+        let mut m1 = {
+            let evicted = evicted.clone();
+            TopMap::<[Option<(isize, isize)>; 4]>::with_eviction_limit(3, move |key, _| {
+                evicted.lock().unwrap().push(key)
+            })
+        };
+
+        m1.extend((0..3).map(|n| (n, n)));
+        let mut m2 = m1.clone();
+        m2.insert(1, 10);
+        m2.insert(5, 5);
+        m1.insert(-1, -1);
+
+        assert_eq!(vec![(-1, -1), (0, 0), (1, 1)], m1.to_vec());
+        assert_eq!(vec![(0, 0), (1, 10), (2, 2)], m2.to_vec());
+        assert_eq!(vec![5, 2], *evicted.lock().unwrap());
+
+        let d1 = (0..100).map(|n| (n, n)).collect::<Vec<(isize, isize)>>();
+        let mut d2 = TopMap::with_array(DynArray::new(16));
+        d2.extend(d1.iter().cloned());
+        assert_eq!(d1, d2.clone().to_vec());
+    }
+
+    #[cfg(feature = "proptest")]
+    #[test]
+    fn proptest_strategies() {
+        use proptest::{self, Action as PropAction};
+        use proptest_crate::strategy::{Strategy, ValueTree};
+        use proptest_crate::test_runner::TestRunner;
+
+        type M = TopMap<[Option<(isize, isize)>; 8]>;
+
+        let key = (0..10u8, -20..40isize, -10_000..10_000isize)
+            .prop_map(|(kind, near, far)| if kind == 0 { far } else { near });
+
+        let mut runner = TestRunner::deterministic();
+        let maps = proptest::top_map::<[Option<(isize, isize)>; 8], _, _, _>(key.clone(), 0..100isize, 0..200);
+        let mut interesting = 0;
+
+        for _ in 0..100 {
+            let m = maps.new_tree(&mut runner).unwrap().current();
+            let stats = m.stats();
+
+            if stats.top_occupied > 0 && stats.rest_len > 0 && stats.top_occupied < stats.span {
+                interesting += 1;
+            }
+        }
+
+        assert!(interesting > 10, "only {} maps had both tiers and holes", interesting);
+
+        runner
+            .run(&(maps, proptest::actions(key, 0..100isize, 0..100)), |(mut m, actions): (M, _)| {
+                let mut model = m.iter().map(|(key, &value)| (key, value)).collect::<BTreeMap<_, _>>();
+
+                for action in actions {
+                    match action {
+                        PropAction::Insert { key, value } => assert_eq!(model.insert(key, value), m.insert(key, value)),
+                        PropAction::Remove { key } => assert_eq!(model.remove(&key), m.remove(key)),
+                        PropAction::Get { key } => assert_eq!(model.get(&key), m.get(key)),
+                    }
+                }
+
+                assert_eq!(model.into_iter().collect::<Vec<_>>(), m.to_vec());
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_from_bytes() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        type M = TopMap<[Option<(i16, u8)>; 16]>;
+        type S = TopSet<[Option<(i16, ())>; 16]>;
+
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut interesting = 0;
+
+        for len in 0..500 {
+            let bytes = (0..len * 8).map(|_| rng.next() as u8).collect::<Vec<_>>();
+
+            let m = <M as Arbitrary>::arbitrary(&mut Unstructured::new(&bytes)).unwrap();
+            let keys = m.iter().map(|(key, _)| key).collect::<Vec<_>>();
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(m.len(), keys.len());
+            assert_eq!(m.occupied, (0..m.top.len()).filter(|&index| m.top[index].is_some()).count());
+            assert_eq!(m.rest_min, m.rest.keys().next().cloned());
+
+            if m.stats().top_occupied > 0 && m.stats().rest_len > 0 {
+                interesting += 1;
+            }
+
+            let s = <S as Arbitrary>::arbitrary(&mut Unstructured::new(&bytes)).unwrap();
+            let keys = s.iter().collect::<Vec<_>>();
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(s.len(), keys.len());
+        }
+
+        assert!(interesting > 100, "only {} maps used both tiers", interesting);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_extend() {
+        use rayon::iter::{IntoParallelIterator, ParallelExtend, ParallelIterator};
+
+        type M = TopMap<[Option<(isize, isize)>; 128]>;
+
+        let mut m1 = (0..100_000isize).into_par_iter().map(|n| (n, n)).collect::<M>();
+        let mut m2 = (0..100_000isize).map(|n| (n, n)).collect::<M>();
+        assert_eq!(m2.to_vec(), m1.to_vec());
+        assert_eq!(lens(&m2), lens(&m1));
+
+        let items = (0..50_000isize).map(|n| ((n * 7919) % 150_000, n)).collect::<Vec<_>>();
+        m1.par_extend(items.clone());
+        m2.extend(items);
+        assert_eq!(m2.to_vec(), m1.to_vec());
+
+        let m3 = (0..1000isize).into_par_iter().map(|n| (n % 10, n)).collect::<M>();
+        assert_eq!((0..10).map(|n| (n, 990 + n)).collect::<Vec<_>>(), m3.to_vec());
+    }
+
+    #[test]
+    fn debug() {
+        let mut m = (10..13).map(|n| (n, n * 10)).collect::<TopMap<[Option<(isize, isize)>; 4]>>();
+        m.remove(11);
+        m.insert(100, 1000);
+
+        assert_eq!("{10: 100, 12: 120, 100: 1000}", format!("{:?}", m));
+        assert_eq!(
+            "TopMap {
+    anchor: Some(
+        10,
+    ),
+    window: {
+        0: 100,
+        1: _,
+        2: 120,
+    },
+    rest_len: 1,
+    rest: {
+        100: 1000,
+    },
+}",
+            format!("{:#?}", m)
+        );
+    }
+
+    #[test]
+    fn entry_debug() {
+        struct Opaque;
+
+        #[derive(Debug)]
+        struct Holder<'a> {
+            entry: Entry<'a, [Option<(isize, Opaque)>; 4]>,
+        }
+
+        let mut m = TopMap::<[Option<(isize, Opaque)>; 4]>::new();
+        m.insert(10, Opaque);
+
+        let holder = Holder { entry: m.entry(10) };
+        assert_eq!("Holder { entry: Vec { key: 10, occupied: true } }", format!("{:?}", holder));
+        assert!(holder.entry.is_occupied());
+        assert_eq!("OutsideTop { key: 11, occupied: false }", format!("{:?}", m.entry(11)));
+        assert_eq!("AboveTop { key: 9, occupied: false }", format!("{:?}", m.entry(9)));
+        assert_eq!("RestMin { key: 100, occupied: false }", format!("{:?}", m.entry(100)));
+
+        let mut m = TopMap::<[Option<(isize, &str)>; 4]>::new();
+        m.insert(10, "a");
+        m.insert(100, "b");
+        assert_eq!("Vec { key: 10, value: Some(\"a\") }", format!("{:?}", m.entry(10).debug_value()));
+        assert_eq!("BTreeMap { key: 100, value: Some(\"b\") }", format!("{:?}", m.entry(100).debug_value()));
+        assert_eq!("BTreeMap { key: 101, value: None }", format!("{:?}", m.entry(101).debug_value()));
+        assert_eq!(101, m.entry(101).key());
+    }
+
+    #[test]
+    fn hash_map() {
+        let hash_map = (0..200).map(|n| (n * 3, n)).collect::<HashMap<isize, isize>>();
+        let m = TopMap::<[Option<(isize, isize)>; 128]>::from(hash_map.clone());
+
+        assert_eq!(
+            hash_map.iter().map(|(&key, &value)| (key, value)).collect::<BTreeMap<_, _>>(),
+            m.iter().map(|(key, &value)| (key, value)).collect::<BTreeMap<_, _>>()
+        );
+
+        let stats = m.stats();
+        assert_eq!(Some(0), stats.anchor);
+        assert_eq!((43, 157), (stats.top_occupied, stats.rest_len));
+        assert_eq!(hash_map, HashMap::from(m));
+        assert_eq!(0, TopMap::<[Option<(isize, isize)>; 4]>::from(HashMap::new()).len());
+    }
+
+    #[test]
+    fn sharded() {
+        let m = ShardedTopMap::<[Option<(isize, isize)>; 16]>::new(4);
+        assert_eq!(4, m.shard_count());
+
+        for key in (-100..100).rev() {
+            assert_eq!(None, m.insert(key, key * 10));
+        }
+
+        assert_eq!(Some(-1000), m.insert(-100, 0));
+        assert_eq!(Some(990), m.remove(99));
+        assert_eq!(Some(0), m.get_cloned(-100));
+        assert!(!m.contains_key(99));
+        assert_eq!(199, m.len());
+
+        let keys = m.snapshot().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!((-100..99).collect::<Vec<_>>(), keys);
+
+        // A window-sized run of keys lands in a single shard's window.
+        let shard = m.shards[0].lock().unwrap();
+        assert_eq!(Some(-64), shard.stats().anchor);
+        assert_eq!(16, shard.stats().top_occupied);
+    }
+
+    #[test]
+    fn sharded_threads() {
+        let m = Arc::new(ShardedTopMap::<[Option<(isize, isize)>; 16]>::new(4));
+
+        let threads = (0..8)
+            .map(|thread| {
+                let m = m.clone();
+
+                thread::spawn(move || {
+                    let own = thread * 1000;
+
+                    for n in 0..1000 {
+                        m.insert(own + n, thread);
+                        m.insert(-(n % 100), thread);
+                        assert_eq!(Some(thread), m.get_cloned(own + n));
+
+                        if n % 2 == 0 {
+                            assert_eq!(Some(thread), m.remove(own + n / 2));
+                        }
+
+                        if n % 3 == 0 {
+                            m.remove(-(n % 50));
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let snapshot = m.snapshot().collect::<Vec<_>>();
+        assert_eq!(m.len(), snapshot.len());
+        assert!(snapshot.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        for thread in 0..8 {
+            let own = snapshot
+                .iter()
+                .filter(|&&(key, _)| key >= thread * 1000 && key < (thread + 1) * 1000)
+                .collect::<Vec<_>>();
+
+            assert_eq!(500, own.len());
+            assert!(own.iter().all(|&&(key, value)| key >= thread * 1000 + 500 && value == thread));
+        }
+
+        assert!(snapshot.iter().all(|&(key, _)| key > -100));
+    }
+
+    #[test]
+    fn builder() {
+        type Map = TopMap<[Option<(isize, isize)>; 8]>;
+
+        let m = Map::builder().build();
+        assert_eq!(None, m.stats().anchor);
+        assert_eq!(FarBelowPolicy::Reanchor, m.far_below);
+        assert_eq!((None, 0), (m.reanchor, m.refill_margin));
+        assert!(m.eviction.is_none());
+
+        let mut m = Map::builder().anchor(1000).build();
+        m.insert(1003, 3);
+        assert_eq!(Some(1000), m.stats().anchor);
+        assert!(in_top(&m, 1003));
+
+        let m = TopMapBuilder::with_array(DynArray::new(16)).build_from_sorted((0..20isize).map(|n| (n, n)));
+        assert_eq!(16, m.stats().top_capacity);
+        assert_eq!([20, 16, 4], lens(&m));
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut m = Map::builder()
+            .eviction_limit(3, {
+                let evicted = evicted.clone();
+                move |key, _| evicted.lock().unwrap().push(key)
+            })
+            .build();
+
+        m.extend((0..5).map(|n| (n, n)));
+        assert_eq!(3, m.len());
+        assert_eq!(vec![3, 4], *evicted.lock().unwrap());
+
+        let mut m = Map::builder()
+            .reanchor_policy(ReanchorPolicy {
+                max_occupied: 2,
+                min_hot_rest: 4,
+            })
+            .build_from_sorted(Some((0, 0)));
+
+        m.extend((100..104).map(|n| (n, n)));
+        assert_eq!(Some(100), m.stats().anchor);
+        assert_eq!(1, m.metrics().reanchors);
+
+        let mut m = Map::builder()
+            .far_below_policy(FarBelowPolicy::KeepWindow)
+            .build_from_sorted((1000..1004).map(|n| (n, n)));
+
+        m.insert(0, 0);
+        assert_eq!(Some(1000), m.stats().anchor);
+
+        let mut m = TopMap::<[Option<(isize, isize)>; 128]>::builder()
+            .refill_margin(8)
+            .build_from_sorted((0..1000).map(|n| (n, n)));
+
+        m.shrink_to_min();
+        m.reset_metrics();
+
+        for _ in 0..100 {
+            m.insert(-70, -70);
+            m.remove(-70);
+        }
+
+        assert_eq!(6, m.metrics().promotions + m.metrics().demotions);
+    }
+
+    #[test]
+    fn aliases() {
+        use prelude::*;
+
+        let m = (0..200).map(|n| (n, n * 2)).collect::<TopMap128<_>>();
+        assert_eq!(200, m.len());
+        assert_eq!(Some(&20), m.get(10));
+
+        let mut m = TopMap64::new();
+        *m.entry(5).or_insert(0) += 1;
+        assert_eq!(1, m[5]);
+
+        let m = TopMap256::<&str>::builder().anchor(1000).build_from_sorted(vec![(1001, "a"), (2000, "b")]);
+        assert_eq!(vec![(1001, &"a"), (2000, &"b")], m.iter().collect::<Vec<_>>());
+        assert!(in_top(&m, 1001));
+        assert!(!in_top(&m, 2000));
+    }
+
+    #[test]
+    fn static_map() {
+        use prelude::TopMap64;
+        use std::sync::LazyLock;
+
+        static MAP: LazyLock<Mutex<TopMap64<&str>>> = LazyLock::new(|| Mutex::new(TopMap64::new()));
+
+        MAP.lock().unwrap().insert(5, "a");
+        MAP.lock().unwrap().insert(500, "b");
+        assert_eq!(vec![(5, &"a"), (500, &"b")], MAP.lock().unwrap().iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn try_get() {
+        let mut m = (0..10).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 4]>>();
+        m.remove(2);
+
+        assert_eq!(Ok(&1), m.try_get(1));
+        assert_eq!(Ok(&9), m.try_get(9));
+        assert_eq!(Err(TopMapError::Missing(2)), m.try_get(2));
+        assert_eq!(Err(TopMapError::Missing(isize::MIN)), m.try_get(isize::MIN));
+        assert_eq!(Err(TopMapError::Missing(isize::MAX)), m.try_get(isize::MAX));
+        assert_eq!("no item with key 2", m.try_get(2).unwrap_err().to_string());
+
+        *m.try_get_mut(9).unwrap() += 1;
+        assert_eq!(Ok(&10), m.try_get(9));
+
+        m.top[0] = Some((3, 3));
+        assert_eq!(Err(TopMapError::MisplacedSlot { key: 0, found: 3 }), m.try_get(0));
+        assert_eq!(Err(TopMapError::MisplacedSlot { key: 0, found: 3 }), m.try_get_mut(0).map(|_| ()));
+        assert_eq!("window slot for key 0 holds key 3", m.try_get(0).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn checked_slots() {
+        use checked::CheckedSlots;
+
+        let mut m = vec![(120, 0), (122, 2), (125, 5)].into_iter().collect::<TopMap<[Option<(i8, u8)>; 16]>>();
+        assert_eq!(Ok(vec![121, 123, 124]), m.try_gaps());
+        assert_eq!(Ok(vec![123, 124]), m.try_gaps_in(123..127));
+        assert_eq!(Ok(vec![121]), m.try_gaps_in(i8::MIN..122));
+        assert_eq!(m.iter_slots().collect::<Vec<_>>(), m.try_iter_slots().unwrap());
+
+        // Slots past the largest key have no key to name
+        for _ in 0..4 {
+            *m.top.push_back() = None;
+        }
+
+        assert_eq!(Err(TopMapError::OutOfKeyRange { offset: 8 }), m.try_gaps());
+        assert_eq!(Ok(vec![126]), m.try_gaps_in(126..127));
+        assert_eq!("window slot 8 runs past the range of the key type", m.try_iter_slots().unwrap_err().to_string());
+
+        // Keys too far from the anchor for an `isize` offset clamp to the window instead of overflowing
+        let m = (-5..5).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 16]>>();
+        assert_eq!(None, m.iter_from(isize::MAX).next());
+        assert_eq!(10, m.count_range(isize::MIN..=isize::MAX));
+        assert_eq!(Some(4), m.prev_before(isize::MAX).map(|(key, _)| key));
+        assert_eq!(Ok(vec![]), m.try_gaps_in(isize::MIN..isize::MAX));
+    }
+
+    #[test]
+    fn check() {
+        let mut m = (0..10).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 4]>>();
+        m.insert(-10, -10);
+        assert_eq!(Ok(()), TopMap::<[Option<(isize, isize)>; 4]>::with_first_key(5).check());
+        assert_eq!(Ok(()), m.check());
+
+        let mut broken = m.clone();
+        broken.occupied += 1;
+        assert_eq!(Err(InvariantViolation::OccupiedCount { recorded: 2, actual: 1 }), broken.check());
+
+        let mut broken = m.clone();
+        broken.top[0] = Some((-9, -9));
+        assert_eq!(Err(InvariantViolation::MisplacedSlot { key: -9, index: 0 }), broken.check());
+
+        let mut broken = m.clone();
+        broken.rest.make_mut().insert(-10, 0);
+        assert_eq!(Err(InvariantViolation::RestOverlapsWindow(-10)), broken.check());
+
+        let mut broken = m.clone();
+        broken.rest.make_mut().remove(&0);
+        assert_eq!(
+            Err(InvariantViolation::StaleRestMin {
+                recorded: Some(0),
+                actual: Some(1)
+            }),
+            broken.check()
+        );
+
+        let mut broken = m.clone();
+        broken.below.insert(-10, 0);
+        assert_eq!(Err(InvariantViolation::BelowOverlapsWindow(-10)), broken.check());
+
+        let mut broken = m.clone();
+        broken.anchor = None;
+        assert_eq!(Err(InvariantViolation::WindowWithoutAnchor), broken.check());
+        assert_eq!("window has slots but no anchor", broken.check().unwrap_err().to_string());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_events() {
+        use std::fmt;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{subscriber, Event, Metadata, Subscriber};
+
+        #[derive(Default)]
+        struct Fields(String, i64, u64);
+
+        impl Visit for Fields {
+            fn record_i64(&mut self, field: &Field, value: i64) {
+                if field.name() == "key" {
+                    self.1 = value;
+                }
+            }
+
+            fn record_u64(&mut self, field: &Field, value: u64) {
+                if field.name() == "moved" {
+                    self.2 = value;
+                }
+            }
+
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{:?}", value);
+                }
+            }
+        }
+
+        struct Capture(Arc<Mutex<Vec<(String, i64, u64)>>>);
+
+        impl Subscriber for Capture {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn new_span(&self, _: &Attributes) -> Id {
+                Id::from_u64(1)
+            }
+
+            fn record(&self, _: &Id, _: &Record) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                self.0.lock().unwrap().push((fields.0, fields.1, fields.2));
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        subscriber::with_default(Capture(events.clone()), || {
+            let mut m = (10..18).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+            m.insert(-100, 0);
+            m.remove(-100);
+            m.shrink_to(2);
+
+            let mut m = TopMap::<[Option<(isize, isize)>; 8]>::builder()
+                .reanchor_policy(ReanchorPolicy {
+                    max_occupied: 2,
+                    min_hot_rest: 4,
+                })
+                .build();
+
+            m.extend((100..104).map(|n| (n, n)));
+            m.insert(0, 0);
+        });
+
+        let expected = vec![
+            ("evicted window", -100, 8),
+            ("refilled window", -100, 4),
+            ("shrank window", 10, 2),
+            ("evicted window", 0, 4),
+            ("reanchored window", 100, 5),
+        ];
+
+        let events = events.lock().unwrap();
+        let events = events.iter().map(|&(ref message, key, moved)| (&message[..], key, moved)).collect::<Vec<_>>();
+        assert_eq!(expected, events);
+    }
+
+    #[test]
+    fn dump() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 64]>::new();
+        m.extend(vec![0, 1, 30, 31, 33, 34, 38].into_iter().map(|n| (n, n)));
+        m.extend((100..110).map(|n| (n, n)));
+
+        assert_eq!(
+            "anchor: Some(0)
+window: 7 occupied in 39 of 64 slots [0 1 _(x28) 30 31 _ 33 34 _ _ _ 38]
+below: 0 []
+rest: 10 [100 101 102 … 107 108 109]
+",
+            m.dump()
+        );
+
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.insert(-100, 0);
+        m.insert(-99, 0);
+        assert!(m.dump().contains("\nbelow: 2 [-100 -99]\n"));
+    }
+
+    #[test]
+    fn tick_map() {
+        let mut m = TickMap::<[Option<(isize, &str)>; 8]>::new(100);
+        assert_eq!(None, m.schedule(0, "now"));
+        assert_eq!(None, m.schedule(3, "soon"));
+        assert_eq!(None, m.schedule(7, "edge"));
+        assert_eq!(None, m.schedule(8, "next window"));
+        assert_eq!(None, m.schedule(20, "later"));
+        assert_eq!(Some("edge"), m.schedule(7, "window edge"));
+        assert_eq!(None, m.schedule(30, "cancelled"));
+        assert_eq!(Some("cancelled"), m.cancel(130));
+        assert_eq!(5, m.len());
+        assert!(!in_top(m.map(), 108));
+        assert!(!in_top(m.map(), 120));
+
+        let mut fired = Vec::new();
+
+        for _ in 0..25 {
+            let tick = m.current_tick();
+
+            if tick == 104 {
+                m.schedule(2, "rescheduled");
+                *m.entry(0).or_insert("") = "same tick";
+            }
+
+            if let Some(value) = m.tick() {
+                fired.push((tick, value));
+            }
+
+            assert_eq!(tick + 1, m.current_tick());
+            assert_eq!(Some(tick + 1), m.map().stats().anchor);
+
+            // Everything due within the window has been promoted into it
+            assert!(m.map().iter().all(|(key, _)| in_top(m.map(), key) == (key < tick + 9)));
+        }
+
+        assert_eq!(
+            vec![
+                (100, "now"),
+                (103, "soon"),
+                (104, "same tick"),
+                (106, "rescheduled"),
+                (107, "window edge"),
+                (108, "next window"),
+                (120, "later"),
+            ],
+            fired
+        );
+
+        assert!(m.is_empty());
+        assert_eq!(125, m.current_tick());
+    }
+
+    #[test]
+    #[should_panic(expected = "tick runs past the range of the key type")]
+    fn tick_map_schedule_past_key_range() {
+        let mut m = TickMap::<[Option<(isize, &str)>; 8]>::new(100);
+        m.schedule(usize::MAX, "never");
+    }
+
+    #[test]
+    fn advance_to() {
+        let mut m = (0..300).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.remove(100);
+        m.reset_metrics();
+
+        assert_eq!(0, m.advance_to(-5));
+        assert_eq!(0, m.advance_to(0));
+        assert_eq!(TierMetrics::default(), m.metrics());
+
+        assert_eq!(100, m.advance_to(101));
+        assert_eq!(Some(101), m.stats().anchor);
+        assert_eq!(1, m.metrics().refills);
+        assert_eq!(199, m.len());
+
+        let drained = m.advance_to_drain(250).map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!((101..250).collect::<Vec<_>>(), drained);
+        assert_eq!(Some(250), m.stats().anchor);
+        assert_eq!([50, 50, 0], lens(&m));
+
+        assert_eq!(50, m.advance_to(1000));
+        assert_eq!(0, m.len());
+        assert_eq!(None, m.stats().anchor);
+    }
+
+    #[test]
+    fn far_apart_keys() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        m.insert(10, 1);
+        assert_eq!(None, m.insert(isize::MIN, 2));
+        assert_eq!(Some(2), m.insert(isize::MIN, 3));
+        assert_eq!(vec![(isize::MIN, 3), (10, 1)], m.to_vec());
+
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        m.insert(-10, 1);
+        assert_eq!(None, m.insert(isize::MAX, 2));
+        assert_eq!(Some(2), m.insert(isize::MAX, 3));
+        assert_eq!(Some(&3), m.get(isize::MAX));
+        assert_eq!(Some(3), m.remove(isize::MAX));
+        assert_eq!(None, m.remove(isize::MIN));
+        assert_eq!(vec![(-10, 1)], m.to_vec());
+
+        m.insert(isize::MAX, 4);
+        assert_eq!(1, m.advance_to(isize::MAX));
+        assert_eq!(vec![(isize::MAX, 4)], m.to_vec());
+        assert_eq!(Some(isize::MAX), m.stats().anchor);
+    }
+
+    #[test]
+    fn remove_range() {
+        fn keys_after<R: ops::RangeBounds<isize>>(range: R) -> (usize, Vec<isize>) {
+            let mut m = (0..20).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+            let count = m.remove_range(range);
+            (count, m.iter().map(|(key, _)| key).collect())
+        }
+
+        // Exactly the window, then straddling the window and rest
+        assert_eq!((8, (8..20).collect()), keys_after(0..8));
+        assert_eq!((6, (0..5).chain(11..20).collect()), keys_after(5..11));
+
+        // Empty ranges
+        assert_eq!((0, (0..20).collect()), keys_after(5..5));
+        assert_eq!((0, (0..20).collect()), keys_after((Bound::Excluded(5), Bound::Excluded(6))));
+        assert_eq!((0, (0..20).collect()), keys_after(100..));
+
+        // Each combination of bounds
+        assert_eq!((3, (0..3).chain(6..20).collect()), keys_after(3..6));
+        assert_eq!((4, (0..3).chain(7..20).collect()), keys_after(3..=6));
+        assert_eq!((2, (0..4).chain(6..20).collect()), keys_after((Bound::Excluded(3), Bound::Excluded(6))));
+        assert_eq!((3, (0..4).chain(7..20).collect()), keys_after((Bound::Excluded(3), Bound::Included(6))));
+        assert_eq!((6, (6..20).collect()), keys_after(..6));
+        assert_eq!((7, (7..20).collect()), keys_after(..=6));
+        assert_eq!((17, (0..3).collect()), keys_after(3..));
+        assert_eq!((16, (0..4).collect()), keys_after((Bound::Excluded(3), Bound::Unbounded)));
+        assert_eq!((20, vec![]), keys_after(..));
+
+        let mut m = (0..20).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        assert_eq!(8, m.remove_range(0..8));
+        assert_eq!(Some(8), m.stats().anchor);
+        assert_eq!([12, 4, 8], lens(&m));
+    }
+
+    #[test]
+    fn partition() {
+        let mut m = (0..300).map(|n| (n, n * 10)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.set_refill_margin(4);
+
+        let (odd, even) = m.partition(|key, &value| key % 2 == 1 && value > 0);
+        assert_eq!((1..300).step_by(2).map(|n| (n, n * 10)).collect::<Vec<_>>(), odd.to_vec());
+        assert_eq!((0..300).step_by(2).map(|n| (n, n * 10)).collect::<Vec<_>>(), even.to_vec());
+        assert_eq!((Some(1), Some(0)), (odd.stats().anchor, even.stats().anchor));
+        assert_eq!([150, 64, 86], lens(&odd));
+        assert_eq!([150, 64, 86], lens(&even));
+        assert_eq!((4, 4), (odd.refill_margin, even.refill_margin));
+
+        let (all, none) = odd.partition(|_, _| true);
+        assert_eq!(150, all.len());
+        assert_eq!(0, none.len());
+        assert_eq!(Ok(()), none.check());
+    }
+
+    #[test]
+    fn add() {
+        let mut m = (100..400).map(|n| (n, 1)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        assert_eq!(3, m.add(100, 2));
+        assert_eq!(5, m.add(100, 2));
+        assert_eq!(11, m.add(399, 10));
+        assert_eq!(-4, m.add(180, -5));
+        assert_eq!(7, m.add(1000, 7));
+        assert_eq!(1, m.add(90, 1));
+        assert_eq!(Some(90), m.stats().anchor);
+        assert_eq!(2, m.add(90, 1));
+        assert_eq!(302, m.len());
+
+        let mut f = TopMap::<[Option<(isize, f64)>; 8]>::new();
+        assert_eq!(0.5, f.add(-3, 0.5));
+        assert_eq!(vec![(-3, 0.5)], f.to_vec());
+    }
+
+    #[test]
+    fn checked_and_saturating_add() {
+        let mut m = (100..400).map(|n| (n, 250)).collect::<TopMap<[Option<(isize, u8)>; 128]>>();
+
+        assert_eq!(Some(255), m.checked_add(100, 5));
+        assert_eq!(None, m.checked_add(100, 1));
+        assert_eq!(None, m.checked_add(399, 6));
+        assert_eq!(Some(&250), m.get(399));
+        assert_eq!(255, m.saturating_add(399, 10));
+        assert_eq!(255, m.saturating_add(150, 255));
+
+        let mut s = TopMap::<[Option<(isize, i8)>; 8]>::new();
+        assert_eq!(Some(-100), s.checked_add(5, -100));
+        assert_eq!(None, s.checked_add(5, -100));
+        assert_eq!(-128, s.saturating_add(5, -100));
+        assert_eq!(Some(7), s.checked_add(1000, 7));
+        assert_eq!(127, s.saturating_add(-50, 127));
+        assert_eq!(vec![(-50, 127), (5, -128), (1000, 7)], s.to_vec());
+        assert_eq!(Some(-50), s.stats().anchor);
+    }
+
+    #[test]
+    fn insert_entry() {
+        let mut m = (100..400).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        for &key in [50, 100, 101, 200, 300, 500, -1000].iter() {
+            let mut entry = m.insert_entry(key, 1);
+            assert_eq!((key, 1), (entry.key(), *entry.get()));
+            *entry.get_mut() += 1;
+            assert_eq!(2, entry.insert(3));
+            *entry.into_mut() *= 10;
+            assert_eq!(Some(&30), m.get(key));
+        }
+
+        assert_eq!(Some(-1000), m.stats().anchor);
+        assert_eq!(303, m.len());
+        assert_eq!(Ok(()), m.check());
+
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        assert_eq!(7, *m.entry(7).insert_entry(7).get());
+        assert_eq!(8, *m.entry(7).insert_entry(8).get());
+        assert_eq!(vec![(7, 8)], m.to_vec());
+    }
+
+    #[test]
+    fn default_top_map() {
+        let mut rng = Rng(12345);
+        let mut counts = DefaultTopMap::<[Option<(isize, usize)>; 8]>::new();
+        let mut expected = BTreeMap::new();
+
+        for _ in 0..1000 {
+            let key = rng.below(40) as isize - 20;
+            counts[key] += 1;
+            *expected.entry(key).or_insert(0) += 1;
+        }
+
+        assert_eq!(expected.into_iter().collect::<Vec<_>>(), counts.to_vec());
+        assert_eq!(Some(-20), counts.stats().anchor);
+        assert_eq!(Ok(()), counts.check());
+
+        let mut m = counts.into_inner();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        let mut counts = DefaultTopMap::from(m);
+        counts[-1000] += 1;
+        assert_eq!((Some(-20), 1), (counts.stats().anchor, counts[-1000]));
+    }
+
+    #[test]
+    fn get_many() {
+        let mut m = (100..400).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.insert(-1000, -1000);
+        m.remove(150);
+        m.remove(301);
+
+        let keys = [100, 150, 399, -1000, 0, 100, 1000, 227, 228, isize::MIN, isize::MAX, 399, 302, 301, 300, 330];
+        assert_eq!(keys.iter().map(|&key| m.get(key)).collect::<Vec<_>>(), m.get_many(&keys));
+        assert_eq!(Some(&100), m.get_many(&keys)[5]);
+        assert!(m.get_many(&[]).is_empty());
+        assert_eq!(vec![None], TopMap::<[Option<(isize, isize)>; 8]>::new().get_many(&[1]));
+    }
+
+    // Only the happy path is defined behaviour; `cargo +nightly miri test get_unchecked` checks it under Miri.
+    #[test]
+    fn get_unchecked() {
+        let mut m = (100..400).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.remove(150);
+
+        let keys = m.iter().map(|(key, _)| key).take_while(|&key| in_top(&m, key)).collect::<Vec<_>>();
+        assert_eq!(127, keys.len());
+
+        for &key in keys.iter() {
+            unsafe {
+                *m.get_unchecked_mut(key) += 1;
+                assert_eq!(key + 1, *m.get_unchecked(key));
+            }
+        }
+
+        for (key, &value) in m.iter() {
+            assert_eq!(if keys.contains(&key) { key + 1 } else { key }, value);
+        }
+
+        // Pushing 9 onto the front wraps both ring buffers, so the keys either side sit in different halves
+        let mut d = TopMap::with_array(DynArray::new(16));
+        d.extend((10..30isize).map(|n| (n, n)));
+        d.remove(10);
+        d.insert(9, 9);
+
+        unsafe {
+            *d.get_unchecked_mut(24) *= 2;
+            assert_eq!((9, 48), (*d.get_unchecked(9), *d.get_unchecked(24)));
+        }
+
+        let mut f = (10..30).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 16]>>();
+        f.remove(10);
+        f.insert(9, 9);
+
+        unsafe {
+            *f.get_unchecked_mut(24) *= 2;
+            assert_eq!((9, 48), (*f.get_unchecked(9), *f.get_unchecked(24)));
+        }
+    }
+
+    #[test]
+    fn get_or_default() {
+        let m = (100..400).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        let stats = m.stats();
+
+        assert_eq!((Some(100), Some(399), None), (m.get_copied(100), m.get_copied(399), m.get_copied(0)));
+        assert_eq!((150, 0, 0), (m.get_or_default(150), m.get_or_default(0), m.get_or_default(1000)));
+        assert_eq!(stats, m.stats());
+        assert_eq!(300, m.len());
+    }
+
+    #[test]
+    fn update() {
+        let mut m = (100..400).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        let stats = m.stats();
+
+        assert!(m.update(100, |value| *value += 1));
+        assert!(m.update(399, |value| *value += 1));
+        assert!(!m.update(0, |_| panic!("above the window")));
+        assert!(!m.update(400, |_| panic!("past the last key")));
+        assert!(!m.update(1000, |_| panic!("far past the last key")));
+        assert_eq!((Some(&101), Some(&400)), (m.get(100), m.get(399)));
+        assert_eq!(stats, m.stats());
+        assert_eq!(300, m.len());
+
+        let mut empty = TopMap::<[Option<(isize, isize)>; 128]>::new();
+        assert!(!empty.update(5, |_| panic!("empty map")));
+        assert_eq!(None, empty.stats().anchor);
+    }
+
+    #[test]
+    fn apply_delta() {
+        let mut m = (100..400).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        let metrics = m.metrics();
+
+        m.apply_delta((100..150).map(|n| (n, None)).chain(vec![
+            (120, Some(-120)),
+            (90, Some(-90)),
+            (95, Some(-95)),
+            (95, None),
+            (399, None),
+            (500, Some(-500)),
+        ]));
+
+        let mut expected = vec![(90, -90), (120, -120)];
+        expected.extend((150..399).map(|n| (n, n)));
+        expected.push((500, -500));
+        assert_eq!(expected, m.to_vec());
+        assert_eq!(Some(90), m.stats().anchor);
+        assert!(m.metrics().refills <= metrics.refills + 1);
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let evicted2 = evicted.clone();
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::with_eviction_limit(3, move |key, _| {
+            evicted2.lock().unwrap().push(key)
+        });
+
+        m.apply_delta(vec![(1, Some(1)), (2, Some(2)), (3, Some(3)), (4, Some(4)), (2, None), (0, Some(0))]);
+        assert_eq!(vec![(0, 0), (1, 1), (3, 3)], m.to_vec());
+        assert_eq!(vec![4], *evicted.lock().unwrap());
+
+        let mut m = (0..4).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        m.apply_delta(vec![(isize::MIN, Some(1)), (isize::MAX, Some(2))]);
+        assert_eq!(vec![(isize::MIN, 1), (0, 0), (1, 1), (2, 2), (3, 3), (isize::MAX, 2)], m.to_vec());
+    }
+
+    #[test]
+    fn remove_many() {
+        let mut m = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        let metrics = m.metrics();
+
+        let keys = (0..100).chain(vec![50, 500, 2000]).collect::<Vec<_>>();
+        let mut expected = (0..100).map(Some).collect::<Vec<_>>();
+        expected.extend(vec![None, Some(500), None]);
+
+        assert_eq!(expected, m.remove_many(&keys));
+        assert_eq!(899, m.len());
+        assert_eq!(Some(100), m.stats().anchor);
+        assert!(m.metrics().refills <= metrics.refills + 1);
+        assert_eq!(Vec::<Option<isize>>::new(), m.remove_many(&[]));
+    }
+
+    #[test]
+    fn retain_ttl() {
+        let mut m = (0..1000).map(|n| (n, n % 5 + 1)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.shrink_to_min();
+
+        for round in 1..=5 {
+            let keys = m.iter().map(|(key, _)| key).collect::<Vec<_>>();
+            let mut visited = Vec::new();
+
+            m.retain(|key, ttl| {
+                visited.push(key);
+                *ttl -= 1;
+                *ttl > 0
+            });
+
+            let expected = (0..1000)
+                .filter(|n| n % 5 + 1 > round)
+                .map(|n| (n, n % 5 + 1 - round))
+                .collect::<Vec<_>>();
+
+            assert_eq!(keys, visited);
+            assert_eq!(expected, m.to_vec());
+            assert!(m.is_empty() || m.tier_of(m.to_vec()[0].0) == Some(Tier::Top { slot: 0 }));
+        }
+
+        assert!(m.is_empty());
+    }
+
+    #[test]
+    fn retain_clears_touch_marks() {
+        let mut m = [0, 2, 10, 11, 12, 13].iter().map(|&n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 4]>>();
+        m.set_demotion_policy(DemotionPolicy::SecondChance);
+        assert!(m.touch(0));
+        assert!(m.touch(2));
+        m.set_reanchor_policy(Some(ReanchorPolicy { max_occupied: 3, min_hot_rest: 2 }));
+        m.insert(14, 14);
+        assert!(m.touch(12));
+        m.demote(12);
+        assert_eq!(Some(Tier::Below), m.tier_of(2));
+        assert_eq!(Some(Tier::Rest), m.tier_of(12));
+
+        m.retain(|key, _| key != 2 && key != 12);
+        assert_eq!(vec![0], m.touched.iter().cloned().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn tier_observer() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut m = TopMap::<[Option<(isize, isize)>; 4]>::new();
+
+        {
+            let events = events.clone();
+            m.set_tier_observer(move |event| events.lock().unwrap().push(event));
+        }
+
+        m.extend((0..3).map(|n| (n, n)));
+        m.insert(5, 5);
+        m.insert(3, 3);
+        m.remove(2);
+        m.insert(2, 2);
+        assert_eq!(vec![TierEvent::WindowAnchored { key: 0 }], *events.lock().unwrap());
+
+        m.insert(-1, -1);
+        m.remove(-1);
+        m.remove(0);
+        m.remove(1);
+        m.demote(3);
+        m.promote(5);
+
+        assert_eq!(
+            vec![
+                TierEvent::WindowAnchored { key: 0 },
+                TierEvent::Demoted { key: 3 },
+                TierEvent::WindowReanchored { old: 0, new: -1 },
+                TierEvent::WindowReanchored { old: -1, new: 0 },
+                TierEvent::WindowReanchored { old: 0, new: 1 },
+                TierEvent::WindowReanchored { old: 1, new: 2 },
+                TierEvent::Promoted { key: 3 },
+                TierEvent::Demoted { key: 3 },
+                TierEvent::Promoted { key: 3 },
+                TierEvent::Promoted { key: 5 },
+            ],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn tier_observer_anchor() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut m = TopMap::<[Option<(isize, isize)>; 4]>::new();
+        m.insert(0, 0);
+        m.insert(10, 10);
+
+        {
+            let events = events.clone();
+            m.set_tier_observer(move |event| events.lock().unwrap().push(event));
+        }
+
+        m.remove(0);
+        m.clear();
+
+        assert_eq!(
+            vec![
+                TierEvent::WindowCleared { key: 0 },
+                TierEvent::WindowAnchored { key: 10 },
+                TierEvent::Promoted { key: 10 },
+                TierEvent::WindowCleared { key: 10 },
+            ],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn tier_guard() {
+        let mut m = (0..8).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 4]>>();
+        let (guard, iter) = m.iter_guarded();
+        let keys = iter.map(|(key, _)| key).collect::<Vec<_>>();
+
+        for &key in keys.iter() {
+            guard.check(&m);
+            m.insert(key, -key);
+        }
+
+        m.remove(2);
+        m.insert(2, 2);
+        guard.check(&m);
+    }
+
+    #[test]
+    #[cfg(all(feature = "debug-guards", debug_assertions))]
+    #[should_panic(expected = "taken (2 promotions, demotions or re-anchors)")]
+    fn tier_guard_reanchor() {
+        let mut m = (0..8).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 4]>>();
+        let (guard, iter) = m.iter_guarded();
+        let keys = iter.map(|(key, _)| key).collect::<Vec<_>>();
+        m.insert(-1, -1);
+
+        for &key in keys.iter() {
+            guard.check(&m);
+            m.get(key);
+        }
+    }
+
+    #[test]
+    fn tier_of() {
+        let mut m = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.remove(5);
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.insert(-1000, 0);
+
+        assert_eq!(Some(Tier::Below), m.tier_of(-1000));
+        assert_eq!(None, m.tier_of(-1));
+        assert_eq!(Some(Tier::Top { slot: 0 }), m.tier_of(0));
+        assert_eq!(None, m.tier_of(5));
+        assert_eq!(Some(Tier::Top { slot: 127 }), m.tier_of(127));
+        assert_eq!(Some(Tier::Rest), m.tier_of(128));
+        assert_eq!(None, m.tier_of(1000));
+
+        m.shrink_to(10);
+        assert_eq!(Some(Tier::Rest), m.tier_of(10));
+        assert!(m.iter().all(|(key, _)| m.tier_of(key).is_some()));
+        assert!(m.iter().all(|(key, _)| in_top(&m, key) == matches!(m.tier_of(key), Some(Tier::Top { .. }))));
+    }
+
+    #[test]
+    fn demotion_policy() {
+        let mut largest_first = (10..18).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        largest_first.set_capacity_limit(8);
+        let mut second_chance = largest_first.clone();
+        second_chance.set_demotion_policy(DemotionPolicy::SecondChance);
+
+        for m in [&mut largest_first, &mut second_chance].iter_mut() {
+            assert!(m.touch(17));
+            assert!(!m.touch(18));
+            m.insert(9, 9);
+            assert_eq!(8, m.len());
+            assert_eq!(Some(Tier::Top { slot: 0 }), m.tier_of(9));
+        }
+
+        assert_eq!((None, Some(&16)), (largest_first.get(17), largest_first.get(16)));
+        assert_eq!((Some(&17), None), (second_chance.get(17), second_chance.get(16)));
+
+        // The mark only lasts for one eviction
+        second_chance.insert(8, 8);
+        largest_first.insert(8, 8);
+        assert!(largest_first.iter().eq(second_chance.iter()));
+
+        // A new key larger than every unmarked key is evicted itself
+        assert!(second_chance.touch(15));
+        second_chance.insert(100, 100);
+        assert_eq!((None, Some(&15)), (second_chance.get(100), second_chance.get(15)));
+    }
+
+    #[test]
+    fn get_promoting() {
+        let mut m = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.shrink_to_min();
+        assert_eq!(Some(Tier::Rest), m.tier_of(100));
+
+        *m.get_promoting(100).unwrap() += 1;
+        assert_eq!(Some(Tier::Top { slot: 100 }), m.tier_of(100));
+        assert_eq!(Some(&mut 101), m.get_promoting(100));
+        assert_eq!(Some(Tier::Top { slot: 99 }), m.tier_of(99));
+        assert_eq!(Some(Tier::Rest), m.tier_of(101));
+
+        assert_eq!(Some(&mut 128), m.get_promoting(128));
+        assert_eq!(Some(Tier::Rest), m.tier_of(128));
+        assert_eq!(None, m.get_promoting(1000));
+        assert_eq!(1000, m.len());
+    }
+
+    #[test]
+    fn cow_clone() {
+        let mut m1 = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        let mut m2 = m1.cow_clone();
+        assert!(ptr::eq(&*m1.rest, &*m2.rest));
+
+        m1.insert(5, -5);
+        m2.remove(10);
+        assert_eq!(None, m2.remove(5000));
+        assert_eq!(Some(&900), m2.get(900));
+        assert!(ptr::eq(&*m1.rest, &*m2.rest));
+
+        m2.insert(900, -900);
+        assert!(!ptr::eq(&*m1.rest, &*m2.rest));
+        assert_eq!((Some(&900), Some(&-900)), (m1.get(900), m2.get(900)));
+        assert_eq!((Some(&-5), Some(&5)), (m1.get(5), m2.get(5)));
+        assert_eq!((Some(&10), None), (m1.get(10), m2.get(10)));
+
+        let m3 = m1.clone();
+        assert!(ptr::eq(&*m1.rest, &*m3.rest));
+        drop(m3);
+
+        let rest: *const _ = &*m1.rest;
+        m1.remove(800);
+        assert!(ptr::eq(rest, &*m1.rest));
+        assert_eq!(Some(&800), m2.get(800));
+
+        let m4 = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        assert!(matches!(m4.clone().rest, RestTier::Owned(_)));
+    }
+
+    #[test]
+    fn checkpoint() {
+        let mut m = (100..400).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        let original = m.to_vec();
+
+        let cp = m.checkpoint();
+        m.insert(100, 0);
+        m.insert(50, 50);
+        m.insert(1000, 1000);
+        m.remove(101);
+        m.remove(300);
+        m.add(200, 5);
+        m[150] = -150;
+        m.remove_range(350..360);
+        m.advance_to(105);
+        assert_eq!(20, m.undo.as_ref().unwrap().entries.len());
+        m.rollback(cp);
+
+        assert_eq!(original, m.to_vec());
+        assert_eq!(Ok(()), m.check());
+        assert!(m.undo.is_none());
+
+        let cp = m.checkpoint();
+        m.insert(0, 0);
+        m.commit(cp);
+        assert_eq!(301, m.len());
+
+        let _cp = m.checkpoint();
+        assert_eq!(0, m.undo.as_ref().unwrap().entries.len());
+        m.clear();
+        assert_eq!(301, m.undo.as_ref().unwrap().entries.len());
+    }
+
+    #[test]
+    fn rollback_at_eviction_limit() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+
+        let mut m = {
+            let evicted = evicted.clone();
+            TopMap::<[Option<(isize, isize)>; 8]>::with_eviction_limit(2, move |key, _| {
+                evicted.lock().unwrap().push(key);
+            })
+        };
+
+        m.insert(1, 1);
+        m.insert(10, 10);
+        let cp = m.checkpoint();
+        m.remove(10);
+        m.insert(2, 2);
+        m.rollback(cp);
+        assert_eq!(vec![(1, 1), (10, 10)], m.to_vec());
+
+        m.remove(10);
+        m.insert(2, 2);
+        let cp = m.checkpoint();
+        m.remove(2);
+        m.insert(3, 3);
+        m.rollback(cp);
+        assert_eq!(vec![(1, 1), (2, 2)], m.to_vec());
+        assert_eq!(Vec::<isize>::new(), *evicted.lock().unwrap());
+
+        m.set_capacity_limit(2);
+        let cp = m.checkpoint();
+        m.remove(2);
+        assert_eq!(None, m.insert_bounded(3, 3));
+        m.rollback(cp);
+        assert_eq!(vec![(1, 1), (2, 2)], m.to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "no item with key 42 (window 0..128, len 998, nearest existing: 41 and 43)")]
+    fn index_missing_key() {
+        let m = (0..1000)
+            .filter(|&n| n != 42 && n != 500)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        let _ = m[42];
+    }
+
+    #[test]
+    #[should_panic(expected = "no item with key 500 (window 0..128, len 998)")]
+    fn index_mut_missing_key() {
+        let mut m = (0..1000)
+            .filter(|&n| n != 42 && n != 500)
+            .map(|n| (n, n))
+            .collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+        m[500] += 1;
+    }
+
+    #[test]
+    #[should_panic(expected = "no item with key 0 (window 9223372036854775806.. (2 slots), len 2, nearest existing")]
+    fn index_missing_key_at_max() {
+        let m = vec![(isize::MAX - 1, 0), (isize::MAX, 0)].into_iter().collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let _ = m[0];
+    }
+
+    #[test]
+    #[should_panic(expected = "no item with key 5 (no window, len 0, nearest existing: none and none)")]
+    fn index_empty() {
+        let _ = TopMap::<[Option<(isize, isize)>; 64]>::new()[5];
+    }
+
+    #[test]
+    #[should_panic(expected = "a checkpoint is already active")]
+    fn nested_checkpoint() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        let _outer = m.checkpoint();
+        let _inner = m.checkpoint();
+    }
+
+    #[test]
+    fn map_values() {
+        let mut m = (0..300)
+            .filter(|n| n % 3 != 0)
+            .map(|n| (n, n as u8))
+            .collect::<TopMap<[Option<(isize, u8)>; 128]>>();
+
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.insert(-1000, 7);
+
+        let stats = m.stats();
+        let slots = m.iter_slots().map(|(key, value)| (key, value.is_some())).collect::<Vec<_>>();
+        let m2 = m.map_values::<[Option<(isize, String)>; 128], _>(|key, value| format!("{}:{}", key, value));
+
+        assert_eq!(stats, m2.stats());
+        assert_eq!(slots, m2.iter_slots().map(|(key, value)| (key, value.is_some())).collect::<Vec<_>>());
+        assert_eq!(Some(&"-1000:7".to_string()), m2.get(-1000));
+        assert_eq!(Some(&"299:43".to_string()), m2.get(299));
+        assert_eq!(Ok(()), m2.check());
+
+        let d = (0..50isize).map(|n| (n, n)).collect::<Vec<_>>();
+        let mut m3 = TopMap::with_array(DynArray::new(16));
+        m3.extend(d.iter().cloned());
+        let m4 = m3.map_values_with_array(DynArray::new(16), |_, value| value * 2);
+        assert_eq!(d.iter().map(|&(key, value)| (key, value * 2)).collect::<Vec<_>>(), m4.to_vec());
+        assert_eq!([50, 16, 34], lens(&m4));
+    }
+
+    #[test]
+    fn merge_with() {
+        let mut m1 = (0..200).map(|n| (n, 1)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        let m2 = (100..300).map(|n| (n, 10)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        let mut conflicts = Vec::new();
+
+        m1.merge_with(m2, |key, mine, theirs| {
+            conflicts.push(key);
+            mine + theirs
+        });
+
+        assert_eq!((100..200).collect::<Vec<_>>(), conflicts);
+        assert_eq!(300, m1.len());
+        assert_eq!(Ok(()), m1.check());
+        assert_eq!(Some(0), m1.stats().anchor);
+        assert_eq!((1, 11, 10), (m1[99], m1[100], m1[200]));
+        assert_eq!(100 + 1100 + 1000, m1.iter().map(|(_, &value)| value).sum::<isize>());
+
+        m1.merge_with(TopMap::new(), |_, _, _| unreachable!());
+        assert_eq!(300, m1.len());
+    }
+
+    #[test]
+    fn set_ops() {
+        let m1 = (0..20).map(|n| (n * 3, n)).collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let m2 = (0..20).map(|n| (n * 2, ())).collect::<TopMap<[Option<(isize, ())>; 128]>>();
+
+        assert_eq!(vec![0, 6, 12, 18, 24, 30, 36], m1.intersection(&m2).collect::<Vec<_>>());
+        assert_eq!(vec![3, 9, 15, 21, 27, 33], m1.difference(&m2).take(6).collect::<Vec<_>>());
+        assert_eq!(vec![0, 2, 3, 4, 6, 8, 9, 10], m1.union(&m2).take(8).collect::<Vec<_>>());
+        assert_eq!(20 + 20 - 7, m1.union(&m2).count());
+        assert_eq!(0, m1.difference(&m1).count());
+
+        assert_eq!(
+            vec![(2, Either::Right(&())), (3, Either::Left(&1)), (4, Either::Right(&()))],
+            m1.symmetric_difference(&m2).take(3).collect::<Vec<_>>()
+        );
+        assert_eq!(0, m1.symmetric_difference(&m1).count());
+
+        let m3 = (0..6).map(|n| (n * 6, n.to_string())).collect::<TopMap<[Option<(isize, String)>; 16]>>();
+        assert!(m3.is_subset_keys(&m1) && m3.is_subset_keys(&m2) && !m1.is_subset_keys(&m3));
+        assert!(m1.eq_keys(&m1) && !m1.eq_keys(&m2) && !m3.eq_keys(&m1));
+        assert!(m1.is_subset_keys(&m1) && TopMap::<[Option<(isize, ())>; 8]>::new().is_subset_keys(&m3));
+    }
+
+    #[test]
+    fn join() {
+        let m1 = (0..10).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let disjoint = (20..30).map(|n| (n, n.to_string())).collect::<TopMap<[Option<(isize, String)>; 128]>>();
+        let nested = (3..6).map(|n| (n, ())).collect::<TopMap<[Option<(isize, ())>; 4]>>();
+        let interleaved = (0..10).map(|n| (n * 2 + 1, n as u8)).collect::<TopMap<[Option<(isize, u8)>; 8]>>();
+
+        let j = m1.join(&disjoint).collect::<Vec<_>>();
+        assert_eq!(20, j.len());
+        assert_eq!((0, Some(&0), None), j[0]);
+        assert_eq!((20, None, Some(&"20".to_string())), j[10]);
+        assert_eq!(0, m1.inner_join(&disjoint).count());
+
+        assert_eq!(
+            vec![(2, Some(&2), None), (3, Some(&3), Some(&())), (4, Some(&4), Some(&())), (5, Some(&5), Some(&()))],
+            m1.join(&nested).skip(2).take(4).collect::<Vec<_>>()
+        );
+        assert_eq!(vec![(3, &3, &()), (4, &4, &()), (5, &5, &())], m1.inner_join(&nested).collect::<Vec<_>>());
+
+        assert_eq!(
+            vec![(8, Some(&8), None), (9, Some(&9), Some(&4)), (11, None, Some(&5))],
+            m1.join(&interleaved).skip(8).take(3).collect::<Vec<_>>()
+        );
+        assert_eq!(vec![1, 3, 5, 7, 9], m1.inner_join(&interleaved).map(|(key, _, _)| key).collect::<Vec<_>>());
+        assert_eq!(15, interleaved.join(&m1).count());
+    }
+
+    #[test]
+    fn drain_top() {
+        let mut m = (0..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+        m.remove(5);
+
+        {
+            let mut drained = m.drain_top();
+            assert_eq!(Some((0, 0)), drained.next());
+        }
+
+        assert_eq!([872, 0, 872], lens(&m));
+        assert_eq!(None, m.get(1));
+        assert_eq!(Some(&128), m.get(128));
+
+        m.insert(50, 50);
+        assert_eq!(Some(50), m.stats().anchor);
+        assert_eq!(vec![(50, 50)], m.drain_top().collect::<Vec<_>>());
+        assert_eq!([872, 0, 872], lens(&m));
+    }
+
+    #[test]
+    fn parts() {
+        type M = TopMap<[Option<(isize, isize)>; 128]>;
+
+        let mut m = (0..1000).map(|n| (n, n)).collect::<M>();
+        m.shrink_to_min();
+        m.remove(10);
+        let stats = m.stats();
+
+        let (top, rest) = m.into_parts();
+        assert_eq!(64, top.len());
+        assert_eq!(Some(&(11, 11)), top.get(10));
+        assert_eq!(935, rest.len());
+
+        let m = M::from_parts(top, rest).unwrap();
+        assert_eq!(stats, m.stats());
+        assert_eq!(None, m.get(10));
+        assert_eq!(63, m[63]);
+        assert_eq!(64, m[64]);
+
+        let (top, rest) = m.into_parts();
+        assert_eq!(
+            (0..1000).filter(|&n| n != 10).map(|n| (n, n)).collect::<Vec<_>>(),
+            top.into_iter().chain(rest).collect::<Vec<_>>()
+        );
+
+        // The round trip re-anchors at the first window entry, dropping the leading holes
+        let mut m = M::with_first_key(-5);
+        m.extend((0..10).map(|n| (n, n)));
+        assert_eq!(5, m.stats().leading_holes);
+        let (top, rest) = m.into_parts();
+        assert_eq!(Some(0), M::from_parts(top, rest).unwrap().stats().anchor);
+
+        let rest = (5..10).map(|n| (n, n)).collect::<BTreeMap<_, _>>();
+        assert_eq!(Some(InvalidParts::UnsortedTop), M::from_parts(vec![(1, 1), (0, 0)], BTreeMap::new()).err());
+        assert_eq!(Some(InvalidParts::TopTooWide), M::from_parts(vec![(0, 0), (128, 128)], BTreeMap::new()).err());
+        let top = vec![(isize::MIN, 0), (isize::MAX, 0)];
+        assert_eq!(Some(InvalidParts::TopTooWide), M::from_parts(top, BTreeMap::new()).err());
+        assert_eq!(Some(InvalidParts::RestOverlapsTop), M::from_parts(vec![(0, 0), (5, 5)], rest.clone()).err());
+        assert_eq!([5, 0, 5], lens(&M::from_parts(Vec::new(), rest).unwrap()));
+    }
+
+    #[test]
+    fn reanchor() {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        m.set_reanchor_policy(Some(ReanchorPolicy {
+            max_occupied: 2,
+            min_hot_rest: 4,
+        }));
+
+        m.insert(0, 0);
+
+        for n in 100..103 {
+            m.insert(n, n);
+        }
+
+        assert_eq!(Some(0), m.stats().anchor);
+        assert_eq!([4, 1, 3], lens(&m));
+
+        m.insert(103, 103);
+        assert_eq!(Some(100), m.stats().anchor);
+        assert_eq!([5, 4, 1], lens(&m));
+        assert_eq!(1, m.metrics().reanchors);
+
+        for n in 104..110 {
+            m.insert(n, n);
+        }
+
+        assert_eq!([11, 8, 3], lens(&m));
+        assert!(in_top(&m, 107));
+        assert_eq!(0, m[0]);
+
+        let keys = m.iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(vec![0, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109], keys);
+
+        assert_eq!(None, m.insert(-5, -5));
+        assert_eq!(Some(-5), m.stats().anchor);
+        assert_eq!([12, 1, 11], lens(&m));
+        assert_eq!(Some(0), m.remove(0));
+        assert_eq!(None, m.get(0));
+        assert_eq!(Some(100), m.stats().anchor);
+        assert_eq!(2, m.metrics().reanchors);
+        assert_eq!(-5, m[-5]);
+
+        let keys = m.iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(vec![-5, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109], keys);
+    }
+
+    #[test]
+    fn insert_outside_top() {
+        let mut map: TopMap<[Option<(isize, isize)>; 128]> = TopMap::new();
+        assert_eq!(None, map.insert(-63, 93));
+        assert_eq!(93, map[-63]);
+
+        assert_eq!(None, map.insert(87, 14));
+        assert_eq!(93, map[-63]);
+        assert_eq!(14, map[87]);
+
+        assert_eq!(None, map.insert(0, 45));
+        assert_eq!(93, map[-63]);
+        assert_eq!(14, map[87]);
+        assert_eq!(45, map[0]);
+
+        assert_eq!(Some(93), map.remove(-63));
+        assert_eq!(None, map.get(-63));
+        assert_eq!(14, map[87]);
+        assert_eq!(45, map[0]);
+
+        assert_eq!(Some(14), map.insert(87, 14));
+        assert_eq!(None, map.get(-63));
+        assert_eq!(14, map[87]);
+        assert_eq!(45, map[0]);
+    }
+
+    #[derive(Clone, Debug)]
+    enum Action<Key, Value> {
+        Insert { key: Key, value: Value },
+        Remove { key: Key },
+        Get { key: Key },
+        EntryDrop { key: Key },
+        EntryOrInsert { key: Key, value: Value },
+        EntryOrInsertWith { key: Key, value: Value },
+        Len,
+        Iter,
+        ShrinkToFit,
+    }
+
+    impl<Key: Arbitrary, Value: Arbitrary> Arbitrary for Action<Key, Value> {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            match u8::arbitrary(g) % 11 {
+                0 | 1 => Action::Insert {
+                    key: Key::arbitrary(g),
+                    value: Value::arbitrary(g),
+                },
+                2 | 3 => Action::Remove { key: Key::arbitrary(g) },
+                4 => Action::Get { key: Key::arbitrary(g) },
+                5 => Action::EntryDrop { key: Key::arbitrary(g) },
+                6 => Action::EntryOrInsert {
+                    key: Key::arbitrary(g),
+                    value: Value::arbitrary(g),
+                },
+                7 => Action::EntryOrInsertWith {
+                    key: Key::arbitrary(g),
+                    value: Value::arbitrary(g),
+                },
+                8 => Action::Len,
+                9 => Action::Iter,
+                10 => Action::ShrinkToFit,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn matches_btree_map<A>(actions: Vec<Action<isize, isize>>) -> bool
+    where
+        A: Array<Key = isize, Value = isize>,
+    {
+        matches_btree_map_from(TopMap::<A>::new(), actions)
+    }
+
+    fn matches_btree_map_from<A>(map2: TopMap<A>, actions: Vec<Action<isize, isize>>) -> bool
+    where
+        A: Layout<Key = isize, Value = isize>,
+    {
+        matches_btree_map_seeded(BTreeMap::new(), map2, actions)
+    }
+
+    fn matches_btree_map_seeded<A>(
+        mut map1: BTreeMap<isize, isize>,
+        mut map2: TopMap<A>,
+        actions: Vec<Action<isize, isize>>,
+    ) -> bool
+    where
+        A: Layout<Key = isize, Value = isize>,
+    {
+        for action in actions {
+            match action {
+                Action::Insert { key, value } => if map1.insert(key, value) != map2.insert(key, value) {
+                    return false;
+                },
+
+                Action::Remove { key } => if map1.remove(&key) != map2.remove(key) {
+                    return false;
+                },
 
                 Action::Get { key } => if map1.get(&key) != map2.get(key) {
                     return false;
                 },
+
+                Action::EntryDrop { key } => {
+                    let stats = map2.stats();
+
+                    if map1.contains_key(&key) != map2.entry(key).is_occupied() || map2.stats() != stats {
+                        return false;
+                    }
+                }
+
+                Action::EntryOrInsert { key, value } => {
+                    if *map1.entry(key).or_insert(value) != *map2.entry(key).or_insert(value) {
+                        return false;
+                    }
+                }
+
+                Action::EntryOrInsertWith { key, value } => {
+                    let expected_calls = if map1.contains_key(&key) { 0 } else { 1 };
+                    let mut calls = 0;
+
+                    let inserted = *map2.entry(key).or_insert_with(|| {
+                        calls += 1;
+                        value
+                    });
+
+                    if calls != expected_calls || *map1.entry(key).or_insert(value) != inserted {
+                        return false;
+                    }
+                }
+
+                Action::Len => if map1.len() != map2.len() || map1.is_empty() != map2.is_empty() {
+                    return false;
+                },
+
+                Action::Iter => if !map1.iter().map(|(&key, value)| (key, value)).eq(map2.iter()) {
+                    return false;
+                },
+
+                Action::ShrinkToFit => map2.shrink_to_fit(),
+            }
+
+            if map2.occupied != (0..map2.top.len()).filter(|&index| map2.top[index].is_some()).count() {
+                return false;
+            }
+        }
+
+        map2.iter().all(|(key, value)| map2.get(key) == Some(value))
+            && map1.into_iter().collect::<Vec<_>>() == map2.to_vec()
+    }
+
+    // Spreads the small keys quickcheck generates over the whole `isize` range, so offsets from the anchor overflow.
+    fn spread_keys(actions: Vec<Action<isize, isize>>) -> Vec<Action<isize, isize>> {
+        let spread = |key: isize| key.saturating_mul(isize::MAX / 50);
+
+        actions
+            .into_iter()
+            .map(|action| match action {
+                Action::Insert { key, value } => Action::Insert { key: spread(key), value },
+                Action::Remove { key } => Action::Remove { key: spread(key) },
+                Action::Get { key } => Action::Get { key: spread(key) },
+                Action::EntryDrop { key } => Action::EntryDrop { key: spread(key) },
+                Action::EntryOrInsert { key, value } => Action::EntryOrInsert { key: spread(key), value },
+                Action::EntryOrInsertWith { key, value } => Action::EntryOrInsertWith { key: spread(key), value },
+                Action::Len => Action::Len,
+                Action::Iter => Action::Iter,
+                Action::ShrinkToFit => Action::ShrinkToFit,
+            })
+            .collect()
+    }
+
+    fn iter_ascends<A>(actions: Vec<Action<isize, isize>>) -> bool
+    where
+        A: Array<Key = isize, Value = isize>,
+    {
+        let mut map = TopMap::<A>::new();
+
+        for action in actions {
+            match action {
+                Action::Insert { key, value } => {
+                    map.insert(key, value);
+                }
+
+                Action::Remove { key } => {
+                    map.remove(key);
+                }
+
+                Action::Get { key } => {
+                    map.get(key);
+                }
+
+                Action::EntryDrop { key } => {
+                    map.entry(key);
+                }
+
+                Action::EntryOrInsert { key, value } | Action::EntryOrInsertWith { key, value } => {
+                    map.entry(key).or_insert_with(|| value);
+                }
+
+                Action::Len | Action::Iter => {}
+                Action::ShrinkToFit => map.shrink_to_fit(),
+            }
+
+            let keys = map.iter().map(|(key, _)| key).collect::<Vec<_>>();
+
+            if keys.windows(2).any(|pair| pair[0] >= pair[1])
+                || !map.iter_mut().map(|(key, _)| key).eq(keys.iter().cloned())
+            {
+                return false;
             }
         }
 
         true
     }
 
+    fn matches_btree_set<A>(actions: Vec<Action<isize, ()>>) -> bool
+    where
+        A: Array<Key = isize, Value = ()>,
+    {
+        let mut set1 = BTreeSet::new();
+        let mut set2 = TopSet::<A>::new();
+
+        for action in actions {
+            match action {
+                Action::Insert { key, .. } => if set1.insert(key) != set2.insert(key) {
+                    return false;
+                },
+
+                Action::Remove { key } => if set1.remove(&key) != set2.remove(key) {
+                    return false;
+                },
+
+                Action::Get { key } => if set1.contains(&key) != set2.contains(key) {
+                    return false;
+                },
+
+                Action::EntryDrop { key } => {
+                    let stats = set2.map.stats();
+
+                    if set1.contains(&key) != set2.map.entry(key).is_occupied() || set2.map.stats() != stats {
+                        return false;
+                    }
+                }
+
+                Action::EntryOrInsert { key, .. } | Action::EntryOrInsertWith { key, .. } => {
+                    set1.insert(key);
+                    set2.map.entry(key).or_insert(());
+                }
+
+                Action::Len => if set1.is_empty() != set2.is_empty() {
+                    return false;
+                },
+
+                Action::Iter => if !set1.iter().cloned().eq(set2.iter()) {
+                    return false;
+                },
+
+                Action::ShrinkToFit => set2.map.shrink_to_fit(),
+            }
+
+            if set1.len() != set2.len() {
+                return false;
+            }
+        }
+
+        set1.into_iter().eq(set2.iter())
+    }
+
+    fn collect_matches_inserts(mut items: Vec<(isize, isize)>, tail: Vec<(isize, isize)>) -> bool {
+        items.sort_by_key(|&(key, _)| key);
+
+        // Descending runs are loaded smallest key first, so only sorted input is sure to lay out exactly like repeated
+        // inserts; otherwise just the contents have to match.
+        let same_layout = tail.is_empty();
+        items.extend(tail);
+
+        let collected = items.iter().cloned().collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let mut inserted = TopMap::<[Option<(isize, isize)>; 8]>::new();
+
+        for &(key, value) in items.iter() {
+            inserted.insert(key, value);
+        }
+
+        (!same_layout || collected.stats() == inserted.stats() && lens(&collected) == lens(&inserted))
+            && collected.len() == inserted.len()
+            && collected.iter().eq(inserted.iter())
+            && collected.occupied == (0..collected.top.len()).filter(|&index| collected.top[index].is_some()).count()
+            && collected.rest_min == collected.rest.keys().next().cloned()
+    }
+
+    fn collect_descending_matches_btree_map(mut items: Vec<(isize, isize)>) -> bool {
+        items.sort_by_key(|&(key, _)| cmp::Reverse(key));
+        let m = items.iter().cloned().collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let b = items.into_iter().collect::<BTreeMap<_, _>>();
+        m.len() == b.len() && b.into_iter().eq(m.iter().map(|(key, &value)| (key, value)))
+    }
+
+    fn append_matches_btree_map(items1: Vec<(isize, isize)>, items2: Vec<(isize, isize)>) -> bool {
+        let mut m1 = items1.iter().cloned().collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let mut m2 = items2.iter().cloned().collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let mut b1 = items1.into_iter().collect::<BTreeMap<_, _>>();
+        let mut b2 = items2.into_iter().collect::<BTreeMap<_, _>>();
+        m1.append(&mut m2);
+        b1.append(&mut b2);
+        m2.is_empty() && m1.len() == b1.len() && b1.into_iter().eq(m1.iter().map(|(key, &value)| (key, value)))
+    }
+
+    fn insert_sorted_iter_matches_btree_map(items: Vec<(isize, isize)>, tail: Vec<(isize, isize)>) -> bool {
+        let mut m = items.iter().cloned().collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let mut b = items.into_iter().collect::<BTreeMap<_, _>>();
+        let start = b.keys().next_back().map_or(0, |&key| key + 1);
+        let mut tail = tail.into_iter().map(|(key, value)| (start + key.abs(), value)).collect::<Vec<_>>();
+        tail.sort_by_key(|&(key, _)| key);
+        tail.dedup_by_key(|&mut (key, _)| key);
+
+        m.insert_sorted_iter(tail.iter().cloned());
+        b.extend(tail);
+        m.len() == b.len() && b.into_iter().eq(m.iter().map(|(key, &value)| (key, value)))
+    }
+
+    fn select_nth_matches_iter(items: Vec<(isize, isize)>, removed: Vec<isize>) -> bool {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.extend(items);
+
+        for key in removed {
+            m.remove(key);
+        }
+
+        (0..m.len() + 2).all(|n| m.select_nth(n) == m.iter().nth(n))
+    }
+
+    fn count_range_matches_btree_map(
+        items: Vec<(isize, isize)>,
+        removed: Vec<isize>,
+        probes: Vec<(isize, isize)>,
+    ) -> bool {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        let mut b = BTreeMap::new();
+
+        for (key, value) in items {
+            m.insert(key, value);
+            b.insert(key, value);
+        }
+
+        for key in removed {
+            m.remove(key);
+            b.remove(&key);
+        }
+
+        probes.into_iter().all(|(start, end)| {
+            let (start, end) = (cmp::min(start, end), cmp::max(start, end));
+
+            m.rank(start) == b.range(..start).count()
+                && m.count_range(start..end) == b.range(start..end).count()
+                && m.count_range(start..=end) == b.range(start..=end).count()
+                && m.count_range((Bound::Excluded(start), Bound::Unbounded))
+                    == b.range((Bound::Excluded(start), Bound::Unbounded)).count()
+                && m.count_range(..) == b.len()
+                && {
+                    let mut visited = Vec::new();
+                    m.for_each_range_mut(start..=end, |key, _| visited.push(key));
+                    visited.into_iter().eq(b.range(start..=end).map(|(&key, _)| key))
+                }
+        })
+    }
+
+    fn advance_to_matches_btree_map(items: Vec<(isize, isize)>, keys: Vec<isize>) -> bool {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        let mut b = BTreeMap::new();
+
+        for (key, value) in items {
+            m.insert(key, value);
+            b.insert(key, value);
+        }
+
+        keys.into_iter().all(|key| {
+            let upper = b.split_off(&key);
+            let removed = mem::replace(&mut b, upper).into_iter().collect::<Vec<_>>();
+            m.advance_to_drain(key).eq(removed) && m.to_vec() == b.clone().into_iter().collect::<Vec<_>>()
+        })
+    }
+
+    fn remove_range_matches_btree_map(items: Vec<(isize, isize)>, probes: Vec<(isize, isize, u8)>) -> bool {
+        let mut m = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        let mut b = BTreeMap::new();
+
+        for (key, value) in items {
+            m.insert(key, value);
+            b.insert(key, value);
+        }
+
+        probes.into_iter().all(|(start, end, kind)| {
+            let (start, end) = (cmp::min(start, end), cmp::max(start, end));
+
+            let range = match kind % 4 {
+                0 => (Bound::Included(start), Bound::Excluded(end)),
+                1 => (Bound::Included(start), Bound::Included(end)),
+                2 => (Bound::Excluded(start), Bound::Included(end)),
+                _ => (Bound::Unbounded, Bound::Included(end)),
+            };
+
+            let removed = b.range(range).map(|(&key, _)| key).collect::<Vec<_>>();
+
+            for key in removed.iter() {
+                b.remove(key);
+            }
+
+            m.remove_range(range) == removed.len() && m.to_vec() == b.clone().into_iter().collect::<Vec<_>>()
+        })
+    }
+
+    fn partition_matches_input(items: Vec<(isize, isize)>, modulus: isize) -> bool {
+        let m = items.iter().cloned().collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let expected = m.to_vec();
+        let modulus = modulus.abs() % 5 + 1;
+        let (accepted, rejected) = m.partition(|key, _| key % modulus == 0);
+
+        let mut union = accepted.to_vec();
+        union.extend(rejected.to_vec());
+        union.sort();
+
+        accepted.check().is_ok()
+            && rejected.check().is_ok()
+            && accepted.iter().all(|(key, _)| key % modulus == 0)
+            && rejected.iter().all(|(key, _)| key % modulus != 0)
+            && union == expected
+    }
+
+    fn set_ops_match_btree_set(keys1: Vec<isize>, keys2: Vec<isize>) -> bool {
+        let m1 = keys1.iter().map(|&key| (key, ())).collect::<TopMap<[Option<(isize, ())>; 8]>>();
+        let m2 = keys2.iter().map(|&key| (key, ())).collect::<TopMap<[Option<(isize, ())>; 128]>>();
+        let s1 = keys1.into_iter().collect::<BTreeSet<_>>();
+        let s2 = keys2.into_iter().collect::<BTreeSet<_>>();
+
+        m1.union(&m2).eq(s1.union(&s2).cloned())
+            && m1.intersection(&m2).eq(s1.intersection(&s2).cloned())
+            && m1.difference(&m2).eq(s1.difference(&s2).cloned())
+            && m2.difference(&m1).eq(s2.difference(&s1).cloned())
+            && m1.symmetric_difference(&m2).map(|(key, _)| key).eq(s1.symmetric_difference(&s2).cloned())
+            && m1.symmetric_difference(&m2).all(|(key, side)| match side {
+                Either::Left(_) => s1.contains(&key),
+                Either::Right(_) => s2.contains(&key),
+            })
+            && m1.eq_keys(&m2) == (s1 == s2)
+            && m1.is_subset_keys(&m2) == s1.is_subset(&s2)
+            && m2.is_subset_keys(&m1) == s2.is_subset(&s1)
+    }
+
+    fn join_matches_btree_map(items1: Vec<(isize, isize)>, items2: Vec<(isize, u8)>) -> bool {
+        let m1 = items1.iter().cloned().collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let m2 = items2.iter().cloned().collect::<TopMap<[Option<(isize, u8)>; 128]>>();
+        let b1 = items1.into_iter().collect::<BTreeMap<_, _>>();
+        let b2 = items2.into_iter().collect::<BTreeMap<_, _>>();
+        let keys = b1.keys().chain(b2.keys()).cloned().collect::<BTreeSet<_>>();
+
+        m1.join(&m2).eq(keys.iter().map(|key| (*key, b1.get(key), b2.get(key))))
+            && m1.inner_join(&m2).eq(b1.iter().filter_map(|(key, value)| Some((*key, value, b2.get(key)?))))
+    }
+
+    fn rollback_restores_contents(
+        mut m: TopMap<[Option<(isize, isize)>; 8]>,
+        items: Vec<(isize, isize)>,
+        actions: Vec<Action<isize, isize>>,
+        range: (isize, isize),
+    ) -> bool {
+        m.set_far_below_policy(FarBelowPolicy::KeepWindow);
+        m.extend(items);
+        let original = m.to_vec();
+        let cp = m.checkpoint();
+
+        for action in actions {
+            match action {
+                Action::Insert { key, value } => {
+                    m.insert(key, value);
+                }
+
+                Action::Remove { key } => {
+                    m.remove(key);
+                }
+
+                Action::Get { key } => {
+                    if let Some(value) = m.get_mut(key) {
+                        *value += 1;
+                    }
+                }
+
+                Action::EntryDrop { key } => {
+                    m.entry(key);
+                }
+
+                Action::EntryOrInsert { key, value } | Action::EntryOrInsertWith { key, value } => {
+                    *m.entry(key).or_insert(value) += 1;
+                }
+
+                Action::Len | Action::Iter => {}
+                Action::ShrinkToFit => m.shrink_to_fit(),
+            }
+        }
+
+        m.remove_range(cmp::min(range.0, range.1)..cmp::max(range.0, range.1));
+        m.rollback(cp);
+        m.to_vec() == original && m.check().is_ok()
+    }
+
+    fn cow_clone_is_independent(items: Vec<(isize, isize)>, actions: Vec<Action<isize, isize>>) -> bool {
+        let mut m1 = TopMap::<[Option<(isize, isize)>; 8]>::new();
+        m1.extend(items);
+        let original = m1.to_vec();
+        let mut m2 = m1.cow_clone();
+        let mut b2 = original.iter().cloned().collect::<BTreeMap<isize, isize>>();
+
+        for action in actions {
+            match action {
+                Action::Insert { key, value } => {
+                    m2.insert(key, value);
+                    b2.insert(key, value);
+                }
+
+                Action::Remove { key } => {
+                    m2.remove(key);
+                    b2.remove(&key);
+                }
+
+                Action::EntryOrInsert { key, value } | Action::EntryOrInsertWith { key, value } => {
+                    *m2.entry(key).or_insert(value) += 1;
+                    *b2.entry(key).or_insert(value) += 1;
+                }
+
+                Action::Get { key } | Action::EntryDrop { key } => {
+                    m2.get(key);
+                }
+
+                Action::Len | Action::Iter => {}
+                Action::ShrinkToFit => m2.shrink_to_fit(),
+            }
+        }
+
+        m1.to_vec() == original && m2.iter().map(|(key, &value)| (key, value)).eq(b2) && m2.check().is_ok()
+    }
+
+    fn remove_many_matches_sequential(items: Vec<(isize, isize)>, batches: Vec<Vec<isize>>) -> bool {
+        let mut map = items.into_iter().collect::<TopMap<[Option<(isize, isize)>; 8]>>();
+        let mut sequential = map.clone();
+
+        batches.into_iter().all(|keys| {
+            let expected = keys.iter().map(|&key| sequential.remove(key)).collect::<Vec<_>>();
+            map.remove_many(&keys) == expected && map.to_vec() == sequential.to_vec() && map.check().is_ok()
+        })
+    }
+
+    fn apply_delta_matches_sequential<A>(
+        mut map: TopMap<A>,
+        items: Vec<(isize, isize)>,
+        batches: Vec<Vec<(isize, Option<isize>)>>,
+    ) -> bool
+    where
+        A: Layout<Key = isize, Value = isize>,
+        A::Storage: Clone,
+    {
+        map.extend(items);
+
+        let mut sequential = map.clone();
+
+        batches.into_iter().all(|batch| {
+            for &(key, value) in batch.iter() {
+                match value {
+                    Some(value) => sequential.insert(key, value),
+                    None => sequential.remove(key),
+                };
+            }
+
+            map.apply_delta(batch);
+            map.to_vec() == sequential.to_vec() && map.check().is_ok()
+        })
+    }
+
     quickcheck! {
+        fn qc_apply_delta_matches_sequential(
+            items: Vec<(isize, isize)>,
+            batches: Vec<Vec<(isize, Option<isize>)>>
+        ) -> bool {
+            apply_delta_matches_sequential(TopMap::<[Option<(isize, isize)>; 8]>::new(), items, batches)
+        }
+
+        fn qc_apply_delta_matches_sequential_keep_window(
+            items: Vec<(isize, isize)>,
+            batches: Vec<Vec<(isize, Option<isize>)>>
+        ) -> bool {
+            let mut map = TopMap::<[Option<(isize, isize)>; 8]>::new();
+            map.set_far_below_policy(FarBelowPolicy::KeepWindow);
+            map.set_reanchor_policy(Some(ReanchorPolicy { max_occupied: 3, min_hot_rest: 2 }));
+            apply_delta_matches_sequential(map, items, batches)
+        }
+
+        fn qc_remove_many_matches_sequential(items: Vec<(isize, isize)>, batches: Vec<Vec<isize>>) -> bool {
+            remove_many_matches_sequential(items, batches)
+        }
+
+        fn qc_join_matches_btree_map(items1: Vec<(isize, isize)>, items2: Vec<(isize, u8)>) -> bool {
+            join_matches_btree_map(items1, items2)
+        }
+
+        fn qc_rollback_restores_contents(
+            items: Vec<(isize, isize)>,
+            actions: Vec<Action<isize, isize>>,
+            range: (isize, isize)
+        ) -> bool {
+            rollback_restores_contents(TopMap::new(), items, actions, range)
+        }
+
+        fn qc_rollback_restores_contents_at_limit(
+            items: Vec<(isize, isize)>,
+            actions: Vec<Action<isize, isize>>,
+            range: (isize, isize)
+        ) -> bool {
+            rollback_restores_contents(TopMap::with_eviction_limit(5, |_, _| {}), items, actions, range)
+        }
+
+        fn qc_cow_clone_is_independent(items: Vec<(isize, isize)>, actions: Vec<Action<isize, isize>>) -> bool {
+            cow_clone_is_independent(items, actions)
+        }
+
+        fn qc_set_ops_match_btree_set(keys1: Vec<isize>, keys2: Vec<isize>) -> bool {
+            set_ops_match_btree_set(keys1, keys2)
+        }
+
+        fn qc_partition_matches_input(items: Vec<(isize, isize)>, modulus: isize) -> bool {
+            partition_matches_input(items, modulus)
+        }
+
+        fn qc_remove_range_matches_btree_map(items: Vec<(isize, isize)>, probes: Vec<(isize, isize, u8)>) -> bool {
+            remove_range_matches_btree_map(items, probes)
+        }
+
+        fn qc_advance_to_matches_btree_map(items: Vec<(isize, isize)>, keys: Vec<isize>) -> bool {
+            advance_to_matches_btree_map(items, keys)
+        }
+
         fn qc_matches_btree_map(actions: Vec<Action<isize, isize>>) -> bool {
-            matches_btree_map(actions)
+            matches_btree_map::<[Option<(isize, isize)>; 128]>(actions)
+        }
+
+        fn qc_matches_btree_map_1(actions: Vec<Action<isize, isize>>) -> bool {
+            matches_btree_map::<[Option<(isize, isize)>; 1]>(actions)
+        }
+
+        fn qc_matches_btree_map_2(actions: Vec<Action<isize, isize>>) -> bool {
+            matches_btree_map::<[Option<(isize, isize)>; 2]>(actions)
+        }
+
+        fn qc_matches_btree_map_3(actions: Vec<Action<isize, isize>>) -> bool {
+            matches_btree_map::<[Option<(isize, isize)>; 3]>(actions)
+        }
+
+        fn qc_matches_btree_map_4(actions: Vec<Action<isize, isize>>) -> bool {
+            matches_btree_map::<[Option<(isize, isize)>; 4]>(actions)
+        }
+
+        fn qc_matches_btree_map_wide(actions: Vec<Action<isize, isize>>) -> bool {
+            matches_btree_map::<Wide>(actions)
+        }
+
+        fn qc_matches_btree_map_far_keys(actions: Vec<Action<isize, isize>>) -> bool {
+            matches_btree_map::<[Option<(isize, isize)>; 8]>(spread_keys(actions))
+        }
+
+        fn qc_iter_ascends(actions: Vec<Action<isize, isize>>) -> bool {
+            iter_ascends::<[Option<(isize, isize)>; 4]>(actions)
+        }
+
+        fn qc_iter_ascends_128(actions: Vec<Action<isize, isize>>) -> bool {
+            iter_ascends::<[Option<(isize, isize)>; 128]>(actions)
+        }
+
+        fn qc_collect_matches_inserts(items: Vec<(isize, isize)>, tail: Vec<(isize, isize)>) -> bool {
+            collect_matches_inserts(items, tail)
+        }
+
+        fn qc_collect_descending_matches_btree_map(items: Vec<(isize, isize)>) -> bool {
+            collect_descending_matches_btree_map(items)
+        }
+
+        fn qc_append_matches_btree_map(items1: Vec<(isize, isize)>, items2: Vec<(isize, isize)>) -> bool {
+            append_matches_btree_map(items1, items2)
+        }
+
+        fn qc_insert_sorted_iter_matches_btree_map(items: Vec<(isize, isize)>, tail: Vec<(isize, isize)>) -> bool {
+            insert_sorted_iter_matches_btree_map(items, tail)
+        }
+
+        fn qc_count_range_matches_btree_map(
+            items: Vec<(isize, isize)>,
+            removed: Vec<isize>,
+            probes: Vec<(isize, isize)>
+        ) -> bool {
+            count_range_matches_btree_map(items, removed, probes)
+        }
+
+        fn qc_select_nth_matches_iter(items: Vec<(isize, isize)>, removed: Vec<isize>) -> bool {
+            select_nth_matches_iter(items, removed)
+        }
+
+        fn qc_matches_btree_set(actions: Vec<Action<isize, ()>>) -> bool {
+            matches_btree_set::<[Option<(isize, ())>; 128]>(actions)
+        }
+
+        fn qc_matches_btree_set_3(actions: Vec<Action<isize, ()>>) -> bool {
+            matches_btree_set::<[Option<(isize, ())>; 3]>(actions)
+        }
+
+        fn qc_matches_btree_map_dyn_1(actions: Vec<Action<isize, isize>>) -> bool {
+            matches_btree_map_from(TopMap::with_array(DynArray::new(1)), actions)
+        }
+
+        fn qc_matches_btree_map_dyn_3(actions: Vec<Action<isize, isize>>) -> bool {
+            matches_btree_map_from(TopMap::with_array(DynArray::new(3)), actions)
+        }
+
+        fn qc_matches_btree_map_dyn_200(actions: Vec<Action<isize, isize>>) -> bool {
+            matches_btree_map_from(TopMap::with_array(DynArray::with_min_size(200, 150)), actions)
+        }
+
+        fn qc_matches_btree_map_keep_window(actions: Vec<Action<isize, isize>>) -> bool {
+            let mut map = TopMap::<[Option<(isize, isize)>; 8]>::new();
+            map.set_far_below_policy(FarBelowPolicy::KeepWindow);
+            matches_btree_map_from(map, actions)
+        }
+
+        fn qc_matches_btree_map_refill_margin(actions: Vec<Action<isize, isize>>) -> bool {
+            let mut map = TopMap::<[Option<(isize, isize)>; 8]>::new();
+            map.set_refill_margin(2);
+            matches_btree_map_from(map, actions)
+        }
+
+        fn qc_matches_btree_map_reanchor(actions: Vec<Action<isize, isize>>) -> bool {
+            let mut map = TopMap::<[Option<(isize, isize)>; 8]>::new();
+            map.set_reanchor_policy(Some(ReanchorPolicy { max_occupied: 3, min_hot_rest: 2 }));
+            matches_btree_map_from(map, actions)
+        }
+    }
+
+    #[cfg(feature = "quickcheck")]
+    quickcheck! {
+        fn qc_arbitrary_matches_btree_map(
+            map: TopMap<[Option<(isize, isize)>; 8]>,
+            actions: Vec<Action<isize, isize>>
+        ) -> bool {
+            let map1 = map.iter().map(|(key, &value)| (key, value)).collect::<BTreeMap<_, _>>();
+
+            map.occupied == (0..map.top.len()).filter(|&index| map.top[index].is_some()).count()
+                && map.rest_min == map.rest.keys().next().cloned()
+                && matches_btree_map_seeded(map1, map, actions)
         }
     }
 }
@@ -0,0 +1,242 @@
+//! Counts heap allocations around the operations that are meant to stay in the window, so a change that starts
+//! touching the ordered maps on the hot path fails here rather than only in the benchmarks.
+
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
+
+extern crate top_map;
+
+#[cfg(feature = "allocator_api")]
+use std::alloc::{AllocError, Allocator};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::mem;
+#[cfg(feature = "allocator_api")]
+use std::ptr::NonNull;
+#[cfg(feature = "allocator_api")]
+use std::rc::Rc;
+#[cfg(feature = "allocator_api")]
+use top_map::{DynArray, InAllocator, Layout as MapLayout};
+use top_map::TopMap;
+
+mod common;
+
+use common::Rng;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    static LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+}
+
+fn add_live_bytes(delta: isize) {
+    let _ = LIVE_BYTES.try_with(|bytes| bytes.set(bytes.get() + delta));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        add_live_bytes(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        add_live_bytes(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        add_live_bytes(new_size as isize - layout.size() as isize);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Counts the allocations `f` makes on this thread; the test harness runs other tests on other threads.
+fn allocations<T, F: FnOnce() -> T>(f: F) -> (usize, T) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (ALLOCATIONS.with(Cell::get) - before, result)
+}
+
+// The heap bytes `f` leaves allocated on this thread, counting whatever it returns.
+fn live_bytes<T, F: FnOnce() -> T>(f: F) -> (usize, T) {
+    let before = LIVE_BYTES.with(Cell::get);
+    let result = f();
+    ((LIVE_BYTES.with(Cell::get) - before) as usize, result)
+}
+
+fn warm_map() -> TopMap<[Option<(isize, isize)>; 128]> {
+    (0..1000).map(|n| (n, n)).collect()
+}
+
+#[test]
+fn new_does_not_allocate() {
+    let (count, m) = allocations(TopMap::<[Option<(isize, isize)>; 128]>::new);
+    assert_eq!(0, count);
+    assert!(m.is_empty());
+}
+
+#[test]
+fn window_operations_do_not_allocate() {
+    let mut m = warm_map();
+
+    let (count, sum) = allocations(|| {
+        let mut sum = 0;
+
+        for round in 0..100 {
+            for key in 10..60 {
+                sum += m.remove(key).unwrap();
+            }
+
+            for key in 10..60 {
+                assert_eq!(None, m.insert(key, key + round));
+            }
+
+            for key in 0..128 {
+                sum += m[key];
+            }
+        }
+
+        sum
+    });
+
+    assert_eq!(0, count);
+    assert!(sum > 0);
+}
+
+#[test]
+fn insert_above_top_allocates_only_rest_nodes() {
+    let mut m = (100..1000).map(|n| (n, n)).collect::<TopMap<[Option<(isize, isize)>; 128]>>();
+
+    // Moving the window down one key demotes one entry into the rest map, which can split at most a leaf and
+    // the internal nodes above it.
+    let (count, _) = allocations(|| m.insert(99, 99));
+    assert!(count <= 3, "{} allocations", count);
+    assert_eq!(Some(&227), m.get(227));
+}
+
+// The estimate models trees filled in key order, which is how the window demotes into the rest map; shuffled inserts
+// fill the nodes further, so there it may only overestimate.
+#[test]
+fn memory_usage_matches_live_bytes() {
+    type M = TopMap<[Option<(isize, isize)>; 128]>;
+
+    let mut rng = Rng(1);
+    let shuffled = (0..100_000).map(|_| rng.below(1_000_000) as isize).collect::<Vec<_>>();
+
+    let cases = [
+        ("ascending", (0..100_000).collect::<Vec<_>>(), 1.1),
+        ("descending", (0..100_000).rev().collect(), 1.1),
+        ("shuffled", shuffled, 1.3),
+    ];
+
+    for (name, keys, max_ratio) in cases {
+        let (bytes, m) = live_bytes(|| {
+            let mut m = M::new();
+
+            for &key in keys.iter() {
+                m.insert(key, key);
+            }
+
+            m
+        });
+
+        let estimate = m.memory_usage() - mem::size_of::<M>();
+        let ratio = estimate as f64 / bytes as f64;
+        assert!((0.9..=max_ratio).contains(&ratio), "{}: estimated {} for {} bytes", name, estimate, bytes);
+    }
+}
+
+#[test]
+fn iter_does_not_allocate() {
+    let mut m = warm_map();
+    m.shrink_to_min();
+
+    let (count, sum) = allocations(|| m.iter().map(|(_, &value)| value).sum::<isize>());
+    assert_eq!(0, count);
+    assert_eq!((0..1000).sum::<isize>(), sum);
+}
+
+#[test]
+fn iter_mut_does_not_allocate() {
+    let mut m = warm_map();
+    m.shrink_to_min();
+
+    let (count, _) = allocations(|| m.iter_mut().for_each(|(key, value)| *value += key));
+    assert_eq!(0, count);
+    assert!(m.iter().all(|(key, &value)| value == key * 2));
+}
+
+// Passes through to the system allocator, bypassing the counting global one, and keeps its own counts.
+#[cfg(feature = "allocator_api")]
+#[derive(Clone, Default)]
+struct MapAllocator {
+    allocated: Rc<Cell<usize>>,
+    live: Rc<Cell<usize>>,
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl Allocator for MapAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = System.allocate(layout)?;
+        self.allocated.set(self.allocated.get() + 1);
+        self.live.set(self.live.get() + 1);
+        Ok(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.live.set(self.live.get() - 1);
+        System.deallocate(ptr, layout)
+    }
+}
+
+// Runs a map built on `alloc` through construction, inserts, removes, retain and clone, checking that nothing along the
+// way comes from the global allocator and that dropping the maps hands back everything `alloc` gave out.
+#[cfg(feature = "allocator_api")]
+fn allocates_only_from<A, F>(alloc: &MapAllocator, new: F)
+where
+    A: MapLayout<Key = isize, Value = isize>,
+    A::Storage: Clone,
+    F: FnOnce() -> TopMap<A>,
+{
+    let (count, ()) = allocations(|| {
+        let mut m = new();
+
+        for n in (0..1000).rev() {
+            m.insert(n, n);
+        }
+
+        for n in (0..1000).step_by(3) {
+            assert_eq!(Some(n), m.remove(n));
+        }
+
+        m.retain(|key, _| key % 5 != 0);
+        let copy = m.clone();
+        assert!(copy.iter().eq(m.iter()));
+        assert_eq!(Some(&998), m.get(998));
+    });
+
+    assert_eq!(0, count);
+    assert!(alloc.allocated.get() > 0);
+    assert_eq!(0, alloc.live.get());
+}
+
+#[cfg(feature = "allocator_api")]
+#[test]
+fn new_in_allocates_only_from_the_given_allocator() {
+    let alloc = MapAllocator::default();
+    allocates_only_from(&alloc, || {
+        TopMap::<InAllocator<[Option<(isize, isize)>; 128], MapAllocator>>::new_in(alloc.clone())
+    });
+}
+
+#[cfg(feature = "allocator_api")]
+#[test]
+fn dyn_array_new_in_allocates_only_from_the_given_allocator() {
+    let alloc = MapAllocator::default();
+    allocates_only_from(&alloc, || TopMap::with_array(DynArray::new_in(128, alloc.clone())));
+}
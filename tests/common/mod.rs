@@ -0,0 +1,23 @@
+//! Helpers shared by the integration tests and, through a `#[path]` module, the unit tests in src/lib.rs. Each test
+//! crate uses a different subset of them.
+#![allow(dead_code)]
+
+// Seeded keys for tests that want more of them than quickcheck generates: Knuth's MMIX LCG, keeping the high bits.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i as u64 + 1) as usize);
+        }
+    }
+}
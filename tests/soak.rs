@@ -0,0 +1,128 @@
+//! Randomised soak test against a `BTreeMap` model. Ignored by default; run it with
+//! `cargo test --release --test soak -- --ignored`. `SOAK_SEED` replays a failing run and `SOAK_OPS` sets its length.
+
+extern crate top_map;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use top_map::{FarBelowPolicy, ReanchorPolicy, TopMap};
+
+mod common;
+
+use common::Rng;
+
+type Map = TopMap<[Option<(isize, isize)>; 8]>;
+
+// Mostly keys near the bottom of the range, where the window sits, with the occasional far outlier.
+fn key(rng: &mut Rng) -> isize {
+    match rng.below(16) {
+        0 => rng.below(100_000) as isize - 50_000,
+        _ => rng.below(256) as isize - 64,
+    }
+}
+
+// Prints the seed if the run panics, whether from an assertion here or from the map's own checks.
+struct ReportSeed(u64);
+
+impl Drop for ReportSeed {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            eprintln!("soak failed; rerun with SOAK_SEED={}", self.0);
+        }
+    }
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    env::var(name).ok().map(|value| value.parse().expect(name))
+}
+
+fn check(map: &Map, model: &BTreeMap<isize, isize>, op: u64, full: bool) {
+    if let Err(violation) = map.check() {
+        panic!("op {}: {}", op, violation);
+    }
+
+    assert_eq!(model.len(), map.len(), "op {}: len", op);
+    assert_eq!(model.is_empty(), map.is_empty(), "op {}: is_empty", op);
+
+    if full {
+        assert!(
+            model.iter().map(|(&key, value)| (key, value)).eq(map.iter()),
+            "op {}: iter",
+            op
+        );
+    } else {
+        assert_eq!(model.len(), map.iter().count(), "op {}: iter count", op);
+    }
+}
+
+#[test]
+#[ignore]
+fn soak() {
+    let seed = env_u64("SOAK_SEED").unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+    let ops = env_u64("SOAK_OPS").unwrap_or(1_000_000);
+    let _report = ReportSeed(seed);
+    let mut rng = Rng(seed);
+
+    let mut map = Map::new();
+    let mut model = BTreeMap::new();
+
+    if rng.below(2) == 0 {
+        map.set_far_below_policy(FarBelowPolicy::KeepWindow);
+    }
+
+    if rng.below(2) == 0 {
+        map.set_reanchor_policy(Some(ReanchorPolicy {
+            max_occupied: 2,
+            min_hot_rest: 4,
+        }));
+    }
+
+    map.set_refill_margin(rng.below(3) as usize);
+
+    for op in 0..ops {
+        let key = key(&mut rng);
+        let value = rng.next() as isize;
+
+        match rng.below(100) {
+            0..=34 => assert_eq!(model.insert(key, value), map.insert(key, value), "op {}: insert {}", op, key),
+            35..=59 => assert_eq!(model.remove(&key), map.remove(key), "op {}: remove {}", op, key),
+            60..=74 => assert_eq!(model.get(&key), map.get(key), "op {}: get {}", op, key),
+
+            75..=84 => assert_eq!(
+                *model.entry(key).or_insert(value),
+                *map.entry(key).or_insert(value),
+                "op {}: entry {}",
+                op,
+                key
+            ),
+
+            85..=89 => {
+                map.entry(key);
+            }
+
+            90..=93 => {
+                let end = key + rng.below(32) as isize;
+                let expected = model.range(key..end).map(|(&key, _)| key).collect::<Vec<_>>();
+
+                for key in expected.iter() {
+                    model.remove(key);
+                }
+
+                assert_eq!(expected.len(), map.remove_range(key..end), "op {}: remove_range {}..{}", op, key, end);
+            }
+
+            94..=95 => map.shrink_to_fit(),
+            96..=97 => map.shrink_to_min(),
+
+            _ => {
+                map.rebalance();
+            }
+        }
+
+        check(&map, &model, op, op % 1000 == 0);
+    }
+
+    check(&map, &model, ops, true);
+}